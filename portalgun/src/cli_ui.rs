@@ -5,7 +5,8 @@
 
 use std::net::SocketAddr;
 
-use crate::Config;
+use crate::client::Connected;
+use crate::{Config, TunnelType};
 use cli_table::format::Padding;
 use cli_table::{format::Justify, print_stderr, Cell, Table};
 use colored::Colorize;
@@ -30,7 +31,7 @@ impl CliInterface {
         if self.config.sub_domain.is_some()
             && (self.config.sub_domain.as_deref() != Some(sub_domain))
         {
-            if self.config.secret_key.is_some() {
+            if self.config.secret_key.is_some() || self.config.client_identity.is_some() {
                 Some(format!(
                     "{}",
                     "To use custom sub-domains feature, please ask to your instance admin."
@@ -47,7 +48,7 @@ impl CliInterface {
         }
     }
 
-    pub fn did_connect(&self, sub_domain: &str, full_hostname: &str) {
+    pub fn did_connect(&self, connected: &Connected) {
         self.spinner
             .finish_with_message("Success! Remote tunnel is now open.\n".green().as_ref());
 
@@ -55,40 +56,67 @@ impl CliInterface {
             return;
         }
 
-        let public_url = self.config.activation_url(full_hostname).bold().green();
+        let public_url = connected.public_url.bold().green();
         let forward_url = self.config.forward_url();
         let inspect = format!("http://localhost:{}", self.introspect.port());
 
-        let table = vec![
-            vec![
-                "Public tunnel URL".green().cell(),
-                public_url
-                    .green()
-                    .cell()
-                    .padding(Padding::builder().left(4).right(4).build())
-                    .justify(Justify::Left),
-            ],
-            vec![
+        let mut table = vec![vec![
+            "Public tunnel URL".green().cell(),
+            public_url
+                .green()
+                .cell()
+                .padding(Padding::builder().left(4).right(4).build())
+                .justify(Justify::Left),
+        ]];
+
+        // raw tcp and udp traffic isn't http, so there is nothing to inspect
+        if self.config.tunnel_type == TunnelType::Http {
+            table.push(vec![
                 "Local inspect dashboard".magenta().cell(),
                 inspect
                     .magenta()
                     .cell()
                     .padding(Padding::builder().left(4).build())
                     .justify(Justify::Left),
-            ],
-            vec![
-                "Forwarding traffic to".cell(),
-                forward_url
-                    .cell()
-                    .padding(Padding::builder().left(4).build())
-                    .justify(Justify::Left),
-            ],
-        ];
+            ]);
+        }
+
+        table.push(vec![
+            "Forwarding traffic to".cell(),
+            forward_url
+                .cell()
+                .padding(Padding::builder().left(4).build())
+                .justify(Justify::Left),
+        ]);
+
+        for tunnel in &connected.tunnels {
+            let binding = match self
+                .config
+                .bindings
+                .iter()
+                .find(|b| b.sub_domain == tunnel.name)
+            {
+                Some(binding) => binding,
+                None => continue,
+            };
+
+            table.push(vec![
+                format!("Tunnel '{}'", tunnel.name).green().cell(),
+                format!(
+                    "{} -> {}",
+                    connected.tunnel_url(tunnel).bold().green(),
+                    self.config.binding_forward_url(binding)
+                )
+                .cell()
+                .padding(Padding::builder().left(4).right(4).build())
+                .justify(Justify::Left),
+            ]);
+        }
 
         let table = table.table();
         print_stderr(table).expect("failed to generate starting terminal user interface");
 
-        if let Some(notice) = self.get_sub_domain_notice(sub_domain) {
+        if let Some(notice) = self.get_sub_domain_notice(&connected.sub_domain) {
             eprintln!("\n{}: {}\n", ">>> Notice".yellow(), notice);
         }
     }
@@ -4,17 +4,23 @@
 // SPDX-License-Identifier: MIT
 
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use super::*;
+use crate::client::{AuthInfo, ClientIdentity, LocalService, Tunnel, TunnelClient};
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::openid2::{authorize, fetch_token};
+use crate::openid2::{authorize, authorize_in_browser, fetch_token};
 
 const SETTINGS_DIR: &str = ".portalgun";
 const SECRET_KEY_FILE: &str = "auth.json";
 
+/// How long access tokens are used for when the provider doesn't say when they expire
+const DEFAULT_ACCESS_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
 /// Command line arguments
 #[derive(Debug, Parser)]
 #[command(name = "portalgun")]
@@ -32,6 +38,10 @@ struct Opts {
     #[clap(long, short = 's')]
     sub_domain: Option<String>,
 
+    /// Reserve the requested sub-domains for your account, so nobody else can use them
+    #[clap(long = "reserve")]
+    reserve: bool,
+
     /// Sets the HOST (i.e. localhost) to forward incoming tunnel traffic to
     #[clap(long = "host", default_value = "localhost")]
     local_host: String,
@@ -47,18 +57,105 @@ struct Opts {
     /// Sets the address of the local introspection dashboard
     #[clap(long = "dashboard-port")]
     dashboard_port: Option<u16>,
+
+    /// Add X-Forwarded-For and Forwarded headers with the visitor's address to incoming requests
+    #[clap(long = "forwarded-headers")]
+    forwarded_headers: bool,
+
+    /// Open an additional tunnel on the same connection, can be used multiple times
+    #[clap(long = "tunnel", value_name = "SUB_DOMAIN=[HOST:]PORT", value_parser = parse_tunnel)]
+    tunnels: Vec<TunnelOpt>,
+
+    /// Connect to CONTROL_SERVER without logging in, if it allows anonymous tunnels
+    #[clap(long = "anonymous", value_name = "CONTROL_SERVER")]
+    anonymous: Option<Url>,
+}
+
+/// An additional tunnel given on the command line
+#[derive(Debug, Clone)]
+struct TunnelOpt {
+    sub_domain: String,
+    local_host: String,
+    local_port: u16,
+}
+
+fn parse_tunnel(s: &str) -> Result<TunnelOpt, String> {
+    let (sub_domain, target) = s
+        .split_once('=')
+        .ok_or_else(|| "expected SUB_DOMAIN=[HOST:]PORT".to_string())?;
+    let (local_host, port) = target.rsplit_once(':').unwrap_or(("localhost", target));
+    let local_port = port
+        .parse()
+        .map_err(|_| format!("invalid port: {}", port))?;
+
+    if sub_domain.is_empty() || local_host.is_empty() {
+        return Err("expected SUB_DOMAIN=[HOST:]PORT".to_string());
+    }
+
+    Ok(TunnelOpt {
+        sub_domain: sub_domain.to_string(),
+        local_host: local_host.to_string(),
+        local_port,
+    })
 }
 
 #[derive(Debug, Subcommand)]
 enum SubCommand {
-    /// Login using OpenID Connect. This will store the authentication token on disk for future use.
+    /// Login using OpenID Connect or an access token. This will store the credential on disk for future use.
     Login {
         /// Corresponding tunnel server websocket URL, example: wss://tunnel.example.com
         #[clap(long = "control-server")]
         control_server: Url,
+
+        /// Access token to store, for servers that don't use OpenID Connect
+        #[clap(long = "token", conflicts_with = "client_cert")]
+        token: Option<String>,
+
+        /// PEM encoded client certificate to authenticate with, for servers that verify them
+        #[clap(long = "client-cert", requires = "client_key")]
+        client_cert: Option<PathBuf>,
+
+        /// PEM encoded private key of the client certificate
+        #[clap(long = "client-key", requires = "client_cert")]
+        client_key: Option<PathBuf>,
+
+        /// Log in with a browser on this machine instead of on any device with a code,
+        /// for OpenID Connect providers without device authorization
+        #[clap(long = "browser", conflicts_with_all = ["token", "client_cert"])]
+        browser: bool,
+    },
+    /// Expose a raw TCP service (i.e. a database) on a server allocated public port
+    Tcp {
+        /// Sets the port of the local TCP service
+        #[clap(long = "port")]
+        port: u16,
+
+        /// Sets the HOST (i.e. localhost) of the local TCP service
+        #[clap(long = "host", default_value = "localhost")]
+        local_host: String,
+    },
+    /// Expose a UDP service (i.e. DNS) on a server allocated public port
+    Udp {
+        /// Sets the port of the local UDP service
+        #[clap(long = "port")]
+        port: u16,
+
+        /// Sets the HOST (i.e. localhost) of the local UDP service
+        #[clap(long = "host", default_value = "localhost")]
+        local_host: String,
     },
 }
 
+/// An additional http tunnel forwarding to its own local service
+#[derive(Debug, Clone)]
+pub struct LocalBinding {
+    /// requested sub-domain, also used as the binding name
+    pub sub_domain: String,
+    pub local_host: String,
+    pub local_port: u16,
+    pub local_addr: SocketAddr,
+}
+
 /// Config
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -69,19 +166,110 @@ pub struct Config {
     pub local_port: u16,
     pub local_addr: SocketAddr,
     pub sub_domain: Option<String>,
+    pub reserve: bool,
+    pub tunnel_type: TunnelType,
     pub secret_key: Option<SecretKey>,
+    pub client_identity: Option<ClientIdentity>,
+    /// the OIDC login the access token was refreshed from, if any
+    pub oidc_session: Option<OidcSession>,
     pub first_run: bool,
     pub dashboard_port: u16,
+    pub forwarded_headers: bool,
+    pub bindings: Vec<LocalBinding>,
     pub verbose: bool,
 }
 
 /// Auth storage
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct AuthStorage {
-    oidc: String,
-    client_id: String,
-    refresh_token: String,
-    control_server: Url,
+#[serde(untagged)]
+pub enum AuthStorage {
+    Oidc {
+        oidc: String,
+        client_id: String,
+        refresh_token: String,
+        control_server: Url,
+    },
+    /// An access token issued out of band
+    Token { token: String, control_server: Url },
+    /// A client certificate, verified by the server when opening the control connection
+    Certificate {
+        client_cert: PathBuf,
+        client_key: PathBuf,
+        control_server: Url,
+    },
+}
+
+/// An OIDC login, exchanging the stored refresh token for access tokens
+#[derive(Debug, Clone)]
+pub struct OidcSession {
+    auth_file: PathBuf,
+    /// the latest access token, and when to refresh it
+    current: Arc<tokio::sync::Mutex<Option<(String, Instant)>>>,
+}
+
+impl OidcSession {
+    fn new(auth_file: PathBuf) -> Self {
+        Self {
+            auth_file,
+            current: Default::default(),
+        }
+    }
+
+    /// An access token that is not about to expire, refreshed when needed
+    pub async fn access_token(&self) -> Result<String, Error> {
+        let mut current = self.current.lock().await;
+        if let Some((access_token, refresh_at)) = current.as_ref() {
+            if Instant::now() < *refresh_at {
+                return Ok(access_token.clone());
+            }
+        }
+
+        let tokens = self.refresh().await?;
+        // refresh once three quarters of the token's lifetime are over
+        let lifetime = tokens.expires_in.unwrap_or(DEFAULT_ACCESS_TOKEN_LIFETIME);
+        *current = Some((
+            tokens.access_token.clone(),
+            Instant::now() + lifetime * 3 / 4,
+        ));
+        Ok(tokens.access_token)
+    }
+
+    /// When the current access token should be refreshed, `None` before the first one
+    pub async fn refresh_at(&self) -> Option<Instant> {
+        self.current.lock().await.as_ref().map(|(_, at)| *at)
+    }
+
+    /// Exchange the stored refresh token, storing the one replacing it if any
+    async fn refresh(&self) -> Result<openid2::Tokens, Error> {
+        let auth_json = std::fs::read_to_string(&self.auth_file)
+            .map_err(|e| Error::Credential(e.to_string()))?;
+        let mut credential: AuthStorage =
+            serde_json::from_str(&auth_json).map_err(|e| Error::Credential(e.to_string()))?;
+
+        let (oidc, client_id, refresh_token) = match &mut credential {
+            AuthStorage::Oidc {
+                oidc,
+                client_id,
+                refresh_token,
+                ..
+            } => (oidc, client_id, refresh_token),
+            _ => {
+                return Err(Error::Credential(
+                    "not logged in with OpenID Connect".to_string(),
+                ))
+            }
+        };
+
+        let tokens = fetch_token(oidc, client_id, refresh_token).await?;
+        if let Some(refresh) = &tokens.refresh_token {
+            *refresh_token = refresh.clone();
+            let json =
+                serde_json::to_string(&credential).map_err(|e| Error::Credential(e.to_string()))?;
+            std::fs::write(&self.auth_file, json).map_err(|e| Error::Credential(e.to_string()))?;
+        }
+
+        Ok(tokens)
+    }
 }
 
 impl Config {
@@ -96,20 +284,87 @@ impl Config {
 
         pretty_env_logger::init();
 
-        let (secret_key, sub_domain, control_url) = match opts.command {
-            Some(SubCommand::Login { control_server }) => {
-                let control_url = control_server.join("wormhole").expect("Malformed URL");
+        let (tunnel_type, local_host, local_port) = match &opts.command {
+            Some(SubCommand::Tcp { port, local_host }) => {
+                (TunnelType::Tcp, local_host.clone(), *port)
+            }
+            Some(SubCommand::Udp { port, local_host }) => {
+                (TunnelType::Udp, local_host.clone(), *port)
+            }
+            _ => (TunnelType::Http, opts.local_host.clone(), opts.port),
+        };
 
-                let (client_id, discovery, scopes) =
-                    crate::get_auth_info(control_url.as_str()).await.unwrap();
+        let mut oidc_session = None;
+        let (secret_key, client_identity, sub_domain, control_url) = match opts.command {
+            Some(SubCommand::Login {
+                control_server,
+                token,
+                client_cert,
+                client_key,
+                browser,
+            }) => {
+                let control_url = control_server.join("wormhole").expect("Malformed URL");
 
-                let refresh = authorize(&discovery, &client_id, scopes).await.unwrap();
+                let auth_storage = if let (Some(client_cert), Some(client_key)) =
+                    (client_cert, client_key)
+                {
+                    // check the files now, rather than on every run
+                    if let Err(e) = load_client_identity(&client_cert, &client_key) {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
 
-                let auth_storage = AuthStorage {
-                    oidc: discovery,
-                    client_id,
-                    refresh_token: refresh,
-                    control_server: control_url,
+                    AuthStorage::Certificate {
+                        client_cert: std::fs::canonicalize(client_cert)
+                            .expect("Failed to resolve the client certificate path."),
+                        client_key: std::fs::canonicalize(client_key)
+                            .expect("Failed to resolve the client key path."),
+                        control_server: control_url,
+                    }
+                } else {
+                    let auth_info = client::auth_info(control_url.as_str()).await.unwrap();
+
+                    match (auth_info, token) {
+                        (_, Some(token)) => AuthStorage::Token {
+                            token,
+                            control_server: control_url,
+                        },
+                        (
+                            AuthInfo::Oidc {
+                                oidc_client_id,
+                                oidc_discovery,
+                                oidc_scopes,
+                            },
+                            None,
+                        ) => {
+                            let refresh = if browser {
+                                authorize_in_browser(&oidc_discovery, &oidc_client_id, oidc_scopes)
+                                    .await
+                            } else {
+                                authorize(&oidc_discovery, &oidc_client_id, oidc_scopes).await
+                            };
+                            let refresh = match refresh {
+                                Ok(refresh) => refresh,
+                                Err(e) => {
+                                    eprintln!("Login failed: {}", e);
+                                    std::process::exit(1);
+                                }
+                            };
+
+                            AuthStorage::Oidc {
+                                oidc: oidc_discovery,
+                                client_id: oidc_client_id,
+                                refresh_token: refresh,
+                                control_server: control_url,
+                            }
+                        }
+                        (AuthInfo::Token, None) => {
+                            eprintln!(
+                            "This server does not use OpenID Connect, please log in with --token."
+                        );
+                            std::process::exit(1);
+                        }
+                    }
                 };
 
                 let auth_json =
@@ -129,90 +384,196 @@ impl Config {
                 eprintln!("Authentication key stored successfully!");
                 std::process::exit(0);
             }
-            None => {
-                let auth_file = dirs::home_dir()
-                    .map(|h| h.join(SETTINGS_DIR).join(SECRET_KEY_FILE))
-                    .expect("Failed to access home directory.");
-                let auth_json = if auth_file.exists() {
-                    std::fs::read_to_string(auth_file.clone())
-                        .map_err(|e| error!("Error reading credential: {:?}", e))
-                        .unwrap()
+            Some(SubCommand::Tcp { .. }) | Some(SubCommand::Udp { .. }) | None => {
+                if let Some(control_server) = &opts.anonymous {
+                    let control_url = control_server.join("wormhole").expect("Malformed URL");
+                    (None, None, opts.sub_domain, control_url)
                 } else {
-                    eprintln!("Credential file not found. Please login first.");
-                    std::process::exit(1);
-                };
-
-                let mut credential: AuthStorage =
-                    serde_json::from_str(&auth_json).expect("Failed to deserialize credential.");
-
-                let (access_token, refresh_token) = fetch_token(
-                    &credential.oidc,
-                    &credential.client_id,
-                    &credential.refresh_token,
-                )
-                .await
-                .expect("Failed to refresh session.");
-
-                if let Some(refresh) = refresh_token {
-                    credential.refresh_token = refresh;
-                    let json = serde_json::to_string(&credential)
-                        .expect("Failed to serialize credential.");
-                    std::fs::write(auth_file, json).expect("Failed to store credential.");
+                    let auth_file = dirs::home_dir()
+                        .map(|h| h.join(SETTINGS_DIR).join(SECRET_KEY_FILE))
+                        .expect("Failed to access home directory.");
+                    let auth_json = if auth_file.exists() {
+                        std::fs::read_to_string(auth_file.clone())
+                            .map_err(|e| error!("Error reading credential: {:?}", e))
+                            .unwrap()
+                    } else {
+                        eprintln!("Credential file not found. Please login first.");
+                        std::process::exit(1);
+                    };
+
+                    let credential: AuthStorage = serde_json::from_str(&auth_json)
+                        .expect("Failed to deserialize credential.");
+
+                    match &credential {
+                        AuthStorage::Oidc { control_server, .. } => {
+                            let session = OidcSession::new(auth_file);
+                            let access_token = session
+                                .access_token()
+                                .await
+                                .expect("Failed to refresh session.");
+                            oidc_session = Some(session);
+
+                            (
+                                Some(access_token),
+                                None,
+                                opts.sub_domain,
+                                control_server.clone(),
+                            )
+                        }
+                        AuthStorage::Token {
+                            token,
+                            control_server,
+                        } => (
+                            Some(token.clone()),
+                            None,
+                            opts.sub_domain,
+                            control_server.clone(),
+                        ),
+                        AuthStorage::Certificate {
+                            client_cert,
+                            client_key,
+                            control_server,
+                        } => {
+                            let identity = match load_client_identity(client_cert, client_key) {
+                                Ok(identity) => identity,
+                                Err(e) => {
+                                    eprintln!("{}", e);
+                                    std::process::exit(1);
+                                }
+                            };
+                            (
+                                None,
+                                Some(identity),
+                                opts.sub_domain,
+                                control_server.clone(),
+                            )
+                        }
+                    }
                 }
-
-                (access_token, opts.sub_domain, credential.control_server)
             }
         };
 
-        let local_addr = match (opts.local_host.as_str(), opts.port)
-            .to_socket_addrs()
-            .unwrap_or(vec![].into_iter())
-            .next()
-        {
-            Some(addr) => addr,
-            None => {
-                error!(
-                    "An invalid local address was specified: {}:{}",
-                    opts.local_host.as_str(),
-                    opts.port
-                );
-                return Err(());
-            }
-        };
+        let local_addr = resolve_local_addr(&local_host, local_port)?;
+
+        if !opts.tunnels.is_empty() && tunnel_type != TunnelType::Http {
+            error!("Additional tunnels can only be used with http tunnels");
+            return Err(());
+        }
+
+        if opts.reserve && secret_key.is_none() && client_identity.is_none() {
+            error!("Anonymous tunnels can't reserve sub-domains, please log in");
+            return Err(());
+        }
+
+        if opts.reserve && sub_domain.is_none() && opts.tunnels.is_empty() {
+            error!("Only requested sub-domains can be reserved, please pass --sub-domain");
+            return Err(());
+        }
+
+        let mut bindings = vec![];
+        for tunnel in opts.tunnels {
+            bindings.push(LocalBinding {
+                local_addr: resolve_local_addr(&tunnel.local_host, tunnel.local_port)?,
+                sub_domain: tunnel.sub_domain,
+                local_host: tunnel.local_host,
+                local_port: tunnel.local_port,
+            });
+        }
 
         Ok(Config {
             client_id: ClientId::generate(),
-            local_host: opts.local_host,
+            local_host,
             use_tls: opts.use_tls,
             control_url,
-            local_port: opts.port,
+            local_port,
             local_addr,
             sub_domain,
+            reserve: opts.reserve,
+            tunnel_type,
             dashboard_port: opts.dashboard_port.unwrap_or(0),
+            forwarded_headers: opts.forwarded_headers,
+            bindings,
             verbose: opts.verbose,
-            secret_key: Some(SecretKey(secret_key)),
+            secret_key: secret_key.map(SecretKey),
+            client_identity,
+            oidc_session,
             first_run: true,
         })
     }
 
-    pub fn activation_url(&self, full_hostname: &str) -> String {
-        format!(
-            "{}://{}",
-            if self.control_url.scheme() == "ws" {
-                "http"
-            } else {
-                "https"
-            },
-            full_hostname
-        )
+    /// The client forwarding tunnel traffic as configured
+    pub fn tunnel_client(&self) -> Result<TunnelClient, client::Error> {
+        let mut builder = Tunnel::builder()
+            .control_url(self.control_url.clone())
+            .tunnel_type(self.tunnel_type)
+            .forward_to(LocalService::new(&self.local_host, self.local_addr))
+            .use_tls(self.use_tls)
+            .reserve(self.reserve)
+            .forwarded_headers(self.forwarded_headers)
+            .observer(introspect::Introspector);
+
+        if let Some(secret_key) = &self.secret_key {
+            builder = builder.token(&secret_key.0);
+        }
+        if let Some(identity) = &self.client_identity {
+            builder = builder.client_certificate(identity.clone());
+        }
+        if let Some(sub_domain) = &self.sub_domain {
+            builder = builder.sub_domain(sub_domain);
+        }
+        for binding in &self.bindings {
+            builder = builder.tunnel(
+                &binding.sub_domain,
+                LocalService::new(&binding.local_host, binding.local_addr),
+            );
+        }
+
+        builder.build()
     }
 
     pub fn forward_url(&self) -> String {
+        if self.tunnel_type != TunnelType::Http {
+            return format!("{}:{}", &self.local_host, &self.local_port);
+        }
+
+        self.http_forward_url(&self.local_host, self.local_port)
+    }
+
+    pub fn binding_forward_url(&self, binding: &LocalBinding) -> String {
+        self.http_forward_url(&binding.local_host, binding.local_port)
+    }
+
+    fn http_forward_url(&self, local_host: &str, local_port: u16) -> String {
         let scheme = if self.use_tls { "https" } else { "http" };
-        format!("{}://{}:{}", &scheme, &self.local_host, &self.local_port)
+        format!("{}://{}:{}", &scheme, local_host, local_port)
     }
     pub fn ws_forward_url(&self) -> String {
         let scheme = if self.use_tls { "wss" } else { "ws" };
         format!("{}://{}:{}", scheme, &self.local_host, &self.local_port)
     }
 }
+
+fn resolve_local_addr(local_host: &str, local_port: u16) -> Result<SocketAddr, ()> {
+    match (local_host, local_port)
+        .to_socket_addrs()
+        .unwrap_or(vec![].into_iter())
+        .next()
+    {
+        Some(addr) => Ok(addr),
+        None => {
+            error!(
+                "An invalid local address was specified: {}:{}",
+                local_host, local_port
+            );
+            Err(())
+        }
+    }
+}
+
+/// Read a PEM encoded client certificate and its private key
+fn load_client_identity(cert: &Path, key: &Path) -> Result<ClientIdentity, String> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+    };
+    ClientIdentity::from_pem(&read(cert)?, &read(key)?).map_err(|e| e.to_string())
+}
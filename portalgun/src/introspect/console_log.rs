@@ -8,7 +8,7 @@ pub fn connect_failed() {
     eprintln!("{}", "CONNECTION REFUSED".red())
 }
 
-pub fn log(request: &httparse::Request, response: &httparse::Response) {
+pub fn log(request: &httparse::Request, response: &httparse::Response, remote: Option<&str>) {
     let out = match response.code {
         Some(code @ 200..=299) => format!("{}", code).green(),
         Some(code) => format!("{}", code).red(),
@@ -20,5 +20,10 @@ pub fn log(request: &httparse::Request, response: &httparse::Response) {
 
     eprint!("{}", out);
 
-    eprintln!("\t\t{}\t{}", method.to_uppercase().yellow(), path.blue());
+    eprint!("\t\t{}\t{}", method.to_uppercase().yellow(), path.blue());
+
+    match remote {
+        Some(remote) => eprintln!("\t{}", remote.dimmed()),
+        None => eprintln!(),
+    }
 }
@@ -7,7 +7,8 @@ pub mod console_log;
 pub use self::console_log::*;
 use super::*;
 
-use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use crate::client::{LocalService, Observer, StreamCapture, TunnelClient};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
 use futures::StreamExt;
 use hyper::Uri;
 use std::net::SocketAddr;
@@ -19,6 +20,7 @@ use warp::Filter;
 pub struct Request {
     id: String,
     status: u16,
+    #[allow(dead_code)]
     is_replay: bool,
     path: Option<String>,
     method: Option<String>,
@@ -29,6 +31,9 @@ pub struct Request {
     started: chrono::NaiveDateTime,
     completed: chrono::NaiveDateTime,
     entire_request: Vec<u8>,
+    remote_addr: Option<String>,
+    /// tunnel the request came in through, `None` for the primary one
+    binding: Option<String>,
 }
 
 impl Request {
@@ -46,7 +51,7 @@ lazy_static::lazy_static! {
     pub static ref REQUESTS:Arc<RwLock<HashMap<String, Request>>> = Arc::new(RwLock::new(HashMap::new()));
 }
 
-pub fn start_introspect_web_dashboard(config: Config) -> SocketAddr {
+pub fn start_introspect_web_dashboard(config: Config, client: TunnelClient) -> SocketAddr {
     let dash_addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], config.dashboard_port));
 
     let css = warp::get().and(warp::path!("static" / "css" / "styles.css").map(|| {
@@ -80,7 +85,7 @@ pub fn start_introspect_web_dashboard(config: Config) -> SocketAddr {
         .or(warp::post()
             .and(warp::path("replay"))
             .and(warp::path::param())
-            .and_then(move |id| replay_request(id, config.clone())))
+            .and_then(move |id| replay_request(id, client.clone())))
         .or(css)
         .or(logo);
 
@@ -91,20 +96,31 @@ pub fn start_introspect_web_dashboard(config: Config) -> SocketAddr {
     web_explorer_address
 }
 
-#[derive(Debug, Clone)]
-pub struct IntrospectChannels {
-    pub request: UnboundedSender<Vec<u8>>,
-    pub response: UnboundedSender<Vec<u8>>,
+/// Records http streams for the dashboard and logs them to the console
+pub struct Introspector;
+
+impl Observer for Introspector {
+    fn connect_failed(&self, _local: &LocalService, _error: &std::io::Error) {
+        connect_failed();
+    }
+
+    fn stream_opened(&self, metadata: Option<&StreamMetadata>) -> Option<StreamCapture> {
+        Some(introspect_stream(metadata))
+    }
 }
 
-pub fn introspect_stream() -> IntrospectChannels {
+fn introspect_stream(metadata: Option<&StreamMetadata>) -> StreamCapture {
     let id = Uuid::new_v4();
+    let remote_addr = metadata.and_then(StreamMetadata::client_ip);
+    let binding = metadata.and_then(|m| m.binding.clone());
     let (request_tx, request_rx) = unbounded::<Vec<u8>>();
     let (response_tx, response_rx) = unbounded::<Vec<u8>>();
 
-    tokio::spawn(async move { collect_stream(id, request_rx, response_rx).await });
+    tokio::spawn(
+        async move { collect_stream(id, remote_addr, binding, request_rx, response_rx).await },
+    );
 
-    IntrospectChannels {
+    StreamCapture {
         request: request_tx,
         response: response_tx,
     }
@@ -112,6 +128,8 @@ pub fn introspect_stream() -> IntrospectChannels {
 
 async fn collect_stream(
     id: Uuid,
+    remote_addr: Option<String>,
+    binding: Option<String>,
     mut request_rx: UnboundedReceiver<Vec<u8>>,
     mut response_rx: UnboundedReceiver<Vec<u8>>,
 ) {
@@ -150,7 +168,7 @@ async fn collect_stream(
     };
     let response_data = collected_response.as_slice()[parts_len..].to_vec();
 
-    console_log::log(&request, &response);
+    console_log::log(&request, &response, remote_addr.as_deref());
 
     let stored_request = Request {
         id: id.to_string(),
@@ -183,6 +201,8 @@ async fn collect_stream(
         completed: chrono::Local::now().naive_local(),
         is_replay: false,
         entire_request: collected_request,
+        remote_addr,
+        binding,
     };
 
     REQUESTS
@@ -226,7 +246,7 @@ enum DataType {
 
 async fn inspector() -> Result<Page<Inspector>, warp::reject::Rejection> {
     let mut requests: Vec<Request> = REQUESTS.read().unwrap().values().cloned().collect();
-    requests.sort_by(|a, b| b.completed.cmp(&a.completed));
+    requests.sort_by_key(|r| std::cmp::Reverse(r.completed));
     let inspect = Inspector { requests };
     Ok(Page(inspect))
 }
@@ -265,29 +285,19 @@ fn get_body_data(input: &[u8]) -> BodyData {
 
 async fn replay_request(
     rid: String,
-    config: Config,
+    client: TunnelClient,
 ) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
     let request: Request = match REQUESTS.read().unwrap().get(&rid) {
         Some(r) => r.clone(),
         None => return Err(warp::reject::not_found()),
     };
 
-    let (tx, rx) = unbounded::<ControlPacket>();
-    tokio::spawn(async move {
-        // keep the rx alive
-        let mut rx = rx;
-        while (rx.next().await).is_some() {
-            // do nothing
-        }
-    });
-
-    let tx = local::setup_new_stream(config, tx, StreamId::generate()).await;
-
-    // send the data to the stream
-    if let Some(mut tx) = tx {
-        let _ = tx.send(StreamMessage::Data(request.entire_request)).await;
-    } else {
-        error!("failed to replay request: local tunnel could not connect");
+    let metadata = StreamMetadata {
+        binding: request.binding,
+        ..Default::default()
+    };
+    if let Err(e) = client.replay(metadata, request.entire_request).await {
+        error!("failed to replay request: {}", e);
         return Err(warp::reject::not_found());
     }
 
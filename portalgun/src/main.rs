@@ -3,13 +3,6 @@
 //
 // SPDX-License-Identifier: MIT
 
-use futures::channel::mpsc::{unbounded, UnboundedSender};
-use futures::{SinkExt, StreamExt};
-
-use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
-
 use human_panic::setup_panic;
 pub use log::{debug, error, info, warn};
 
@@ -22,7 +15,6 @@ mod cli_ui;
 mod config;
 mod error;
 mod introspect;
-mod local;
 mod update;
 pub use self::error::*;
 
@@ -32,23 +24,12 @@ pub use config::*;
 pub use portalgun_lib::*;
 
 use crate::cli_ui::CliInterface;
+use crate::client::TunnelClient;
 use colored::Colorize;
-use futures::future::Either;
-use std::time::Duration;
-use tokio::sync::Mutex;
-
-pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, UnboundedSender<StreamMessage>>>>;
+use std::time::{Duration, Instant};
 
-lazy_static::lazy_static! {
-    pub static ref ACTIVE_STREAMS:ActiveStreams = Arc::new(RwLock::new(HashMap::new()));
-    pub static ref RECONNECT_TOKEN: Arc<Mutex<Option<ReconnectToken>>> = Arc::new(Mutex::new(None));
-}
-
-#[derive(Debug, Clone)]
-pub enum StreamMessage {
-    Data(Vec<u8>),
-    Close,
-}
+/// Wait this long before trying to refresh the access token again
+const TOKEN_REFRESH_RETRY: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() {
@@ -57,318 +38,131 @@ async fn main() {
         Err(_) => return,
     };
 
-    setup_panic!();
+    #[allow(deprecated)]
+    {
+        setup_panic!();
+    }
 
     update::check().await;
 
-    let introspect_dash_addr = introspect::start_introspect_web_dashboard(config.clone());
-
-    loop {
-        let (restart_tx, mut restart_rx) = unbounded();
-        let wormhole = run_wormhole(config.clone(), introspect_dash_addr, restart_tx);
-        let result = futures::future::select(Box::pin(wormhole), restart_rx.next()).await;
-        config.first_run = false;
-
-        match result {
-            Either::Left((Err(e), _)) => match e {
-                Error::WebSocketError(_) | Error::NoResponseFromServer | Error::Timeout => {
-                    error!("Control error: {:?}. Retrying in 5 seconds.", e);
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                }
-                Error::AuthenticationFailed => {
-                    if config.secret_key.is_none() {
-                        eprintln!(
-                            ">> {}",
-                            "Please use an access key with the `--key` option".yellow()
-                        );
-                        eprintln!(
-                            ">> {}{}",
-                            "You can get your access key here: ".yellow(),
-                            "https://dashboard.tunnelto.dev".yellow().underline()
-                        );
-                    } else {
-                        eprintln!(
-                            ">> {}{}",
-                            "Please check your access key at ".yellow(),
-                            "https://dashboard.tunnelto.dev".yellow().underline()
-                        );
-                    }
-                    eprintln!("\nError: {}", format!("{}", e).red());
-                    return;
-                }
-                _ => {
-                    eprintln!("Error: {}", format!("{}", e).red());
-                    return;
-                }
-            },
-            Either::Right((Some(e), _)) => {
-                warn!("restarting in 3 seconds...from error: {:?}", e);
-                tokio::time::sleep(Duration::from_secs(3)).await;
-            }
-            _ => {}
-        };
+    let client = match config.tunnel_client() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Error: {}", format!("{}", e).red());
+            return;
+        }
+    };
+    let introspect_dash_addr =
+        introspect::start_introspect_web_dashboard(config.clone(), client.clone());
 
-        info!("restarting wormhole");
+    if let Some(session) = &config.oidc_session {
+        tokio::spawn(reauthenticate(session.clone(), client.clone()));
     }
-}
 
-/// Setup the tunnel to our control server
-async fn run_wormhole(
-    config: Config,
-    introspect_web_addr: SocketAddr,
-    mut restart_tx: UnboundedSender<Option<Error>>,
-) -> Result<(), Error> {
-    let interface = CliInterface::start(config.clone(), introspect_web_addr);
-    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-    let Wormhole {
-        websocket,
-        sub_domain,
-        hostname,
-    } = connect_to_wormhole(&config).await?;
-
-    interface.did_connect(&sub_domain, &hostname);
-
-    // split reading and writing
-    let (mut ws_sink, mut ws_stream) = websocket.split();
-
-    // tunnel channel
-    let (tunnel_tx, mut tunnel_rx) = unbounded::<ControlPacket>();
-
-    // continuously write to websocket tunnel
-    let mut restart = restart_tx.clone();
-    tokio::spawn(async move {
-        loop {
-            let packet = match tunnel_rx.next().await {
-                Some(data) => data,
-                None => {
-                    warn!("control flow didn't send anything!");
-                    let _ = restart.send(Some(Error::Timeout)).await;
+    loop {
+        // don't replay an access token that expired while we were connected
+        if let (false, Some(session)) = (config.first_run, &config.oidc_session) {
+            match session.access_token().await {
+                Ok(access_token) => client.set_token(access_token),
+                Err(e) if e.needs_login() => {
+                    ask_to_log_in(&e);
                     return;
                 }
-            };
-
-            if let Err(e) = ws_sink.send(Message::binary(packet.serialize())).await {
-                warn!("failed to write message to tunnel websocket: {:?}", e);
-                let _ = restart.send(Some(Error::WebSocketError(e))).await;
-                return;
+                Err(e) => warn!("Failed to refresh the access token: {}", e),
             }
         }
-    });
 
-    // continuously read from websocket tunnel
+        let result = run_wormhole(&config, &client, introspect_dash_addr).await;
+        config.first_run = false;
 
-    loop {
-        match ws_stream.next().await {
-            Some(Ok(message)) if message.is_close() => {
-                debug!("got close message");
-                let _ = restart_tx.send(None).await;
-                return Ok(());
+        match result {
+            Err(e) if e.is_transient() => {
+                error!("Control error: {:?}. Retrying in 5 seconds.", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
             }
-            Some(Ok(message)) => {
-                let packet = process_control_flow_message(
-                    config.clone(),
-                    tunnel_tx.clone(),
-                    message.into_data(),
-                )
-                .await
-                .map_err(|e| {
-                    error!("Malformed protocol control packet: {:?}", e);
-                    Error::MalformedMessageFromServer
-                })?;
-                debug!("Processed packet: {:?}", packet.packet_type());
+            Err(e) if e.needs_login() => {
+                ask_to_log_in(&e);
+                return;
             }
-            Some(Err(e)) => {
-                warn!("websocket read error: {:?}", e);
-                return Err(Error::Timeout);
+            Err(e @ client::Error::AuthenticationFailed) => {
+                if config.secret_key.is_none() {
+                    eprintln!(
+                        ">> {}",
+                        "Please use an access key with the `--key` option".yellow()
+                    );
+                    eprintln!(
+                        ">> {}{}",
+                        "You can get your access key here: ".yellow(),
+                        "https://dashboard.tunnelto.dev".yellow().underline()
+                    );
+                } else {
+                    eprintln!(
+                        ">> {}{}",
+                        "Please check your access key at ".yellow(),
+                        "https://dashboard.tunnelto.dev".yellow().underline()
+                    );
+                }
+                eprintln!("\nError: {}", format!("{}", e).red());
+                return;
             }
-            None => {
-                warn!("websocket sent none");
-                return Err(Error::Timeout);
+            Err(e) => {
+                eprintln!("Error: {}", format!("{}", e).red());
+                return;
             }
-        }
-    }
-}
-
-pub async fn get_auth_info(control_url: &str) -> Result<(String, String, Vec<String>), Error> {
-    let (mut websocket, _) = tokio_tungstenite::connect_async(control_url).await?;
-
-    // send our Client Hello message
-    let client_hello = ClientHello::generate(None, ClientType::AuthInfo);
-
-    info!("connecting to wormhole...");
-
-    let hello = serde_json::to_vec(&client_hello).unwrap();
-    websocket
-        .send(Message::binary(hello))
-        .await
-        .expect("Failed to send client hello to wormhole server.");
-
-    // wait for Server hello
-    let server_hello_data = websocket
-        .next()
-        .await
-        .ok_or(Error::NoResponseFromServer)??
-        .into_data();
-    let server_hello = serde_json::from_slice::<ServerHello>(&server_hello_data).map_err(|e| {
-        error!("Couldn't parse server_hello from {:?}", e);
-        Error::ServerReplyInvalid
-    })?;
-
-    websocket.close(None).await?;
+            Ok(()) => {}
+        };
 
-    match server_hello {
-        ServerHello::AuthInfo {
-            oidc_client_id,
-            oidc_discovery,
-            oidc_scopes,
-        } => Ok((oidc_client_id, oidc_discovery, oidc_scopes)),
-        _ => Err(Error::AuthenticationFailed),
+        info!("restarting wormhole");
     }
 }
 
-struct Wormhole {
-    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
-    sub_domain: String,
-    hostname: String,
-}
-
-async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
-    let (mut websocket, _) = tokio_tungstenite::connect_async(&config.control_url).await?;
-
-    // send our Client Hello message
-    let client_hello = match config.secret_key.clone() {
-        Some(secret_key) => ClientHello::generate(
-            config.sub_domain.clone(),
-            ClientType::Auth { key: secret_key },
-        ),
-        None => {
-            // if we have a reconnect token, use it.
-            if let Some(reconnect) = RECONNECT_TOKEN.lock().await.clone() {
-                ClientHello::reconnect(reconnect)
-            } else {
-                ClientHello::generate(config.sub_domain.clone(), ClientType::Anonymous)
+/// Refresh the access token before it expires, handing the fresh one to the
+/// server so the tunnel stays open
+async fn reauthenticate(session: OidcSession, client: TunnelClient) {
+    loop {
+        let refresh_at = session.refresh_at().await.unwrap_or_else(Instant::now);
+        tokio::time::sleep_until(refresh_at.into()).await;
+
+        match session.access_token().await {
+            Ok(access_token) => client.set_token(access_token),
+            // the tunnel stays open until the server sees the current token expire
+            Err(e) if e.needs_login() => {
+                ask_to_log_in(&e);
+                return;
             }
-        }
-    };
-
-    info!("connecting to wormhole...");
-
-    let hello = serde_json::to_vec(&client_hello).unwrap();
-    websocket
-        .send(Message::binary(hello))
-        .await
-        .expect("Failed to send client hello to wormhole server.");
-
-    // wait for Server hello
-    let server_hello_data = websocket
-        .next()
-        .await
-        .ok_or(Error::NoResponseFromServer)??
-        .into_data();
-    let server_hello = serde_json::from_slice::<ServerHello>(&server_hello_data).map_err(|e| {
-        error!("Couldn't parse server_hello from {:?}", e);
-        Error::ServerReplyInvalid
-    })?;
-
-    let (sub_domain, hostname) = match server_hello {
-        ServerHello::Success {
-            sub_domain,
-            client_id,
-            hostname,
-        } => {
-            info!("Server accepted our connection. I am client_{}", client_id);
-            (sub_domain, hostname)
-        }
-        ServerHello::AuthFailed => {
-            return Err(Error::AuthenticationFailed);
-        }
-        ServerHello::InvalidSubDomain => {
-            return Err(Error::InvalidSubDomain);
-        }
-        ServerHello::SubDomainInUse => {
-            return Err(Error::SubDomainInUse);
-        }
-        ServerHello::AuthInfo { .. } => {
-            // Huh, how did we get here?
-            return Err(Error::MalformedMessageFromServer);
-        }
-        ServerHello::Error(error) => return Err(Error::ServerError(error)),
-    };
-
-    Ok(Wormhole {
-        websocket,
-        sub_domain,
-        hostname,
-    })
-}
-
-async fn process_control_flow_message(
-    config: Config,
-    mut tunnel_tx: UnboundedSender<ControlPacket>,
-    payload: Vec<u8>,
-) -> Result<ControlPacket, Box<dyn std::error::Error>> {
-    let control_packet = ControlPacket::deserialize(&payload)?;
-
-    match &control_packet {
-        ControlPacket::Init(stream_id) => {
-            info!("stream[{:?}] -> init", stream_id.to_string());
-        }
-        ControlPacket::Ping(reconnect_token) => {
-            log::info!("got ping. reconnect_token={}", reconnect_token.is_some());
-
-            if let Some(reconnect) = reconnect_token {
-                let _ = RECONNECT_TOKEN.lock().await.replace(reconnect.clone());
+            Err(e) => {
+                warn!("Failed to refresh the access token: {}", e);
+                tokio::time::sleep(TOKEN_REFRESH_RETRY).await;
             }
-            let _ = tunnel_tx.send(ControlPacket::Ping(None)).await;
-        }
-        ControlPacket::Refused(_) => return Err("unexpected control packet".into()),
-        ControlPacket::End(stream_id) => {
-            // find the stream
-            let stream_id = stream_id.clone();
-
-            info!("got end stream [{:?}]", &stream_id);
-
-            tokio::spawn(async move {
-                let stream = ACTIVE_STREAMS.read().unwrap().get(&stream_id).cloned();
-                if let Some(mut tx) = stream {
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                    let _ = tx.send(StreamMessage::Close).await.map_err(|e| {
-                        error!("failed to send stream close: {:?}", e);
-                    });
-                    ACTIVE_STREAMS.write().unwrap().remove(&stream_id);
-                }
-            });
         }
-        ControlPacket::Data(stream_id, data) => {
-            info!(
-                "stream[{:?}] -> new data: {:?}",
-                stream_id.to_string(),
-                data.len()
-            );
+    }
+}
 
-            if !ACTIVE_STREAMS.read().unwrap().contains_key(stream_id)
-                && local::setup_new_stream(config.clone(), tunnel_tx.clone(), stream_id.clone())
-                    .await
-                    .is_none()
-            {
-                error!("failed to open local tunnel")
-            }
+/// Tell the user their login is gone, and why
+fn ask_to_log_in(error: &dyn std::fmt::Display) {
+    eprintln!(
+        ">> {}",
+        "Please log in again with `portalgun login`".yellow()
+    );
+    eprintln!("\nError: {}", format!("{}", error).red());
+}
 
-            // find the right stream
-            let active_stream = ACTIVE_STREAMS.read().unwrap().get(stream_id).cloned();
+/// Setup the tunnel to our control server
+async fn run_wormhole(
+    config: &Config,
+    client: &TunnelClient,
+    introspect_web_addr: SocketAddr,
+) -> Result<(), client::Error> {
+    let interface = CliInterface::start(config.clone(), introspect_web_addr);
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let connection = client.connect().await?;
 
-            // forward data to it
-            if let Some(mut tx) = active_stream {
-                tx.send(StreamMessage::Data(data.clone())).await?;
-                info!("forwarded to local tcp ({})", stream_id.to_string());
-            } else {
-                error!("got data but no stream to send it to.");
-                tunnel_tx
-                    .send(ControlPacket::Refused(stream_id.clone()))
-                    .await?;
-            }
-        }
-    };
+    let connected = connection.info();
+    info!(
+        "using protocol version {} with capabilities [{}]",
+        connected.protocol_version, connected.capabilities
+    );
+    interface.did_connect(connected);
 
-    Ok(control_packet.clone())
+    connection.serve().await
 }
@@ -3,15 +3,31 @@
 // SPDX-License-Identifier: MIT
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use url::Url;
 
-use oauth2::basic::BasicClient;
+use oauth2::basic::{BasicClient, BasicErrorResponseType};
 use oauth2::devicecode::StandardDeviceAuthorizationResponse;
 use oauth2::reqwest::async_http_client;
 use oauth2::{
-    AuthUrl, ClientId, DeviceAuthorizationUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
+    AuthUrl, AuthorizationCode, ClientId, CsrfToken, DeviceAuthorizationUrl, PkceCodeChallenge,
+    RedirectUrl, RefreshToken, RequestTokenError, Scope, TokenResponse, TokenUrl,
 };
 
+/// How long to wait for the user to log in with their browser
+const BROWSER_LOGIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long a connection to the callback listener has to send its request
+const BROWSER_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Path of the loopback listener the provider redirects the browser to
+const BROWSER_CALLBACK_PATH: &str = "/callback";
+
 // Minimum OIDC Discovery parse struct
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OIDCDiscovery {
@@ -20,24 +36,36 @@ pub struct OIDCDiscovery {
     pub device_authorization_endpoint: Option<Url>, // Not included in OIDC standard, but used by some OAuth2 providers
 }
 
-pub async fn authorize(
+/// Create an OAuth2 client for the provider of `discovery_url`, by specifying the client ID,
+/// client secret, authorization URL and token URL
+async fn oauth_client(
     discovery_url: &str,
     client_id: &str,
-    scopes: Vec<String>,
-) -> Result<String, crate::Error> {
+) -> Result<(BasicClient, OIDCDiscovery), crate::Error> {
     let discovery: OIDCDiscovery = reqwest::get(discovery_url).await?.json().await?;
 
-    // Create an OAuth2 client by specifying the client ID, client secret, authorization URL and
-    // token URL.
     let client = BasicClient::new(
         ClientId::new(client_id.to_string()),
         None,
-        AuthUrl::from_url(discovery.authorization_endpoint),
-        Some(TokenUrl::from_url(discovery.token_endpoint.ok_or_else(
-            || crate::Error::OAuth2("Token endpoint not found".to_owned()),
-        )?)),
-    )
-    .set_device_authorization_url(DeviceAuthorizationUrl::from_url(
+        AuthUrl::from_url(discovery.authorization_endpoint.clone()),
+        Some(TokenUrl::from_url(
+            discovery
+                .token_endpoint
+                .clone()
+                .ok_or_else(|| crate::Error::OAuth2("Token endpoint not found".to_owned()))?,
+        )),
+    );
+
+    Ok((client, discovery))
+}
+
+pub async fn authorize(
+    discovery_url: &str,
+    client_id: &str,
+    scopes: Vec<String>,
+) -> Result<String, crate::Error> {
+    let (client, discovery) = oauth_client(discovery_url, client_id).await?;
+    let client = client.set_device_authorization_url(DeviceAuthorizationUrl::from_url(
         discovery.device_authorization_endpoint.ok_or_else(|| {
             crate::Error::OAuth2("Device authorization endpoint not found".to_owned())
         })?,
@@ -82,37 +110,211 @@ pub async fn authorize(
         .clone())
 }
 
+/// Log in with the authorization code flow and PKCE, for providers without
+/// device authorization. The provider redirects the browser to a listener on
+/// `127.0.0.1`, which receives the code.
+pub async fn authorize_in_browser(
+    discovery_url: &str,
+    client_id: &str,
+    scopes: Vec<String>,
+) -> Result<String, crate::Error> {
+    let (client, _) = oauth_client(discovery_url, client_id).await?;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let redirect_url = format!(
+        "http://127.0.0.1:{}{}",
+        listener.local_addr()?.port(),
+        BROWSER_CALLBACK_PATH
+    );
+    let client = client.set_redirect_uri(
+        RedirectUrl::new(redirect_url)
+            .map_err(|e| crate::Error::OAuth2(format!("Error: {}", e)))?,
+    );
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let mut auth_request = client
+        .authorize_url(CsrfToken::new_random)
+        .set_pkce_challenge(pkce_challenge);
+    for scope in scopes {
+        auth_request = auth_request.add_scope(Scope::new(scope));
+    }
+    let (auth_url, csrf_token) = auth_request.url();
+
+    eprintln!("Open this URL in your browser:\n{}", auth_url);
+    open_browser(auth_url.as_str());
+
+    let code = tokio::time::timeout(BROWSER_LOGIN_TIMEOUT, receive_code(&listener, &csrf_token))
+        .await
+        .map_err(|_| {
+            crate::Error::OAuth2("Timed out waiting for the browser login".to_owned())
+        })??;
+
+    let token_result = client
+        .exchange_code(code)
+        .set_pkce_verifier(pkce_verifier)
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| crate::Error::OAuth2(format!("Error: {}", e)))?;
+
+    Ok(token_result
+        .refresh_token()
+        .ok_or_else(|| crate::Error::OAuth2("Refresh token not found".to_owned()))?
+        .secret()
+        .clone())
+}
+
+/// Open `url` in the default browser. Users open it themselves when that fails.
+fn open_browser(url: &str) {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("rundll32");
+        command.arg("url.dll,FileProtocolHandler");
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+
+    let result = command
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Err(e) = result {
+        log::debug!("failed to open a browser: {}", e);
+    }
+}
+
+/// Wait for the provider to send the browser back with the authorization code
+async fn receive_code(
+    listener: &TcpListener,
+    csrf_token: &CsrfToken,
+) -> Result<AuthorizationCode, crate::Error> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        // don't let a connection that never sends a request hold up the others
+        let target =
+            match tokio::time::timeout(BROWSER_REQUEST_TIMEOUT, read_request_target(&mut socket))
+                .await
+            {
+                Ok(Some(target)) => target,
+                Ok(None) | Err(_) => continue,
+            };
+
+        let url = match Url::parse("http://127.0.0.1").and_then(|base| base.join(&target)) {
+            Ok(url) if url.path() == BROWSER_CALLBACK_PATH => url,
+            _ => {
+                respond(&mut socket, "404 Not Found", "Not found.").await;
+                continue;
+            }
+        };
+
+        // ignore requests that didn't come from our login, i.e. forged by another site
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        if params.get("state") != Some(csrf_token.secret()) {
+            respond(&mut socket, "400 Bad Request", "Unexpected login state.").await;
+            continue;
+        }
+
+        if let Some(error) = params.get("error") {
+            respond(
+                &mut socket,
+                "200 OK",
+                "Login failed, please return to portalgun.",
+            )
+            .await;
+            let description = params.get("error_description").cloned();
+            return Err(crate::Error::OAuth2(format!(
+                "Error: {}",
+                description.unwrap_or_else(|| error.clone())
+            )));
+        }
+
+        if let Some(code) = params.get("code") {
+            respond(
+                &mut socket,
+                "200 OK",
+                "Logged in, you may close this window.",
+            )
+            .await;
+            return Ok(AuthorizationCode::new(code.clone()));
+        }
+
+        respond(
+            &mut socket,
+            "400 Bad Request",
+            "No authorization code was given.",
+        )
+        .await;
+    }
+}
+
+/// The request target of an http request, `None` if it is malformed
+async fn read_request_target(socket: &mut TcpStream) -> Option<String> {
+    let mut buf = vec![0; 8192];
+    let mut len = 0;
+
+    loop {
+        let read = socket.read(&mut buf[len..]).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        len += read;
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf[..len]).ok()? {
+            httparse::Status::Complete(_) => return request.path.map(str::to_owned),
+            httparse::Status::Partial if len < buf.len() => continue,
+            httparse::Status::Partial => return None,
+        }
+    }
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, message: &str) {
+    let body = format!("<html><body><p>{}</p></body></html>", message);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = socket.write_all(response.as_bytes()).await;
+}
+
+/// Tokens issued in exchange for a refresh token
+pub struct Tokens {
+    pub access_token: String,
+    /// replaces the refresh token that was used, if the provider rotates them
+    pub refresh_token: Option<String>,
+    /// how long the access token is valid for, if the provider said so
+    pub expires_in: Option<Duration>,
+}
+
 pub async fn fetch_token(
     discovery_url: &str,
     client_id: &str,
     refresh_token: &str,
-) -> Result<(String, Option<String>), crate::Error> {
-    let discovery: OIDCDiscovery = reqwest::get(discovery_url).await?.json().await?;
-
-    // Create an OAuth2 client by specifying the client ID, client secret, authorization URL and
-    // token URL.
-    let client = BasicClient::new(
-        ClientId::new(client_id.to_string()),
-        None,
-        AuthUrl::from_url(discovery.authorization_endpoint),
-        Some(TokenUrl::from_url(discovery.token_endpoint.ok_or_else(
-            || crate::Error::OAuth2("Token endpoint not found".to_owned()),
-        )?)),
-    )
-    .set_device_authorization_url(DeviceAuthorizationUrl::from_url(
-        discovery.device_authorization_endpoint.ok_or_else(|| {
-            crate::Error::OAuth2("Device authorization endpoint not found".to_owned())
-        })?,
-    ));
+) -> Result<Tokens, crate::Error> {
+    let (client, _) = oauth_client(discovery_url, client_id).await?;
 
     let token_result = client
         .exchange_refresh_token(&RefreshToken::new(refresh_token.to_owned()))
         .request_async(async_http_client)
         .await
-        .map_err(|e| crate::Error::OAuth2(format!("Error: {}", e)))?;
+        .map_err(|e| match &e {
+            // the refresh token expired or was revoked
+            RequestTokenError::ServerResponse(response)
+                if *response.error() == BasicErrorResponseType::InvalidGrant =>
+            {
+                crate::Error::LoginExpired(format!("Error: {}", e))
+            }
+            _ => crate::Error::OAuth2(format!("Error: {}", e)),
+        })?;
 
-    Ok((
-        token_result.access_token().secret().clone(),
-        token_result.refresh_token().map(|t| t.secret().to_owned()),
-    ))
+    Ok(Tokens {
+        access_token: token_result.access_token().secret().clone(),
+        refresh_token: token_result.refresh_token().map(|t| t.secret().to_owned()),
+        expires_in: token_result.expires_in(),
+    })
 }
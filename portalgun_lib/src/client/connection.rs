@@ -0,0 +1,590 @@
+// SPDX-FileCopyrightText: 2023 perillamint <perillamint@silicon.moe>
+// SPDX-FileCopyrightText: 2020-2022 Alex Grinman <me@alexgr.in>
+//
+// SPDX-License-Identifier: MIT
+
+use super::*;
+use futures::channel::mpsc::channel;
+use futures::stream::SplitStream;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// What the server told us about the tunnels it opened
+#[derive(Debug, Clone)]
+pub struct Connected {
+    pub client_id: ClientId,
+    pub sub_domain: String,
+    pub hostname: String,
+    /// public port of tcp and udp tunnels
+    pub port: Option<u16>,
+    pub protocol_version: u32,
+    pub capabilities: Capabilities,
+    /// additional http tunnels bound over the same connection
+    pub tunnels: Vec<BoundTunnel>,
+    /// URL visitors use to reach the primary tunnel
+    pub public_url: String,
+    scheme: &'static str,
+}
+
+impl Connected {
+    /// URL visitors use to reach an additional tunnel
+    pub fn tunnel_url(&self, tunnel: &BoundTunnel) -> String {
+        format!("{}://{}", self.scheme, tunnel.hostname)
+    }
+}
+
+/// A control connection the server accepted, see [`TunnelClient::connect`]
+pub struct Connection {
+    client: TunnelClient,
+    websocket: WebSocket,
+    info: Connected,
+}
+
+/// How the server wants clients to log in
+#[derive(Debug, Clone)]
+pub enum AuthInfo {
+    /// Log in with the server's OpenID Connect provider
+    Oidc {
+        oidc_client_id: String,
+        oidc_discovery: String,
+        oidc_scopes: Vec<String>,
+    },
+    /// Use an access token issued out of band, i.e. an API key
+    Token,
+}
+
+/// Ask the server at `control_url` how to log in
+pub async fn auth_info(control_url: &str) -> Result<AuthInfo, Error> {
+    let (mut websocket, _) = tokio_tungstenite::connect_async(control_url).await?;
+
+    // send our Client Hello message
+    let client_hello = ClientHello::generate(None, ClientType::AuthInfo);
+
+    info!("connecting to wormhole...");
+
+    let server_hello = exchange_hello(&mut websocket, &client_hello).await?;
+    websocket.close(None).await?;
+
+    match server_hello {
+        ServerHello::AuthInfo {
+            oidc_client_id,
+            oidc_discovery,
+            oidc_scopes,
+        } => Ok(AuthInfo::Oidc {
+            oidc_client_id,
+            oidc_discovery,
+            oidc_scopes,
+        }),
+        ServerHello::TokenAuthInfo => Ok(AuthInfo::Token),
+        ServerHello::UnsupportedProtocolVersion {
+            min_version,
+            max_version,
+        } => Err(Error::UnsupportedProtocolVersion {
+            min_version,
+            max_version,
+        }),
+        ServerHello::Error(error) => Err(Error::ServerError(error)),
+        ServerHello::Rejected { code, detail } => Err(Error::Rejected(code, detail)),
+        _ => Err(Error::AuthenticationFailed),
+    }
+}
+
+/// Send our hello and wait for the server's
+async fn exchange_hello(
+    websocket: &mut WebSocket,
+    client_hello: &ClientHello,
+) -> Result<ServerHello, Error> {
+    let hello = serde_json::to_vec(client_hello).unwrap_or_default();
+    websocket.send(Message::binary(hello)).await?;
+
+    // wait for Server hello
+    let server_hello_data = websocket
+        .next()
+        .await
+        .ok_or(Error::NoResponseFromServer)??
+        .into_data();
+    serde_json::from_slice::<ServerHello>(&server_hello_data).map_err(|e| {
+        error!("Couldn't parse server_hello from {:?}", e);
+        Error::ServerReplyInvalid
+    })
+}
+
+impl TunnelClient {
+    /// Open a control connection and have the server bind our tunnels
+    pub async fn connect(&self) -> Result<Connection, Error> {
+        let settings = &self.inner.settings;
+        let connector = settings
+            .identity
+            .as_ref()
+            .map(ClientIdentity::connector)
+            .transpose()?;
+        let (mut websocket, _) = tokio_tungstenite::connect_async_tls_with_config(
+            &settings.control_url,
+            None,
+            false,
+            connector,
+        )
+        .await?;
+
+        // send our Client Hello message
+        let reconnect_token = self.inner.reconnect_token.lock().unwrap().clone();
+        let token = self.inner.token.lock().unwrap().clone();
+        let client_hello = match (token, reconnect_token) {
+            (Some(secret_key), _) => ClientHello::generate(
+                settings.sub_domain.clone(),
+                ClientType::Auth { key: secret_key },
+            ),
+            (None, _) if settings.identity.is_some() => {
+                ClientHello::generate(settings.sub_domain.clone(), ClientType::Certificate)
+            }
+            // if we have a reconnect token, use it.
+            (None, Some(reconnect)) => ClientHello::reconnect(reconnect),
+            (None, None) => {
+                ClientHello::generate(settings.sub_domain.clone(), ClientType::Anonymous)
+            }
+        }
+        .with_tunnel_type(settings.tunnel_type)
+        .with_reservation(settings.reserve)
+        .with_tunnels(
+            settings
+                .bindings
+                .iter()
+                .map(|(sub_domain, _)| TunnelBinding {
+                    name: sub_domain.clone(),
+                    sub_domain: Some(sub_domain.clone()),
+                })
+                .collect(),
+        );
+
+        info!("connecting to wormhole...");
+
+        let (sub_domain, client_id, hostname, protocol_version, capabilities, port, tunnels) =
+            match exchange_hello(&mut websocket, &client_hello).await? {
+                ServerHello::Success {
+                    sub_domain,
+                    client_id,
+                    hostname,
+                    protocol_version,
+                    capabilities,
+                    port,
+                    tunnels,
+                } => {
+                    info!("Server accepted our connection. I am client_{}", client_id);
+                    (
+                        sub_domain,
+                        client_id,
+                        hostname,
+                        protocol_version,
+                        capabilities,
+                        port,
+                        tunnels,
+                    )
+                }
+                ServerHello::AuthFailed => {
+                    return Err(Error::AuthenticationFailed);
+                }
+                ServerHello::InvalidSubDomain => {
+                    return Err(Error::InvalidSubDomain);
+                }
+                ServerHello::SubDomainInUse => {
+                    return Err(Error::SubDomainInUse);
+                }
+                ServerHello::AuthInfo { .. } | ServerHello::TokenAuthInfo => {
+                    // Huh, how did we get here?
+                    return Err(Error::MalformedMessageFromServer);
+                }
+                ServerHello::Error(error) => return Err(Error::ServerError(error)),
+                ServerHello::Rejected { code, detail } => {
+                    return Err(Error::Rejected(code, detail))
+                }
+                ServerHello::UnsupportedProtocolVersion {
+                    min_version,
+                    max_version,
+                } => {
+                    return Err(Error::UnsupportedProtocolVersion {
+                        min_version,
+                        max_version,
+                    })
+                }
+            };
+
+        // an older server would have silently handed us an http tunnel
+        if let Some(required) = settings.tunnel_type.capability() {
+            if !capabilities.has(required) || port.is_none() {
+                return Err(Error::TunnelTypeUnsupported(settings.tunnel_type));
+            }
+        }
+
+        // an older server ignores the extra tunnels we asked for
+        if !settings.bindings.is_empty()
+            && (!capabilities.has(Capability::MultipleTunnels)
+                || tunnels.len() != settings.bindings.len())
+        {
+            return Err(Error::MultipleTunnelsUnsupported);
+        }
+
+        // an older server ignores the reservation, but the tunnel works all the same
+        if settings.reserve && !capabilities.has(Capability::Reservations) {
+            warn!("the server does not support reservations, the sub-domain was not reserved");
+        }
+
+        let scheme = if settings.control_url.scheme() == "ws" {
+            "http"
+        } else {
+            "https"
+        };
+        let public_url = match port {
+            Some(port) => format!("{}:{}", hostname, port),
+            None => format!("{}://{}", scheme, hostname),
+        };
+
+        Ok(Connection {
+            client: self.clone(),
+            websocket,
+            info: Connected {
+                client_id,
+                sub_domain,
+                hostname,
+                port,
+                protocol_version,
+                capabilities,
+                tunnels,
+                public_url,
+                scheme,
+            },
+        })
+    }
+
+    /// Keep serving streams on a connection the server is going away from
+    async fn drain(
+        self,
+        capabilities: Capabilities,
+        tunnel_tx: Sender<ControlPacket>,
+        mut ws_stream: SplitStream<WebSocket>,
+        _open: oneshot::Sender<()>,
+        deadline: Duration,
+    ) {
+        let serve = async {
+            while let Some(Ok(message)) = ws_stream.next().await {
+                if message.is_close() {
+                    break;
+                }
+
+                if let Err(e) = self
+                    .handle_packet(&capabilities, tunnel_tx.clone(), message.into_data())
+                    .await
+                {
+                    error!("Malformed protocol control packet: {:?}", e);
+                    break;
+                }
+            }
+        };
+
+        let _ = tokio::time::timeout(deadline, serve).await;
+        info!("previous connection drained");
+    }
+
+    async fn handle_packet(
+        &self,
+        capabilities: &Capabilities,
+        mut tunnel_tx: Sender<ControlPacket>,
+        payload: Vec<u8>,
+    ) -> Result<ControlPacket, Box<dyn std::error::Error + Send + Sync>> {
+        let control_packet = ControlPacket::deserialize(&payload).map_err(|e| e.to_string())?;
+        let streams = &self.inner.streams;
+        let pending_streams = &self.inner.pending_streams;
+
+        match &control_packet {
+            ControlPacket::Init(stream_id, metadata) => {
+                let remote = metadata.as_ref().and_then(StreamMetadata::client_ip);
+                info!(
+                    "stream[{:?}] -> init from {}",
+                    stream_id.to_string(),
+                    remote.as_deref().unwrap_or("unknown")
+                );
+
+                // raw tcp services may speak first, i.e. greet with a banner, so don't
+                // wait for the visitor's first bytes to connect to them
+                if self.inner.settings.tunnel_type == TunnelType::Tcp {
+                    if local::setup_new_stream(
+                        self,
+                        capabilities,
+                        tunnel_tx.clone(),
+                        stream_id.clone(),
+                        metadata.clone(),
+                    )
+                    .await
+                    .is_none()
+                    {
+                        error!("failed to open local tunnel")
+                    }
+                } else if let Some(metadata) = metadata {
+                    pending_streams
+                        .write()
+                        .unwrap()
+                        .insert(stream_id.clone(), metadata.clone());
+                }
+            }
+            ControlPacket::Ping(reconnect_token) => {
+                info!("got ping. reconnect_token={}", reconnect_token.is_some());
+
+                if let Some(reconnect) = reconnect_token {
+                    let _ = self
+                        .inner
+                        .reconnect_token
+                        .lock()
+                        .unwrap()
+                        .replace(reconnect.clone());
+                }
+                let _ = tunnel_tx.send(ControlPacket::Ping(None)).await;
+            }
+            ControlPacket::Refused(_) | ControlPacket::Reauthenticate(_) => {
+                return Err("unexpected control packet".into())
+            }
+            ControlPacket::GoAway(deadline) => {
+                info!("server is going away in {}s, reconnecting", deadline);
+            }
+            ControlPacket::Datagram(stream_id, data) => {
+                debug!(
+                    "stream[{:?}] -> datagram: {:?}",
+                    stream_id.to_string(),
+                    data.len()
+                );
+                udp::forward_datagram(self, tunnel_tx.clone(), stream_id.clone(), data.clone())
+                    .await;
+            }
+            ControlPacket::WindowUpdate(stream_id, credit) => {
+                debug!(
+                    "stream[{:?}] -> window update: {}",
+                    stream_id.to_string(),
+                    credit
+                );
+                if let Some(stream) = streams.read().unwrap().get(stream_id) {
+                    stream.send_window.grant(*credit);
+                }
+            }
+            ControlPacket::End(stream_id) if capabilities.has(Capability::HalfClose) => {
+                info!("got end stream [{:?}]", stream_id);
+                pending_streams.write().unwrap().remove(stream_id);
+
+                // only our writes to the local service are done, it may still answer
+                let stream = streams.read().unwrap().get(stream_id).cloned();
+                if let Some(mut stream) = stream {
+                    let _ = stream.tx.send(local::StreamMessage::Close).await;
+                }
+            }
+            ControlPacket::Reset(stream_id) => {
+                info!("got reset stream [{:?}]", stream_id);
+                pending_streams.write().unwrap().remove(stream_id);
+
+                let stream = streams.write().unwrap().remove(stream_id);
+                if let Some(mut stream) = stream {
+                    stream.send_window.close();
+                    let _ = stream.tx.send(local::StreamMessage::Close).await;
+                }
+            }
+            ControlPacket::End(stream_id) => {
+                // find the stream
+                let stream_id = stream_id.clone();
+
+                info!("got end stream [{:?}]", &stream_id);
+                pending_streams.write().unwrap().remove(&stream_id);
+
+                let client = self.clone();
+                tokio::spawn(async move {
+                    let streams = &client.inner.streams;
+                    let stream = streams.read().unwrap().get(&stream_id).cloned();
+                    if let Some(mut stream) = stream {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        let _ = stream
+                            .tx
+                            .send(local::StreamMessage::Close)
+                            .await
+                            .map_err(|e| {
+                                error!("failed to send stream close: {:?}", e);
+                            });
+                        stream.send_window.close();
+                        streams.write().unwrap().remove(&stream_id);
+                    }
+                });
+            }
+            ControlPacket::Data(stream_id, data) => {
+                info!(
+                    "stream[{:?}] -> new data: {:?}",
+                    stream_id.to_string(),
+                    data.len()
+                );
+
+                // tcp streams were opened on init already
+                let tcp = self.inner.settings.tunnel_type == TunnelType::Tcp;
+                if !tcp && !streams.read().unwrap().contains_key(stream_id) {
+                    let metadata = pending_streams.write().unwrap().remove(stream_id);
+                    if local::setup_new_stream(
+                        self,
+                        capabilities,
+                        tunnel_tx.clone(),
+                        stream_id.clone(),
+                        metadata,
+                    )
+                    .await
+                    .is_none()
+                    {
+                        error!("failed to open local tunnel")
+                    }
+                }
+
+                // find the right stream
+                let active_stream = streams.read().unwrap().get(stream_id).cloned();
+
+                // forward data to it
+                if let Some(mut stream) = active_stream {
+                    let message = local::StreamMessage::Data(data.clone());
+
+                    // without flow control every window is unlimited, and waiting for a slow
+                    // local service would hold up every other stream of the connection
+                    let overran = if !stream.recv_window.consume(data.len()) {
+                        error!("server overran the stream window, resetting stream");
+                        true
+                    } else {
+                        match stream.tx.try_send(message) {
+                            Ok(_) => false,
+                            Err(e) if e.is_full() => {
+                                error!("server flooded the stream queue, resetting stream");
+                                true
+                            }
+                            Err(e) => return Err(e.into_send_error().into()),
+                        }
+                    };
+
+                    if overran {
+                        let half_close = capabilities.has(Capability::HalfClose);
+                        local::reset_stream(self, &mut tunnel_tx, stream_id, half_close).await;
+                        stream.tx.close_channel();
+                        return Ok(control_packet.clone());
+                    }
+                    info!("forwarded to local tcp ({})", stream_id);
+                } else {
+                    error!("got data but no stream to send it to.");
+                    tunnel_tx
+                        .send(ControlPacket::Refused(stream_id.clone()))
+                        .await?;
+                }
+            }
+        };
+
+        Ok(control_packet.clone())
+    }
+}
+
+impl Connection {
+    pub fn info(&self) -> &Connected {
+        &self.info
+    }
+
+    /// Forward visitor streams until the control connection ends.
+    ///
+    /// Returns `Ok` when the server closed the connection or asked us to
+    /// move elsewhere, streams still in flight are then served in the
+    /// background until they finish. Reconnect in either case.
+    pub async fn serve(self) -> Result<(), Error> {
+        let Connection {
+            client,
+            websocket,
+            info,
+        } = self;
+        let capabilities = info.capabilities;
+
+        // split reading and writing
+        let (mut ws_sink, mut ws_stream) = websocket.split();
+
+        // tunnel channel
+        let (tunnel_tx, mut tunnel_rx) = channel::<ControlPacket>(CONTROL_QUEUE_LEN);
+
+        // fresh tokens go to the server over this connection from now on
+        let token_auth = client.inner.token.lock().unwrap().is_some();
+        *client.inner.control.lock().unwrap() = (token_auth
+            && capabilities.has(Capability::Reauthentication))
+        .then(|| tunnel_tx.clone());
+
+        // the connection is closed once we, or a drain, let go of this
+        let (open, mut closed) = oneshot::channel::<()>();
+        let (failed_tx, mut failed) = oneshot::channel::<Error>();
+
+        // continuously write to websocket tunnel
+        tokio::spawn(async move {
+            let error = loop {
+                let packet = tokio::select! {
+                    packet = tunnel_rx.next() => packet,
+                    _ = &mut closed => {
+                        let _ = ws_sink.close().await;
+                        return;
+                    }
+                };
+
+                let packet = match packet {
+                    Some(data) => data,
+                    None => {
+                        warn!("control flow didn't send anything!");
+                        break Error::Timeout;
+                    }
+                };
+
+                if let Err(e) = ws_sink.send(Message::binary(packet.serialize())).await {
+                    warn!("failed to write message to tunnel websocket: {:?}", e);
+                    break e.into();
+                }
+            };
+            let _ = failed_tx.send(error);
+        });
+
+        // continuously read from websocket tunnel
+        loop {
+            let message = tokio::select! {
+                message = ws_stream.next() => message,
+                error = &mut failed => return Err(error.unwrap_or(Error::Timeout)),
+            };
+
+            match message {
+                Some(Ok(message)) if message.is_close() => {
+                    debug!("got close message");
+                    return Ok(());
+                }
+                Some(Ok(message)) => {
+                    let packet = client
+                        .handle_packet(&capabilities, tunnel_tx.clone(), message.into_data())
+                        .await
+                        .map_err(|e| {
+                            error!("Malformed protocol control packet: {:?}", e);
+                            Error::MalformedMessageFromServer
+                        })?;
+                    debug!("Processed packet: {:?}", packet.packet_type());
+
+                    if let ControlPacket::GoAway(deadline) = packet {
+                        // reconnect right away, the old connection keeps serving its streams
+                        tokio::spawn(client.drain(
+                            capabilities,
+                            tunnel_tx,
+                            ws_stream,
+                            open,
+                            Duration::from_secs(deadline.into()),
+                        ));
+                        return Ok(());
+                    }
+                }
+                Some(Err(e)) => {
+                    warn!("websocket read error: {:?}", e);
+                    return Err(Error::Timeout);
+                }
+                None => {
+                    warn!("websocket sent none");
+                    return Err(Error::Timeout);
+                }
+            }
+        }
+    }
+}
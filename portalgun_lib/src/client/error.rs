@@ -0,0 +1,80 @@
+// SPDX-FileCopyrightText: 2023 perillamint <perillamint@silicon.moe>
+// SPDX-FileCopyrightText: 2020-2022 Alex Grinman <me@alexgr.in>
+//
+// SPDX-License-Identifier: MIT
+
+use crate::{ErrorCode, TunnelType};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to connect to control server: {0}.")]
+    WebSocketError(Box<tokio_tungstenite::tungstenite::error::Error>),
+
+    #[error("Server denied the connection.")]
+    AuthenticationFailed,
+
+    #[error("Server sent a malformed message.")]
+    MalformedMessageFromServer,
+
+    #[error("Invalid sub-domain specified.")]
+    InvalidSubDomain,
+
+    #[error("Cannot use this sub-domain, it is already taken.")]
+    SubDomainInUse,
+
+    #[error("{0}")]
+    ServerError(String),
+
+    #[error("Server rejected the connection ({0}): {1}")]
+    Rejected(ErrorCode, String),
+
+    #[error("The server does not support {0} tunnels.")]
+    TunnelTypeUnsupported(TunnelType),
+
+    #[error("The server does not support multiple tunnels on one connection.")]
+    MultipleTunnelsUnsupported,
+
+    #[error("The server only supports protocol versions {min_version} to {max_version}, please upgrade portalgun.")]
+    UnsupportedProtocolVersion { min_version: u32, max_version: u32 },
+
+    #[error("The server responded with an invalid response.")]
+    ServerReplyInvalid,
+
+    #[error("The server did not respond to our client_hello.")]
+    NoResponseFromServer,
+
+    #[error("The server timed out sending us something.")]
+    Timeout,
+
+    #[error("Invalid tunnel configuration: {0}.")]
+    InvalidConfig(&'static str),
+
+    #[error("Invalid client certificate: {0}.")]
+    InvalidClientCertificate(String),
+
+    #[error("Failed to connect to the local service.")]
+    LocalServiceUnavailable,
+}
+
+impl From<tokio_tungstenite::tungstenite::error::Error> for Error {
+    fn from(error: tokio_tungstenite::tungstenite::error::Error) -> Self {
+        Error::WebSocketError(Box::new(error))
+    }
+}
+
+impl Error {
+    /// Connecting again later may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::WebSocketError(_) | Error::NoResponseFromServer | Error::Timeout => true,
+            Error::Rejected(code, _) => code.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// The user has to log in again before the server will accept them
+    pub fn needs_login(&self) -> bool {
+        matches!(self, Error::Rejected(code, _) if code.needs_login())
+    }
+}
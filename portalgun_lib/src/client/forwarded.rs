@@ -0,0 +1,107 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Add `X-Forwarded-For` and `Forwarded` headers to requests headed for the
+//! local service, using the stream metadata sent by the server.
+//!
+//! Like the introspection dashboard, only the first request of a stream is
+//! looked at.
+
+use crate::StreamMetadata;
+use std::net::IpAddr;
+
+/// Give up looking for the end of the request head after this many bytes
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct ForwardedHeaders {
+    headers: String,
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl ForwardedHeaders {
+    pub(crate) fn new(metadata: &StreamMetadata) -> Option<Self> {
+        let remote = metadata.remote_ip()?;
+
+        let forwarded_for = match &metadata.forwarded_for {
+            Some(chain) => format!("{}, {}", chain, remote),
+            None => remote.to_string(),
+        };
+
+        let mut forwarded = format!("for={}", forwarded_node(remote));
+        if let Some(host) = &metadata.host {
+            forwarded.push_str(&format!(";host=\"{}\"", host.replace(['"', '\\'], "")));
+        }
+        forwarded.push_str(if metadata.tls {
+            ";proto=https"
+        } else {
+            ";proto=http"
+        });
+
+        Some(Self {
+            headers: format!(
+                "X-Forwarded-For: {}\r\nForwarded: {}\r\n",
+                forwarded_for, forwarded
+            ),
+            pending: vec![],
+            done: false,
+        })
+    }
+
+    /// Bytes headed for the local service, returns what can be written now.
+    /// Data is held back until the request head is complete.
+    pub(crate) fn rewrite(&mut self, data: Vec<u8>) -> Vec<u8> {
+        if self.done {
+            return data;
+        }
+
+        self.pending.extend(data);
+        let end = match self.pending.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end,
+            // not http, or a head too large to bother with
+            None if self.pending.len() >= MAX_HEAD_BYTES => return self.finish(),
+            None => return vec![],
+        };
+        self.done = true;
+
+        let pending = std::mem::take(&mut self.pending);
+        let (head, rest) = pending.split_at(end + 2);
+
+        // the visitor's own values are already part of our chain
+        let mut out = head
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .filter(|(i, line)| *i == 0 || !is_forwarded_header(line))
+            .flat_map(|(_, line)| [line, b"\n"].concat())
+            .collect::<Vec<u8>>();
+        out.extend(self.headers.as_bytes());
+        out.extend(rest);
+        out
+    }
+
+    /// Whatever is still held back, when the stream ends
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        self.done = true;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+fn is_forwarded_header(line: &[u8]) -> bool {
+    let name = match line.iter().position(|b| *b == b':') {
+        Some(colon) => &line[..colon],
+        None => return false,
+    };
+
+    name.eq_ignore_ascii_case(b"x-forwarded-for") || name.eq_ignore_ascii_case(b"forwarded")
+}
+
+/// Node identifier for the `Forwarded` header (RFC 7239)
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Client certificates, for control servers that authenticate clients with mutual TLS
+
+use super::*;
+use tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+use tokio_tungstenite::Connector;
+
+/// A client certificate chain and its private key
+#[derive(Clone)]
+pub struct ClientIdentity {
+    chain: Vec<Certificate>,
+    key: PrivateKey,
+}
+
+impl std::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field("chain", &self.chain.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientIdentity {
+    /// Parse a PEM encoded certificate chain, the client's certificate first, and
+    /// its PEM encoded private key (PKCS#8, PKCS#1 or SEC1)
+    pub fn from_pem(chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        let invalid = |error: std::io::Error| Error::InvalidClientCertificate(error.to_string());
+
+        let chain: Vec<_> = rustls_pemfile::certs(&mut &chain[..])
+            .map_err(invalid)?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        if chain.is_empty() {
+            return Err(Error::InvalidClientCertificate(
+                "no certificate found".to_string(),
+            ));
+        }
+
+        let mut key = key;
+        let key = loop {
+            match rustls_pemfile::read_one(&mut key).map_err(invalid)? {
+                Some(rustls_pemfile::Item::PKCS8Key(key))
+                | Some(rustls_pemfile::Item::RSAKey(key))
+                | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+                Some(_) => continue,
+                None => {
+                    return Err(Error::InvalidClientCertificate(
+                        "no private key found".to_string(),
+                    ))
+                }
+            }
+        };
+
+        Ok(Self { chain, key })
+    }
+
+    /// Connector presenting this certificate, trusting the platform's root certificates
+    pub(crate) fn connector(&self) -> Result<Connector, Error> {
+        let mut roots = RootCertStore::empty();
+        match rustls_native_certs::load_native_certs() {
+            Ok(certs) => {
+                let certs: Vec<_> = certs.into_iter().map(|cert| cert.0).collect();
+                roots.add_parsable_certificates(&certs);
+            }
+            Err(error) => warn!("failed to load the platform's root certificates: {}", error),
+        }
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(self.chain.clone(), self.key.clone())
+            .map_err(|error| Error::InvalidClientCertificate(error.to_string()))?;
+        Ok(Connector::Rustls(Arc::new(config)))
+    }
+}
@@ -0,0 +1,332 @@
+// SPDX-FileCopyrightText: 2023 perillamint <perillamint@silicon.moe>
+// SPDX-FileCopyrightText: 2020-2022 Alex Grinman <me@alexgr.in>
+//
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+
+use super::*;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::{SinkExt, StreamExt};
+
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+use super::forwarded::ForwardedHeaders;
+use super::observer::CaptureSink;
+
+pub trait AnyTcpStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AnyTcpStream for T {}
+
+/// A stream tunneled to the local service
+#[derive(Debug, Clone)]
+pub(crate) struct ActiveStream {
+    pub tx: Sender<StreamMessage>,
+    /// credit for sending local bytes to the server
+    pub send_window: SendWindow,
+    /// credit the server has for sending us bytes
+    pub recv_window: RecvWindow,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum StreamMessage {
+    Data(Vec<u8>),
+    Close,
+}
+
+/// Establish a new local stream and start processing messages to it
+pub(crate) async fn setup_new_stream(
+    client: &TunnelClient,
+    capabilities: &Capabilities,
+    mut tunnel_tx: Sender<ControlPacket>,
+    stream_id: StreamId,
+    metadata: Option<StreamMetadata>,
+) -> Option<Sender<StreamMessage>> {
+    let settings = &client.inner.settings;
+    let binding = metadata.as_ref().and_then(|m| m.binding.as_deref());
+    let local = match settings.local_service(binding) {
+        Some(local) => local,
+        None => {
+            error!("stream for unknown tunnel: {}", binding.unwrap_or_default());
+            let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+            return None;
+        }
+    };
+
+    info!(
+        "setting up local stream: {} -> {}",
+        &stream_id.to_string(),
+        &local.addr
+    );
+
+    let local_tcp = match TcpStream::connect(local.addr).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("failed to connect to local service: {}", e);
+            settings.observer.connect_failed(local, &e);
+            let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+            return None;
+        }
+    };
+
+    let local_tcp: Box<dyn AnyTcpStream> = if settings.use_tls {
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        let config_builder = ClientConfig::builder();
+        let config: ClientConfig = config_builder
+            .with_safe_defaults()
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        let config = TlsConnector::from(Arc::new(config));
+
+        let stream = match ServerName::try_from(local.host.as_str()) {
+            Ok(dnsname) => config.connect(dnsname, local_tcp).await,
+            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)),
+        };
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                error!("failed to connect to TLS service: {}", e);
+                settings.observer.connect_failed(local, &e);
+                let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+                return None;
+            }
+        };
+
+        Box::new(stream)
+    } else {
+        Box::new(local_tcp)
+    };
+
+    // raw tcp and udp traffic isn't http, so there is nothing to inspect
+    let capture = match settings.tunnel_type {
+        TunnelType::Http => settings.observer.stream_opened(metadata.as_ref()),
+        TunnelType::Tcp | TunnelType::Udp => None,
+    };
+    let (capture_request, capture_response) = match capture {
+        Some(StreamCapture { request, response }) => {
+            (CaptureSink::new(request), CaptureSink::new(response))
+        }
+        None => (CaptureSink::disabled(), CaptureSink::disabled()),
+    };
+
+    let forwarded = match (&metadata, settings.tunnel_type) {
+        (Some(metadata), TunnelType::Http) if settings.forwarded_headers => {
+            ForwardedHeaders::new(metadata)
+        }
+        _ => None,
+    };
+
+    let (stream, sink) = split(local_tcp);
+
+    let (tx, rx) = channel(STREAM_QUEUE_LEN);
+    let flow_control = capabilities.has(Capability::FlowControl);
+    let halves = capabilities
+        .has(Capability::HalfClose)
+        .then(StreamHalves::new);
+    let active_stream = ActiveStream {
+        tx: tx.clone(),
+        send_window: SendWindow::new(flow_control),
+        recv_window: RecvWindow::new(flow_control),
+    };
+    client
+        .inner
+        .streams
+        .write()
+        .unwrap()
+        .insert(stream_id.clone(), active_stream.clone());
+
+    // Read local tcp bytes, send them tunnel
+    let client_clone = client.clone();
+    let stream_id_clone = stream_id.clone();
+    let send_window = active_stream.send_window.clone();
+    let tunnel_tx_clone = tunnel_tx.clone();
+    let halves_clone = halves.clone();
+    tokio::spawn(async move {
+        process_local_tcp(
+            client_clone,
+            stream,
+            tunnel_tx_clone,
+            stream_id_clone,
+            send_window,
+            halves_clone,
+            capture_response,
+        )
+        .await;
+    });
+
+    // Forward remote packets to local tcp
+    let client = client.clone();
+    tokio::spawn(async move {
+        forward_to_local_tcp(
+            client,
+            sink,
+            rx,
+            tunnel_tx,
+            stream_id,
+            active_stream.recv_window,
+            halves,
+            forwarded,
+            capture_request,
+        )
+        .await;
+    });
+
+    Some(tx)
+}
+
+/// Read local tcp bytes and send them to the tunnel.
+///
+/// `halves` is only set when the server understands half-closed streams.
+async fn process_local_tcp<T>(
+    client: TunnelClient,
+    mut stream: ReadHalf<T>,
+    mut tunnel: Sender<ControlPacket>,
+    stream_id: StreamId,
+    send_window: SendWindow,
+    halves: Option<StreamHalves>,
+    mut capture: CaptureSink,
+) where
+    T: AnyTcpStream,
+{
+    let streams = &client.inner.streams;
+    let mut buf = [0; 4 * 1024];
+
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                error!("failed to read data from local service: {:?}", e);
+                reset_stream(&client, &mut tunnel, &stream_id, halves.is_some()).await;
+                return;
+            }
+        };
+
+        if n == 0 {
+            info!("done reading from client stream");
+            match halves {
+                Some(halves) => {
+                    let _ = tunnel.send(ControlPacket::End(stream_id.clone())).await;
+                    if halves.finish() {
+                        streams.write().unwrap().remove(&stream_id);
+                    }
+                }
+                None => {
+                    streams.write().unwrap().remove(&stream_id);
+                }
+            }
+            return;
+        }
+
+        // the stream was reset or closed while we were reading
+        if !streams.read().unwrap().contains_key(&stream_id) {
+            info!("stream is gone, stop reading from local service");
+            return;
+        }
+
+        let data = buf[..n].to_vec();
+        debug!(
+            "read from local service: {:?}",
+            std::str::from_utf8(&data).unwrap_or("<non utf8>")
+        );
+
+        // wait for the server to have room for more
+        if !send_window.reserve(n).await {
+            info!("stream closed while waiting for credit");
+            return;
+        }
+
+        let packet = ControlPacket::Data(stream_id.clone(), data.clone());
+        if tunnel.send(packet).await.is_err() {
+            warn!("control connection is gone, stop reading from local service");
+            return;
+        }
+
+        capture.capture(data).await;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn forward_to_local_tcp<T>(
+    client: TunnelClient,
+    mut sink: WriteHalf<T>,
+    mut queue: Receiver<StreamMessage>,
+    mut tunnel: Sender<ControlPacket>,
+    stream_id: StreamId,
+    recv_window: RecvWindow,
+    halves: Option<StreamHalves>,
+    mut forwarded: Option<ForwardedHeaders>,
+    mut capture: CaptureSink,
+) where
+    T: AnyTcpStream,
+{
+    loop {
+        let data = match queue.next().await {
+            Some(StreamMessage::Data(data)) => data,
+            message => {
+                warn!("closing stream");
+                if let Some(held) = forwarded.as_mut().map(ForwardedHeaders::finish) {
+                    let _ = sink.write_all(&held).await;
+                }
+
+                let _ = sink.shutdown().await.map_err(|e| {
+                    error!("failed to shutdown: {:?}", e);
+                });
+
+                // the local service may still be answering
+                if let (Some(StreamMessage::Close), Some(halves)) = (message, halves) {
+                    if halves.finish() {
+                        client.inner.streams.write().unwrap().remove(&stream_id);
+                    }
+                }
+                return;
+            }
+        };
+
+        let len = data.len();
+        let data = match forwarded.as_mut() {
+            Some(forwarded) => forwarded.rewrite(data),
+            None => data,
+        };
+
+        if let Err(e) = sink.write_all(&data).await {
+            error!("failed to write packet data to local tcp socket: {:?}", e);
+            reset_stream(&client, &mut tunnel, &stream_id, halves.is_some()).await;
+            return;
+        }
+        debug!("wrote to local service: {:?}", data.len());
+
+        // let the server know it can send more
+        if let Some(update) = recv_window.release(&stream_id, len) {
+            let _ = tunnel.send(update).await;
+        }
+
+        capture.capture(data).await;
+    }
+}
+
+/// Drop a broken stream and, if the server understands it, abort its other half
+pub(crate) async fn reset_stream(
+    client: &TunnelClient,
+    tunnel: &mut Sender<ControlPacket>,
+    stream_id: &StreamId,
+    half_close: bool,
+) {
+    let stream = client.inner.streams.write().unwrap().remove(stream_id);
+    if let Some(stream) = stream {
+        stream.send_window.close();
+    }
+
+    if half_close {
+        let _ = tunnel.send(ControlPacket::Reset(stream_id.clone())).await;
+    }
+}
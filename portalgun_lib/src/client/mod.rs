@@ -0,0 +1,417 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Embeddable tunnel client.
+//!
+//! [`Tunnel`] opens a tunnel in-process and keeps it connected in the
+//! background, i.e. to receive webhooks in an integration test:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), portalgun_lib::client::Error> {
+//! use portalgun_lib::client::Tunnel;
+//! use std::net::SocketAddr;
+//!
+//! let tunnel = Tunnel::builder()
+//!     .control_url("wss://tunnel.example.com/wormhole".parse().unwrap())
+//!     .token("access-token")
+//!     .forward_to("127.0.0.1:3000".parse::<SocketAddr>().unwrap())
+//!     .connect()
+//!     .await?;
+//!
+//! println!("webhooks go to {}", tunnel.public_url());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Callers that want to drive reconnects themselves use [`TunnelClient`]
+//! and [`Connection`] instead.
+
+use super::*;
+use futures::channel::mpsc::{channel, Sender};
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+mod connection;
+mod error;
+mod forwarded;
+mod identity;
+mod local;
+mod observer;
+mod udp;
+
+pub use self::connection::{auth_info, AuthInfo, Connected, Connection};
+pub use self::error::Error;
+pub use self::identity::ClientIdentity;
+pub use self::observer::{Observer, StreamCapture};
+pub use url::Url;
+
+use self::local::ActiveStream;
+use self::observer::NoObserver;
+
+/// Wait this long before reconnecting after losing the control connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Most control packets queued for the server before their senders wait for the websocket
+const CONTROL_QUEUE_LEN: usize = 1024;
+
+/// A local service tunnel traffic is forwarded to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalService {
+    pub addr: SocketAddr,
+    /// Host name of the service, used to verify it when forwarding over TLS
+    pub host: String,
+}
+
+impl LocalService {
+    pub fn new(host: impl Into<String>, addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            host: host.into(),
+        }
+    }
+}
+
+impl From<SocketAddr> for LocalService {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.ip().to_string(), addr)
+    }
+}
+
+/// Builds a [`TunnelClient`], or connects a [`Tunnel`] right away
+#[derive(Clone)]
+pub struct TunnelBuilder {
+    control_url: Option<Url>,
+    token: Option<SecretKey>,
+    sub_domain: Option<String>,
+    reserve: bool,
+    tunnel_type: TunnelType,
+    local: Option<LocalService>,
+    bindings: Vec<(String, LocalService)>,
+    use_tls: bool,
+    forwarded_headers: bool,
+    observer: Arc<dyn Observer>,
+    identity: Option<ClientIdentity>,
+}
+
+impl Default for TunnelBuilder {
+    fn default() -> Self {
+        Self {
+            control_url: None,
+            token: None,
+            sub_domain: None,
+            reserve: false,
+            tunnel_type: TunnelType::default(),
+            local: None,
+            bindings: vec![],
+            use_tls: false,
+            forwarded_headers: false,
+            observer: Arc::new(NoObserver),
+            identity: None,
+        }
+    }
+}
+
+impl TunnelBuilder {
+    /// Websocket URL of the server's control endpoint, i.e. `wss://tunnel.example.com/wormhole`
+    pub fn control_url(mut self, control_url: Url) -> Self {
+        self.control_url = Some(control_url);
+        self
+    }
+
+    /// Access token to authenticate with, the tunnel is anonymous without one
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(SecretKey(token.into()));
+        self
+    }
+
+    /// Present a client certificate to the control server, which authenticates
+    /// the tunnel with it unless a token is given too
+    pub fn client_certificate(mut self, identity: ClientIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Ask for a sub-domain instead of a random one
+    pub fn sub_domain(mut self, sub_domain: impl Into<String>) -> Self {
+        self.sub_domain = Some(sub_domain.into());
+        self
+    }
+
+    /// Reserve the requested sub-domains for the owner of the token, so nobody
+    /// else can use them, even while this client is offline
+    pub fn reserve(mut self, reserve: bool) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    /// Kind of tunnel to open, http by default
+    pub fn tunnel_type(mut self, tunnel_type: TunnelType) -> Self {
+        self.tunnel_type = tunnel_type;
+        self
+    }
+
+    /// Local service to forward tunnel traffic to
+    pub fn forward_to(mut self, local: impl Into<LocalService>) -> Self {
+        self.local = Some(local.into());
+        self
+    }
+
+    /// Open an additional http tunnel on `sub_domain` over the same connection
+    pub fn tunnel(mut self, sub_domain: impl Into<String>, local: impl Into<LocalService>) -> Self {
+        self.bindings.push((sub_domain.into(), local.into()));
+        self
+    }
+
+    /// Connect to the local services over TLS
+    pub fn use_tls(mut self, use_tls: bool) -> Self {
+        self.use_tls = use_tls;
+        self
+    }
+
+    /// Add `X-Forwarded-For` and `Forwarded` headers with the visitor's address to requests
+    pub fn forwarded_headers(mut self, forwarded_headers: bool) -> Self {
+        self.forwarded_headers = forwarded_headers;
+        self
+    }
+
+    /// Watch the traffic forwarded through the tunnel
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = Arc::new(observer);
+        self
+    }
+
+    pub fn build(self) -> Result<TunnelClient, Error> {
+        let control_url = self
+            .control_url
+            .ok_or(Error::InvalidConfig("no control url given"))?;
+        let local = self
+            .local
+            .ok_or(Error::InvalidConfig("no local service to forward to"))?;
+
+        if !self.bindings.is_empty() && self.tunnel_type != TunnelType::Http {
+            return Err(Error::InvalidConfig(
+                "additional tunnels can only be used with http tunnels",
+            ));
+        }
+
+        Ok(TunnelClient {
+            inner: Arc::new(Inner {
+                settings: Settings {
+                    control_url,
+                    sub_domain: self.sub_domain,
+                    reserve: self.reserve,
+                    tunnel_type: self.tunnel_type,
+                    local,
+                    bindings: self.bindings,
+                    use_tls: self.use_tls,
+                    forwarded_headers: self.forwarded_headers,
+                    observer: self.observer,
+                    identity: self.identity,
+                },
+                streams: RwLock::new(HashMap::new()),
+                pending_streams: RwLock::new(HashMap::new()),
+                udp_sessions: RwLock::new(HashMap::new()),
+                reconnect_token: Mutex::new(None),
+                token: Mutex::new(self.token),
+                control: Mutex::new(None),
+            }),
+        })
+    }
+
+    /// Build the client and open the tunnel, see [`Tunnel`]
+    pub async fn connect(self) -> Result<Tunnel, Error> {
+        Tunnel::connect(self.build()?).await
+    }
+}
+
+struct Settings {
+    control_url: Url,
+    sub_domain: Option<String>,
+    reserve: bool,
+    tunnel_type: TunnelType,
+    local: LocalService,
+    /// additional http tunnels, by requested sub-domain
+    bindings: Vec<(String, LocalService)>,
+    use_tls: bool,
+    forwarded_headers: bool,
+    observer: Arc<dyn Observer>,
+    identity: Option<ClientIdentity>,
+}
+
+impl Settings {
+    /// Local service for streams of `binding`, `None` being the primary tunnel
+    fn local_service(&self, binding: Option<&str>) -> Option<&LocalService> {
+        match binding {
+            None => Some(&self.local),
+            Some(name) => self
+                .bindings
+                .iter()
+                .find(|(sub_domain, _)| sub_domain == name)
+                .map(|(_, local)| local),
+        }
+    }
+}
+
+struct Inner {
+    settings: Settings,
+    streams: RwLock<HashMap<StreamId, ActiveStream>>,
+    /// metadata of streams that were announced but haven't seen any data yet
+    pending_streams: RwLock<HashMap<StreamId, StreamMetadata>>,
+    udp_sessions: RwLock<HashMap<StreamId, Sender<Vec<u8>>>>,
+    reconnect_token: Mutex<Option<ReconnectToken>>,
+    /// access token to authenticate with, replaced with [`TunnelClient::set_token`]
+    token: Mutex<Option<SecretKey>>,
+    /// packets to the server of the current connection, if it accepts fresh tokens
+    control: Mutex<Option<Sender<ControlPacket>>>,
+}
+
+/// Forwards tunnel traffic to the local services.
+///
+/// Cloning is cheap, clones share their streams and credentials.
+#[derive(Clone)]
+pub struct TunnelClient {
+    inner: Arc<Inner>,
+}
+
+impl TunnelClient {
+    /// Authenticate with `token` from now on, i.e. after refreshing an expiring
+    /// access token. The next connection uses it, and the current one hands it to
+    /// the server right away if it accepts fresh tokens, which keeps the tunnel
+    /// open past the expiry of the previous token.
+    pub fn set_token(&self, token: impl Into<String>) {
+        let token = SecretKey(token.into());
+        *self.inner.token.lock().unwrap() = Some(token.clone());
+
+        if let Some(control) = self.inner.control.lock().unwrap().as_ref() {
+            debug!("handing the server a fresh token");
+            if let Err(e) = control
+                .clone()
+                .try_send(ControlPacket::Reauthenticate(token))
+            {
+                warn!("failed to hand the server a fresh token: {:?}", e);
+            }
+        }
+    }
+
+    /// Send a captured request to the local service again, as if it came
+    /// through the tunnel. The response only reaches the [`Observer`].
+    pub async fn replay(&self, metadata: StreamMetadata, data: Vec<u8>) -> Result<(), Error> {
+        let (tx, mut rx) = channel::<ControlPacket>(CONTROL_QUEUE_LEN);
+        tokio::spawn(async move {
+            // keep the rx alive
+            while (rx.next().await).is_some() {
+                // do nothing
+            }
+        });
+
+        let mut stream = local::setup_new_stream(
+            self,
+            &Capabilities::default(),
+            tx,
+            StreamId::generate(),
+            Some(metadata),
+        )
+        .await
+        .ok_or(Error::LocalServiceUnavailable)?;
+
+        let _ = stream.send(local::StreamMessage::Data(data)).await;
+        Ok(())
+    }
+}
+
+/// A tunnel kept open in the background, reconnecting whenever the control
+/// connection is lost. The tunnel is closed when this handle is dropped.
+pub struct Tunnel {
+    client: TunnelClient,
+    connected: watch::Receiver<Connected>,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl Tunnel {
+    pub fn builder() -> TunnelBuilder {
+        TunnelBuilder::default()
+    }
+
+    async fn connect(client: TunnelClient) -> Result<Self, Error> {
+        let connection = client.connect().await?;
+        let (connected_tx, connected) = watch::channel(connection.info().clone());
+        let task = tokio::spawn(keep_connected(client.clone(), connection, connected_tx));
+
+        Ok(Self {
+            client,
+            connected,
+            task,
+        })
+    }
+
+    /// Public URL of the primary tunnel
+    pub fn public_url(&self) -> String {
+        self.connected.borrow().public_url.clone()
+    }
+
+    /// The current control connection. It may change after a reconnect,
+    /// i.e. anonymous tunnels without a sub-domain get a new random one.
+    pub fn connected(&self) -> Connected {
+        self.connected.borrow().clone()
+    }
+
+    pub fn client(&self) -> &TunnelClient {
+        &self.client
+    }
+
+    /// Wait until the tunnel can't be reconnected anymore
+    pub async fn closed(&mut self) -> Result<(), Error> {
+        match (&mut self.task).await {
+            Ok(result) => result,
+            // i.e. the runtime is shutting down
+            Err(_) => Ok(()),
+        }
+    }
+
+    pub async fn close(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn keep_connected(
+    client: TunnelClient,
+    mut connection: Connection,
+    connected: watch::Sender<Connected>,
+) -> Result<(), Error> {
+    loop {
+        match connection.serve().await {
+            Ok(()) => {}
+            Err(e) if e.is_transient() => {
+                warn!("tunnel connection lost: {}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+
+        connection = loop {
+            match client.connect().await {
+                Ok(connection) => break connection,
+                Err(e) if e.is_transient() => {
+                    warn!("failed to reconnect tunnel: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        connected.send_replace(connection.info().clone());
+    }
+}
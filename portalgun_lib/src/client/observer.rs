@@ -0,0 +1,74 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Hooks for watching the traffic a [`TunnelClient`](super::TunnelClient) forwards.
+
+use super::LocalService;
+use crate::StreamMetadata;
+use futures::channel::mpsc::UnboundedSender;
+use futures::SinkExt;
+
+/// Only this many bytes of each request and response are captured
+const MAX_CAPTURE_BYTES: usize = 1024 * 1024;
+
+pub trait Observer: Send + Sync {
+    /// The local service could not be reached
+    fn connect_failed(&self, _local: &LocalService, _error: &std::io::Error) {}
+
+    /// An http stream to the local service was opened. The first MiB of
+    /// each direction is sent to the returned capture, whose channels are
+    /// closed once the stream ends.
+    fn stream_opened(&self, _metadata: Option<&StreamMetadata>) -> Option<StreamCapture> {
+        None
+    }
+}
+
+/// Receives the bytes of one stream
+#[derive(Debug, Clone)]
+pub struct StreamCapture {
+    /// bytes sent by the visitor
+    pub request: UnboundedSender<Vec<u8>>,
+    /// bytes sent by the local service
+    pub response: UnboundedSender<Vec<u8>>,
+}
+
+pub(crate) struct NoObserver;
+
+impl Observer for NoObserver {}
+
+/// One direction of a stream being captured
+#[derive(Debug, Clone)]
+pub(crate) struct CaptureSink {
+    tx: Option<UnboundedSender<Vec<u8>>>,
+    remaining: usize,
+}
+
+impl CaptureSink {
+    pub(crate) fn new(tx: UnboundedSender<Vec<u8>>) -> Self {
+        Self {
+            tx: Some(tx),
+            remaining: MAX_CAPTURE_BYTES,
+        }
+    }
+
+    /// A sink that captures nothing, for streams nobody watches
+    pub(crate) fn disabled() -> Self {
+        Self {
+            tx: None,
+            remaining: 0,
+        }
+    }
+
+    /// Capture stream bytes, dropping anything past the capture limit
+    pub(crate) async fn capture(&mut self, mut data: Vec<u8>) {
+        let tx = match self.tx.as_mut() {
+            Some(tx) if self.remaining > 0 => tx,
+            _ => return,
+        };
+
+        data.truncate(self.remaining);
+        self.remaining -= data.len();
+        let _ = tx.send(data).await;
+    }
+}
@@ -0,0 +1,160 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Relay UDP tunnel datagrams to the local service.
+//!
+//! Each remote peer gets its own local socket, so replies from the local
+//! service find their way back to the right peer. Sessions are dropped
+//! once they have been idle for a while.
+
+use super::*;
+use futures::channel::mpsc::{channel, Receiver};
+use futures::StreamExt;
+use tokio::net::UdpSocket;
+
+/// Close local sessions that have been quiet for this long
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest datagram we will receive from the local service
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Most local sessions open at once, datagrams of further peers are dropped
+/// until some go quiet
+const MAX_SESSIONS: usize = 256;
+
+/// Most datagrams queued for the local service per session
+const SESSION_QUEUE_LEN: usize = 64;
+
+/// Send a datagram from a remote peer to the local service
+pub(crate) async fn forward_datagram(
+    client: &TunnelClient,
+    tunnel_tx: Sender<ControlPacket>,
+    stream_id: StreamId,
+    data: Vec<u8>,
+) {
+    let sessions = &client.inner.udp_sessions;
+    let session = sessions.read().unwrap().get(&stream_id).cloned();
+    let mut session = match session {
+        Some(session) => session,
+        None => match open_session(client, tunnel_tx, stream_id.clone()).await {
+            Some(session) => session,
+            None => return,
+        },
+    };
+
+    // UDP is lossy anyway, drop datagrams rather than queue them without bound
+    match session.try_send(data) {
+        Ok(_) => {}
+        Err(e) if e.is_full() => {
+            debug!(
+                "udp session [{}] is busy, dropping datagram",
+                stream_id.to_string()
+            );
+        }
+        Err(_) => {
+            debug!("udp session [{}] already closed", stream_id.to_string());
+            sessions.write().unwrap().remove(&stream_id);
+        }
+    }
+}
+
+async fn open_session(
+    client: &TunnelClient,
+    tunnel_tx: Sender<ControlPacket>,
+    stream_id: StreamId,
+) -> Option<Sender<Vec<u8>>> {
+    if client.inner.udp_sessions.read().unwrap().len() >= MAX_SESSIONS {
+        debug!("too many udp sessions, dropping datagram");
+        return None;
+    }
+
+    let settings = &client.inner.settings;
+    let local = &settings.local;
+    info!(
+        "setting up local udp session: {} -> {}",
+        &stream_id.to_string(),
+        &local.addr
+    );
+
+    let bind_addr = if local.addr.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 0))
+    };
+
+    let socket = match UdpSocket::bind(bind_addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("failed to bind local udp socket: {}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = socket.connect(local.addr).await {
+        error!("failed to connect to local service: {}", e);
+        settings.observer.connect_failed(local, &e);
+        return None;
+    }
+
+    let (tx, rx) = channel(SESSION_QUEUE_LEN);
+    client
+        .inner
+        .udp_sessions
+        .write()
+        .unwrap()
+        .insert(stream_id.clone(), tx.clone());
+
+    let client = client.clone();
+    tokio::spawn(async move {
+        relay_session(client, socket, stream_id, rx, tunnel_tx).await;
+    });
+
+    Some(tx)
+}
+
+async fn relay_session(
+    client: TunnelClient,
+    socket: UdpSocket,
+    stream_id: StreamId,
+    mut queue: Receiver<Vec<u8>>,
+    mut tunnel: Sender<ControlPacket>,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        tokio::select! {
+            data = queue.next() => match data {
+                Some(data) => {
+                    if let Err(e) = socket.send(&data).await {
+                        debug!("failed to send datagram to local service: {:?}", e);
+                    }
+                }
+                None => break,
+            },
+            result = socket.recv(&mut buf) => match result {
+                Ok(n) => {
+                    let packet = ControlPacket::Datagram(stream_id.clone(), buf[..n].to_vec());
+                    match tunnel.try_send(packet) {
+                        Ok(_) => {}
+                        Err(e) if e.is_full() => debug!("control queue is full, dropping datagram"),
+                        Err(_) => break,
+                    }
+                }
+                // i.e. the local service isn't listening (yet)
+                Err(e) => debug!("failed to read from local udp socket: {:?}", e),
+            },
+            _ = tokio::time::sleep(SESSION_IDLE_TIMEOUT) => {
+                debug!("udp session [{}] idle, closing", stream_id.to_string());
+                break;
+            }
+        }
+    }
+
+    client
+        .inner
+        .udp_sessions
+        .write()
+        .unwrap()
+        .remove(&stream_id);
+}
@@ -7,10 +7,125 @@ use base64::Engine;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+mod window;
+pub use self::window::*;
+
+#[cfg(feature = "client")]
+pub mod client;
+
+/// Version of the control protocol spoken by this build.
+///
+/// Peers that predate version negotiation do not send a version at all
+/// and are treated as version `0`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// An optional protocol feature a peer can advertise during the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Per-stream credit based flow control using `ControlPacket::WindowUpdate`
+    FlowControl,
+    /// Raw TCP tunnels on a server allocated port
+    TcpTunnel,
+    /// UDP tunnels on a server allocated port, using `ControlPacket::Datagram`
+    UdpTunnel,
+    /// `ControlPacket::End` closes one direction only and may be sent by
+    /// either side, `ControlPacket::Reset` aborts a stream
+    HalfClose,
+    /// `ControlPacket::Init` carries a [`StreamMetadata`] record
+    StreamMetadata,
+    /// Additional http tunnels bound over the same control connection,
+    /// told apart by [`StreamMetadata::binding`]
+    MultipleTunnels,
+    /// The server announces restarts with `ControlPacket::GoAway`
+    GoAway,
+    /// Handshake failures are reported with `ServerHello::Rejected`
+    StructuredErrors,
+    /// Servers without an OIDC provider answer `ClientType::AuthInfo`
+    /// with `ServerHello::TokenAuthInfo`
+    TokenLogin,
+    /// The server reserves requested sub-domains for their owner when
+    /// asked to with `ClientHello::reserve_sub_domains`
+    Reservations,
+    /// Clients hand the server a fresh access token with
+    /// `ControlPacket::Reauthenticate` before the one they connected with expires
+    Reauthentication,
+    /// A capability advertised by a newer peer that this build doesn't know about
+    #[serde(other)]
+    Unknown,
+}
+
+/// The set of capabilities advertised by (or negotiated with) a peer
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Capabilities(BTreeSet<Capability>);
+
+impl Capabilities {
+    /// All capabilities implemented by this build
+    pub fn supported() -> Self {
+        Self(BTreeSet::from([
+            Capability::FlowControl,
+            Capability::TcpTunnel,
+            Capability::UdpTunnel,
+            Capability::HalfClose,
+            Capability::StreamMetadata,
+            Capability::MultipleTunnels,
+            Capability::GoAway,
+            Capability::StructuredErrors,
+            Capability::TokenLogin,
+            Capability::Reservations,
+            Capability::Reauthentication,
+        ]))
+    }
+
+    /// This set with `capability` removed
+    pub fn without(mut self, capability: Capability) -> Self {
+        self.0.remove(&capability);
+        self
+    }
+
+    /// The capabilities both we and the peer support
+    pub fn negotiate(&self, peer: &Capabilities) -> Self {
+        Self(
+            self.0
+                .intersection(&peer.0)
+                .filter(|c| **c != Capability::Unknown)
+                .copied()
+                .collect(),
+        )
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.0.contains(&capability)
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self
+            .0
+            .iter()
+            .map(|c| format!("{:?}", c))
+            .collect::<Vec<_>>();
+        f.write_str(&names.join(","))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(transparent)]
 pub struct SecretKey(pub String);
+
+/// Keeps keys out of the logs, i.e. when logging a `ClientHello`
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
 impl SecretKey {
     pub fn generate() -> Self {
         let mut rng = rand::thread_rng();
@@ -40,6 +155,18 @@ pub enum ServerHello {
         sub_domain: String,
         hostname: String,
         client_id: ClientId,
+        /// negotiated protocol version, `0` when talking to a legacy server
+        #[serde(default)]
+        protocol_version: u32,
+        /// negotiated capabilities
+        #[serde(default)]
+        capabilities: Capabilities,
+        /// public port allocated for raw TCP tunnels
+        #[serde(default)]
+        port: Option<u16>,
+        /// additional tunnels bound for the client
+        #[serde(default)]
+        tunnels: Vec<BoundTunnel>,
     },
     SubDomainInUse,
     InvalidSubDomain,
@@ -49,7 +176,96 @@ pub enum ServerHello {
         oidc_discovery: String,
         oidc_scopes: Vec<String>,
     },
+    /// The server takes access tokens issued out of band instead of an OIDC login,
+    /// see `Capability::TokenLogin`
+    TokenAuthInfo,
     Error(String),
+    /// The server does not speak the protocol version requested by the client
+    UnsupportedProtocolVersion {
+        min_version: u32,
+        max_version: u32,
+    },
+    /// The handshake failed, see `Capability::StructuredErrors`
+    Rejected {
+        code: ErrorCode,
+        detail: String,
+    },
+}
+
+/// Why the server turned a client away
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The access token has expired
+    TokenExpired,
+    /// The access token is malformed, badly signed or not meant for this server
+    TokenInvalid,
+    /// The access token was signed by a key the server doesn't know
+    UnknownSigningKey,
+    /// The server could not check the credentials right now
+    AuthUnavailable,
+    /// The reconnect token is invalid or has expired
+    ReconnectTokenInvalid,
+    /// The server does not accept anonymous clients
+    AnonymousNotAllowed,
+    /// The requested sub-domain requires payment
+    PaymentRequired,
+    /// The credentials don't grant the requested sub-domain
+    SubDomainNotAllowed,
+    /// The requested sub-domain contains invalid characters
+    SubDomainInvalid,
+    /// The requested sub-domain is reserved by the server
+    SubDomainBlocked,
+    /// The requested sub-domain is used by another client
+    SubDomainInUse,
+    /// The requested tunnel type is disabled on this server
+    TunnelTypeDisabled,
+    /// No public ports are left for the requested tunnel
+    NoPortsAvailable,
+    /// The additional tunnels requested are invalid
+    InvalidTunnels,
+    /// The requested sub-domain could not be reserved
+    ReservationFailed,
+    /// The anonymous tunnel has reached its maximum lifetime
+    TunnelExpired,
+    /// The client's address has too many anonymous tunnels open
+    TooManyTunnels,
+    /// The credentials were revoked by the server's admins
+    TokenRevoked,
+    /// An error code introduced by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .unwrap_or_default();
+        f.write_str(&name)
+    }
+}
+
+impl ErrorCode {
+    /// The user has to log in again before the server will accept them
+    pub fn needs_login(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::TokenExpired
+                | ErrorCode::TokenInvalid
+                | ErrorCode::UnknownSigningKey
+                | ErrorCode::TokenRevoked
+        )
+    }
+
+    /// The same hello may succeed if sent again later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::AuthUnavailable | ErrorCode::NoPortsAvailable | ErrorCode::TooManyTunnels
+        )
+    }
 }
 
 impl ServerHello {
@@ -77,6 +293,22 @@ pub struct ClientHello {
     pub sub_domain: Option<String>,
     pub client_type: ClientType,
     pub reconnect_token: Option<ReconnectToken>,
+    /// protocol version spoken by the client, `0` for legacy clients
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// capabilities supported by the client
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// kind of tunnel the client wants
+    #[serde(default)]
+    pub tunnel_type: TunnelType,
+    /// additional http tunnels to bind, see `Capability::MultipleTunnels`
+    #[serde(default)]
+    pub tunnels: Vec<TunnelBinding>,
+    /// reserve the requested sub-domains for the owner of the credentials,
+    /// see `Capability::Reservations`
+    #[serde(default)]
+    pub reserve_sub_domains: bool,
 }
 
 impl ClientHello {
@@ -86,24 +318,105 @@ impl ClientHello {
             client_type: typ,
             sub_domain,
             reconnect_token: None,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            tunnel_type: TunnelType::default(),
+            tunnels: vec![],
+            reserve_sub_domains: false,
         }
     }
 
+    pub fn with_tunnel_type(mut self, tunnel_type: TunnelType) -> Self {
+        self.tunnel_type = tunnel_type;
+        self
+    }
+
+    pub fn with_tunnels(mut self, tunnels: Vec<TunnelBinding>) -> Self {
+        self.tunnels = tunnels;
+        self
+    }
+
+    pub fn with_reservation(mut self, reserve_sub_domains: bool) -> Self {
+        self.reserve_sub_domains = reserve_sub_domains;
+        self
+    }
+
     pub fn reconnect(reconnect_token: ReconnectToken) -> Self {
         ClientHello {
             id: ClientId::generate(),
             sub_domain: None,
             client_type: ClientType::Anonymous,
             reconnect_token: Some(reconnect_token),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            tunnel_type: TunnelType::default(),
+            tunnels: vec![],
+            reserve_sub_domains: false,
+        }
+    }
+}
+
+/// An additional http tunnel requested by the client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TunnelBinding {
+    /// Client chosen name, unique within the connection
+    pub name: String,
+    /// Requested sub-domain, a random one is picked if `None`
+    #[serde(default)]
+    pub sub_domain: Option<String>,
+}
+
+/// An additional tunnel the server bound for the client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BoundTunnel {
+    pub name: String,
+    pub sub_domain: String,
+    pub hostname: String,
+}
+
+/// How visitors reach a tunnel
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelType {
+    /// HTTP requests routed by their `Host` header
+    #[default]
+    Http,
+    /// Raw TCP connections to a server allocated port
+    Tcp,
+    /// UDP datagrams to a server allocated port
+    Udp,
+}
+
+impl TunnelType {
+    /// The capability a server needs to offer this kind of tunnel
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            TunnelType::Http => None,
+            TunnelType::Tcp => Some(Capability::TcpTunnel),
+            TunnelType::Udp => Some(Capability::UdpTunnel),
+        }
+    }
+}
+
+impl std::fmt::Display for TunnelType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelType::Http => f.write_str("HTTP"),
+            TunnelType::Tcp => f.write_str("TCP"),
+            TunnelType::Udp => f.write_str("UDP"),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientType {
-    Auth { key: SecretKey },
+    Auth {
+        key: SecretKey,
+    },
     Anonymous,
     AuthInfo,
+    /// Authenticated by the client certificate the control connection was opened with
+    Certificate,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
@@ -141,22 +454,116 @@ impl StreamId {
     }
 }
 
-impl ToString for StreamId {
-    fn to_string(&self) -> String {
-        format!(
+impl std::fmt::Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
             "stream_{}",
             base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.0)
         )
     }
 }
 
+/// Tracks which directions of a stream are still open
+#[derive(Debug, Clone)]
+pub struct StreamHalves(Arc<AtomicU8>);
+
+impl Default for StreamHalves {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU8::new(2)))
+    }
+}
+
+impl StreamHalves {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mark one direction as finished, returning `true` once both are
+    pub fn finish(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+            == Ok(1)
+    }
+}
+
+/// Version of the [`StreamMetadata`] encoding sent with `ControlPacket::Init`
+pub const STREAM_METADATA_VERSION: u8 = 1;
+
+/// What the server knows about the visitor behind a new stream
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamMetadata {
+    /// Address of the visitor as seen by the server
+    #[serde(default)]
+    pub remote_addr: Option<SocketAddr>,
+    /// Host the visitor asked for, only known for http tunnels
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Whether the visitor reached the edge over TLS
+    #[serde(default)]
+    pub tls: bool,
+    /// `X-Forwarded-For` of the visitor's request, if a proxy set one
+    #[serde(default)]
+    pub forwarded_for: Option<String>,
+    /// Name of the [`TunnelBinding`] the stream belongs to, `None` for the primary tunnel
+    #[serde(default)]
+    pub binding: Option<String>,
+}
+
+impl StreamMetadata {
+    /// Address of the visitor's end of the connection, with v4-mapped addresses unwrapped
+    pub fn remote_ip(&self) -> Option<IpAddr> {
+        self.remote_addr.map(|addr| addr.ip().to_canonical())
+    }
+
+    /// Address of the visitor, preferring what a proxy in front of the server reported
+    pub fn client_ip(&self) -> Option<String> {
+        self.forwarded_for
+            .as_deref()
+            .and_then(|f| f.split(',').next())
+            .map(|ip| ip.trim().to_string())
+            .filter(|ip| !ip.is_empty())
+            .or_else(|| self.remote_ip().map(|ip| ip.to_string()))
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        [
+            vec![STREAM_METADATA_VERSION],
+            serde_json::to_vec(self).unwrap_or_default(),
+        ]
+        .concat()
+    }
+
+    /// Decode a metadata record, ignoring versions we don't understand
+    fn deserialize(data: &[u8]) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        match data.split_first() {
+            Some((&STREAM_METADATA_VERSION, record)) => Ok(Some(serde_json::from_slice(record)?)),
+            _ => Ok(None),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ControlPacket {
-    Init(StreamId),
+    /// A new stream, with metadata when `Capability::StreamMetadata` was negotiated
+    Init(StreamId, Option<StreamMetadata>),
     Data(StreamId, Vec<u8>),
     Refused(StreamId),
+    /// The sender won't write any more data to this stream
     End(StreamId),
     Ping(Option<ReconnectToken>),
+    /// Grant the peer more credit (in bytes) to send on a stream
+    WindowUpdate(StreamId, u32),
+    /// A single datagram of a UDP tunnel, keyed by a per-peer stream id
+    Datagram(StreamId, Vec<u8>),
+    /// Abort both directions of a stream
+    Reset(StreamId),
+    /// The server is shutting down: no new streams will be opened and the
+    /// connection is closed after the given number of seconds
+    GoAway(u32),
+    /// A fresh access token for the connection, sent by the client before the
+    /// one it authenticated with expires
+    Reauthenticate(SecretKey),
 }
 
 pub const PING_INTERVAL: u64 = 30;
@@ -167,7 +574,12 @@ const TOKEN_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
 impl ControlPacket {
     pub fn serialize(self) -> Vec<u8> {
         match self {
-            ControlPacket::Init(sid) => [vec![0x01], sid.0.to_vec()].concat(),
+            ControlPacket::Init(sid, metadata) => [
+                vec![0x01],
+                sid.0.to_vec(),
+                metadata.map(|m| m.serialize()).unwrap_or_default(),
+            ]
+            .concat(),
             ControlPacket::Data(sid, data) => [vec![0x02], sid.0.to_vec(), data].concat(),
             ControlPacket::Refused(sid) => [vec![0x03], sid.0.to_vec()].concat(),
             ControlPacket::End(sid) => [vec![0x04], sid.0.to_vec()].concat(),
@@ -177,16 +589,35 @@ impl ControlPacket {
                 });
                 [vec![0x05], data].concat()
             }
+            ControlPacket::WindowUpdate(sid, credit) => {
+                [vec![0x06], sid.0.to_vec(), credit.to_be_bytes().to_vec()].concat()
+            }
+            ControlPacket::Datagram(sid, data) => [vec![0x07], sid.0.to_vec(), data].concat(),
+            ControlPacket::Reset(sid) => [vec![0x08], sid.0.to_vec()].concat(),
+            ControlPacket::GoAway(deadline) => [
+                vec![0x09],
+                EMPTY_STREAM.0.to_vec(),
+                deadline.to_be_bytes().to_vec(),
+            ]
+            .concat(),
+            ControlPacket::Reauthenticate(key) => {
+                [vec![0x0A], EMPTY_STREAM.0.to_vec(), key.0.into_bytes()].concat()
+            }
         }
     }
 
     pub fn packet_type(&self) -> &str {
         match &self {
             ControlPacket::Ping(_) => "PING",
-            ControlPacket::Init(_) => "INIT STREAM",
+            ControlPacket::Init(_, _) => "INIT STREAM",
             ControlPacket::Data(_, _) => "STREAM DATA",
             ControlPacket::Refused(_) => "REFUSED",
             ControlPacket::End(_) => "END STREAM",
+            ControlPacket::WindowUpdate(_, _) => "WINDOW UPDATE",
+            ControlPacket::Datagram(_, _) => "DATAGRAM",
+            ControlPacket::Reset(_) => "RESET STREAM",
+            ControlPacket::GoAway(_) => "GO AWAY",
+            ControlPacket::Reauthenticate(_) => "REAUTHENTICATE",
         }
     }
 
@@ -200,7 +631,7 @@ impl ControlPacket {
         let stream_id = StreamId(stream_id);
 
         let packet = match data[0] {
-            0x01 => ControlPacket::Init(stream_id),
+            0x01 => ControlPacket::Init(stream_id, StreamMetadata::deserialize(&data[9..])?),
             0x02 => ControlPacket::Data(stream_id, data[9..].to_vec()),
             0x03 => ControlPacket::Refused(stream_id),
             0x04 => ControlPacket::End(stream_id),
@@ -213,9 +644,113 @@ impl ControlPacket {
                     )))
                 }
             }
+            0x06 => {
+                let credit: [u8; 4] = data[9..]
+                    .try_into()
+                    .map_err(|_| "invalid WindowUpdate, bad credit length")?;
+                ControlPacket::WindowUpdate(stream_id, u32::from_be_bytes(credit))
+            }
+            0x07 => ControlPacket::Datagram(stream_id, data[9..].to_vec()),
+            0x08 => ControlPacket::Reset(stream_id),
+            0x09 => {
+                let deadline: [u8; 4] = data[9..]
+                    .try_into()
+                    .map_err(|_| "invalid GoAway, bad deadline length")?;
+                ControlPacket::GoAway(u32::from_be_bytes(deadline))
+            }
+            0x0A => ControlPacket::Reauthenticate(SecretKey(
+                String::from_utf8(data[9..].to_vec())
+                    .map_err(|_| "invalid Reauthenticate, token is not utf-8")?,
+            )),
             _ => return Err("invalid control byte in DataPacket".into()),
         };
 
         Ok(packet)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(packet: ControlPacket) -> ControlPacket {
+        let data = packet.serialize();
+        let decoded = ControlPacket::deserialize(&data).unwrap();
+        assert_eq!(decoded.clone().serialize(), data);
+        decoded
+    }
+
+    #[test]
+    fn window_update_round_trips() {
+        let stream_id = StreamId::generate();
+        match round_trip(ControlPacket::WindowUpdate(stream_id.clone(), 65_536)) {
+            ControlPacket::WindowUpdate(id, 65_536) => assert_eq!(id, stream_id),
+            other => panic!("unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn window_update_requires_four_credit_bytes() {
+        let mut data = ControlPacket::WindowUpdate(StreamId::generate(), 1).serialize();
+        data.pop();
+        assert!(ControlPacket::deserialize(&data).is_err());
+    }
+
+    #[test]
+    fn datagram_round_trips() {
+        let stream_id = StreamId::generate();
+        match round_trip(ControlPacket::Datagram(stream_id.clone(), vec![1, 2, 3])) {
+            ControlPacket::Datagram(id, data) => {
+                assert_eq!(id, stream_id);
+                assert_eq!(data, vec![1, 2, 3]);
+            }
+            other => panic!("unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reset_round_trips() {
+        let stream_id = StreamId::generate();
+        match round_trip(ControlPacket::Reset(stream_id.clone())) {
+            ControlPacket::Reset(id) => assert_eq!(id, stream_id),
+            other => panic!("unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn go_away_round_trips() {
+        match round_trip(ControlPacket::GoAway(30)) {
+            ControlPacket::GoAway(30) => {}
+            other => panic!("unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn go_away_requires_four_deadline_bytes() {
+        let data = [ControlPacket::GoAway(30).serialize(), vec![0]].concat();
+        assert!(ControlPacket::deserialize(&data).is_err());
+    }
+
+    #[test]
+    fn client_hello_debug_redacts_the_key() {
+        let key = SecretKey("pgk_0123abcd_secret".to_string());
+        let hello = ClientHello::generate(None, ClientType::Auth { key: key.clone() });
+        assert!(!format!("{:?}", hello).contains(&key.0));
+        assert!(!format!("{:?}", ControlPacket::Reauthenticate(key.clone())).contains(&key.0));
+    }
+
+    #[test]
+    fn reauthenticate_round_trips() {
+        let key = SecretKey("an-access-token".to_string());
+        match round_trip(ControlPacket::Reauthenticate(key)) {
+            ControlPacket::Reauthenticate(key) => assert_eq!(key.0, "an-access-token"),
+            other => panic!("unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reauthenticate_requires_a_utf8_token() {
+        let data = [vec![0x0A], EMPTY_STREAM.0.to_vec(), vec![0xff, 0xfe]].concat();
+        assert!(ControlPacket::deserialize(&data).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Credit based flow control for tunneled streams.
+//!
+//! Each direction of a stream starts out with [`STREAM_WINDOW`] bytes of
+//! credit. The sender spends credit for every data packet it sends and the
+//! receiver hands it back with a `WindowUpdate` once the bytes have been
+//! written out to their destination. Peers that did not negotiate
+//! [`crate::Capability::FlowControl`] get unlimited windows.
+
+use crate::{ControlPacket, StreamId};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Initial credit, in bytes, for each direction of a stream
+pub const STREAM_WINDOW: u32 = 256 * 1024;
+
+/// Most data packets queued for a stream before they are written out.
+/// Peers keeping to their window never fill it, unless they split their
+/// data into packets of a few hundred bytes.
+pub const STREAM_QUEUE_LEN: usize = 1024;
+
+/// Credit we may spend sending data to the peer
+#[derive(Debug, Clone)]
+pub struct SendWindow {
+    credit: Option<Arc<Semaphore>>,
+}
+
+impl SendWindow {
+    pub fn new(flow_control: bool) -> Self {
+        Self {
+            credit: flow_control.then(|| Arc::new(Semaphore::new(STREAM_WINDOW as usize))),
+        }
+    }
+
+    /// Wait until `len` bytes may be sent and spend that credit.
+    /// Returns `false` if the window was closed in the meantime.
+    pub async fn reserve(&self, len: usize) -> bool {
+        let credit = match &self.credit {
+            Some(credit) => credit,
+            None => return true,
+        };
+
+        let len = len.min(STREAM_WINDOW as usize) as u32;
+        match credit.acquire_many(len).await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Credit granted by the peer through a `WindowUpdate`
+    pub fn grant(&self, credit: u32) {
+        if let Some(window) = &self.credit {
+            // never let a confused peer grow the window past its initial size
+            let room = (STREAM_WINDOW as usize).saturating_sub(window.available_permits());
+            window.add_permits((credit as usize).min(room));
+        }
+    }
+
+    /// Wake up and fail anyone waiting on this window
+    pub fn close(&self) {
+        if let Some(credit) = &self.credit {
+            credit.close();
+        }
+    }
+}
+
+/// Credit the peer may spend sending data to us
+#[derive(Debug, Clone)]
+pub struct RecvWindow {
+    remaining: Option<Arc<AtomicU32>>,
+}
+
+impl RecvWindow {
+    pub fn new(flow_control: bool) -> Self {
+        Self {
+            remaining: flow_control.then(|| Arc::new(AtomicU32::new(STREAM_WINDOW))),
+        }
+    }
+
+    /// Account for `len` received bytes.
+    /// Returns `false` if the peer overran the window it was given.
+    pub fn consume(&self, len: usize) -> bool {
+        let remaining = match &self.remaining {
+            Some(remaining) => remaining,
+            None => return true,
+        };
+
+        let len = match u32::try_from(len) {
+            Ok(len) => len,
+            Err(_) => return false,
+        };
+        remaining
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |r| r.checked_sub(len))
+            .is_ok()
+    }
+
+    /// Hand `len` delivered bytes back to the peer, returning the update to send
+    pub fn release(&self, stream_id: &StreamId, len: usize) -> Option<ControlPacket> {
+        let remaining = self.remaining.as_ref()?;
+        let len = len.min(STREAM_WINDOW as usize) as u32;
+        if len == 0 {
+            return None;
+        }
+
+        let _ = remaining.fetch_update(Ordering::AcqRel, Ordering::Acquire, |r| {
+            Some(r.saturating_add(len).min(STREAM_WINDOW))
+        });
+        Some(ControlPacket::WindowUpdate(stream_id.clone(), len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn available(window: &SendWindow) -> usize {
+        window.credit.as_ref().unwrap().available_permits()
+    }
+
+    #[tokio::test]
+    async fn send_window_spends_and_regains_credit() {
+        let window = SendWindow::new(true);
+        assert!(window.reserve(1000).await);
+        assert_eq!(available(&window), STREAM_WINDOW as usize - 1000);
+
+        window.grant(400);
+        assert_eq!(available(&window), STREAM_WINDOW as usize - 600);
+    }
+
+    #[tokio::test]
+    async fn send_window_never_grows_past_its_initial_size() {
+        let window = SendWindow::new(true);
+        assert!(window.reserve(100).await);
+
+        window.grant(STREAM_WINDOW);
+        assert_eq!(available(&window), STREAM_WINDOW as usize);
+    }
+
+    #[tokio::test]
+    async fn closed_send_window_refuses_reservations() {
+        let window = SendWindow::new(true);
+        window.close();
+        assert!(!window.reserve(1).await);
+    }
+
+    #[tokio::test]
+    async fn send_window_without_flow_control_is_unlimited() {
+        let window = SendWindow::new(false);
+        assert!(window.reserve(STREAM_WINDOW as usize * 4).await);
+        window.close();
+        assert!(window.reserve(1).await);
+    }
+
+    #[test]
+    fn recv_window_rejects_overruns() {
+        let window = RecvWindow::new(true);
+        assert!(window.consume(STREAM_WINDOW as usize - 10));
+        assert!(!window.consume(11));
+
+        // the rejected bytes were not counted
+        assert!(window.consume(10));
+        assert!(!window.consume(1));
+    }
+
+    #[test]
+    fn recv_window_release_hands_credit_back() {
+        let stream_id = StreamId::generate();
+        let window = RecvWindow::new(true);
+        assert!(window.consume(STREAM_WINDOW as usize));
+
+        match window.release(&stream_id, 500) {
+            Some(ControlPacket::WindowUpdate(id, 500)) => assert_eq!(id, stream_id),
+            other => panic!("unexpected update: {:?}", other),
+        }
+        assert!(window.consume(500));
+        assert!(!window.consume(1));
+
+        assert!(window.release(&stream_id, 0).is_none());
+    }
+
+    #[test]
+    fn recv_window_release_is_capped_at_the_window() {
+        let stream_id = StreamId::generate();
+        let window = RecvWindow::new(true);
+        let _ = window.release(&stream_id, STREAM_WINDOW as usize * 2);
+
+        assert!(window.consume(STREAM_WINDOW as usize));
+        assert!(!window.consume(1));
+    }
+
+    #[test]
+    fn recv_window_without_flow_control_is_unlimited() {
+        let window = RecvWindow::new(false);
+        assert!(window.consume(STREAM_WINDOW as usize * 4));
+        assert!(window.release(&StreamId::generate(), 100).is_none());
+    }
+}
@@ -6,17 +6,27 @@
 pub struct ActiveStream {
     pub id: StreamId,
     pub client: ConnectedClient,
-    pub tx: UnboundedSender<StreamMessage>,
+    pub tx: Sender<StreamMessage>,
+    /// credit for forwarding remote bytes to the client
+    pub send_window: SendWindow,
+    /// credit the client has for sending us bytes
+    pub recv_window: RecvWindow,
+    /// directions of the stream that are still open
+    pub halves: StreamHalves,
 }
 
 impl ActiveStream {
-    pub fn new(client: ConnectedClient) -> (Self, UnboundedReceiver<StreamMessage>) {
-        let (tx, rx) = unbounded();
+    pub fn new(client: ConnectedClient) -> (Self, Receiver<StreamMessage>) {
+        let (tx, rx) = channel(STREAM_QUEUE_LEN);
+        let flow_control = client.capabilities.has(Capability::FlowControl);
         (
             ActiveStream {
                 id: StreamId::generate(),
                 client,
                 tx,
+                send_window: SendWindow::new(flow_control),
+                recv_window: RecvWindow::new(flow_control),
+                halves: StreamHalves::new(),
             },
             rx,
         )
@@ -29,6 +39,10 @@ use super::*;
 #[derive(Debug, Clone)]
 pub enum StreamMessage {
     Data(Vec<u8>),
+    /// the client won't send any more data
+    End,
+    /// the client aborted the stream
+    Reset,
     TunnelRefused,
     NoClientTunnel,
 }
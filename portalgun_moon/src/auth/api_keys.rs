@@ -0,0 +1,301 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Long-lived API keys issued by the server, i.e. for CI runners.
+//!
+//! Only a hash of each key is kept, in a JSON file managed with
+//! `portalgun_moon api-key`. Changes to the file, i.e. revocations, are
+//! picked up without restarting the server.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use portalgun_lib::SecretKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use super::json_file::{self, modified};
+use super::{
+    match_sub_domain, AuthError, AuthResult, AuthService, ClientCertificate, Identity, LoginMethod,
+};
+
+/// Prefix telling API keys apart from other credentials
+const API_KEY_PREFIX: &str = "pgk_";
+
+/// Prefix of the subject of API keys, keeping owners apart from the users of the identity provider
+const SUBJECT_PREFIX: &str = "apikey:";
+
+/// An issued API key, without the key itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Short identifier to revoke the key by
+    pub id: String,
+    /// Hex encoded SHA-256 of the key
+    hash: String,
+    /// Who the key was issued to
+    pub owner: String,
+    /// Sub-domain patterns the key may use
+    pub sub_domains: Vec<String>,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// The subject of clients using the key, i.e. `apikey:ci`
+    pub fn subject(&self) -> String {
+        format!("{}{}", SUBJECT_PREFIX, self.owner)
+    }
+}
+
+/// The API keys of a file, reloaded whenever it changes
+#[derive(Debug)]
+pub struct ApiKeys {
+    path: PathBuf,
+    loaded: RwLock<LoadedKeys>,
+}
+
+#[derive(Debug, Default)]
+struct LoadedKeys {
+    /// keys by hash
+    keys: HashMap<String, ApiKey>,
+    modified: Option<SystemTime>,
+}
+
+impl ApiKeys {
+    /// Load the keys from `path`, starting with none if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let keys = Self {
+            path: path.to_owned(),
+            loaded: RwLock::new(LoadedKeys::default()),
+        };
+        keys.reload()?;
+        Ok(keys)
+    }
+
+    /// All issued keys
+    pub fn list(&self) -> Vec<ApiKey> {
+        let mut keys: Vec<_> = self.loaded.read().unwrap().keys.values().cloned().collect();
+        keys.sort_by_key(|key| key.created);
+        keys
+    }
+
+    /// Issue a new key, returning it along with its record. The key itself is not kept.
+    pub fn mint(
+        &self,
+        owner: &str,
+        sub_domains: Vec<String>,
+        expires: Option<DateTime<Utc>>,
+    ) -> Result<(String, ApiKey), anyhow::Error> {
+        let id = SecretKey::generate().0[..8].to_lowercase();
+        let key = format!("{}{}_{}", API_KEY_PREFIX, id, SecretKey::generate().0);
+        let record = ApiKey {
+            id,
+            hash: hash(&key),
+            owner: owner.to_owned(),
+            sub_domains,
+            created: Utc::now(),
+            expires,
+        };
+
+        self.update(|keys| {
+            keys.insert(record.hash.clone(), record.clone());
+        })?;
+        Ok((key, record))
+    }
+
+    /// Revoke the key with `id`, returning whether there was one
+    pub fn revoke(&self, id: &str) -> Result<bool, anyhow::Error> {
+        let mut revoked = false;
+        self.update(|keys| {
+            let before = keys.len();
+            keys.retain(|_, key| key.id != id);
+            revoked = keys.len() != before;
+        })?;
+        Ok(revoked)
+    }
+
+    /// Look up an unexpired key
+    fn find(&self, key: &str) -> Result<ApiKey, AuthError> {
+        if let Err(error) = self.reload_if_changed() {
+            tracing::warn!(
+                ?error,
+                "failed to reload API keys, keeping the ones we have"
+            );
+        }
+
+        let record = self
+            .loaded
+            .read()
+            .unwrap()
+            .keys
+            .get(&hash(key))
+            .cloned()
+            .ok_or(AuthError::UnknownToken)?;
+
+        if record.expires.is_some_and(|expires| expires < Utc::now()) {
+            return Err(AuthError::Expired);
+        }
+        Ok(record)
+    }
+
+    fn reload_if_changed(&self) -> Result<(), anyhow::Error> {
+        let modified = modified(&self.path)?;
+        if modified != self.loaded.read().unwrap().modified {
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<(), anyhow::Error> {
+        let modified = modified(&self.path)?;
+        let keys: Vec<ApiKey> = json_file::load(&self.path)?;
+        tracing::info!(keys = keys.len(), path = %self.path.display(), "loaded API keys");
+
+        *self.loaded.write().unwrap() = LoadedKeys {
+            keys: keys
+                .into_iter()
+                .map(|key| (key.hash.clone(), key))
+                .collect(),
+            modified,
+        };
+        Ok(())
+    }
+
+    /// Change the keys on disk, starting from the latest version of the file
+    fn update(
+        &self,
+        change: impl FnOnce(&mut HashMap<String, ApiKey>),
+    ) -> Result<(), anyhow::Error> {
+        self.reload()?;
+        let mut loaded = self.loaded.write().unwrap();
+        change(&mut loaded.keys);
+
+        let mut keys: Vec<_> = loaded.keys.values().collect();
+        keys.sort_by_key(|key| key.created);
+        json_file::save_atomic(&self.path, &keys)?;
+        loaded.modified = modified(&self.path)?;
+        Ok(())
+    }
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(hmac_sha256::Hash::hash(key.as_bytes()))
+}
+
+/// Accepts API keys, and hands every other credential to another auth service
+pub struct ApiKeyAuth {
+    keys: ApiKeys,
+    inner: Arc<dyn AuthService>,
+}
+
+impl ApiKeyAuth {
+    pub fn new(keys: ApiKeys, inner: Arc<dyn AuthService>) -> Self {
+        Self { keys, inner }
+    }
+}
+
+#[async_trait]
+impl AuthService for ApiKeyAuth {
+    /// Authenticate a subdomain with an AuthKey
+    async fn auth_sub_domain(
+        &self,
+        auth_key: &str,
+        subdomain: &str,
+    ) -> Result<AuthResult, AuthError> {
+        if !auth_key.starts_with(API_KEY_PREFIX) {
+            return self.inner.auth_sub_domain(auth_key, subdomain).await;
+        }
+
+        let key = self.keys.find(auth_key)?;
+        match_sub_domain(&key.sub_domains, subdomain)
+    }
+
+    async fn auth_certificate(
+        &self,
+        certificate: &ClientCertificate,
+        subdomain: &str,
+    ) -> Result<AuthResult, AuthError> {
+        self.inner.auth_certificate(certificate, subdomain).await
+    }
+
+    async fn identity(&self, auth_key: &str) -> Identity {
+        if !auth_key.starts_with(API_KEY_PREFIX) {
+            return self.inner.identity(auth_key).await;
+        }
+
+        match self.keys.find(auth_key) {
+            Ok(key) => Identity {
+                subject: Some(key.subject()),
+                token_id: Some(key.id),
+                expires: key.expires,
+            },
+            Err(_) => Identity::default(),
+        }
+    }
+
+    fn login_method(&self) -> LoginMethod {
+        self.inner.login_method()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::json_file::tests::temporary_file;
+    use crate::auth::NoAuth;
+
+    #[test]
+    fn finds_issued_keys() {
+        let path = temporary_file();
+        let keys = ApiKeys::load(&path).unwrap();
+        let (key, record) = keys.mint("ci", vec!["ci-*".into()], None).unwrap();
+
+        assert!(key.starts_with(API_KEY_PREFIX));
+        assert_eq!(keys.find(&key).unwrap().id, record.id);
+        assert!(matches!(
+            keys.find("pgk_unknown"),
+            Err(AuthError::UnknownToken)
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_expired_keys() {
+        let path = temporary_file();
+        let keys = ApiKeys::load(&path).unwrap();
+        let expired = Utc::now() - chrono::Duration::minutes(1);
+        let (key, _) = keys.mint("ci", vec![], Some(expired)).unwrap();
+
+        assert!(matches!(keys.find(&key), Err(AuthError::Expired)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_revoked_keys() {
+        let path = temporary_file();
+        let keys = ApiKeys::load(&path).unwrap();
+        let (key, record) = keys.mint("ci", vec![], None).unwrap();
+
+        assert!(keys.revoke(&record.id).unwrap());
+        assert!(!keys.revoke(&record.id).unwrap());
+        assert!(matches!(keys.find(&key), Err(AuthError::UnknownToken)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn identifies_keys_by_owner() {
+        let path = temporary_file();
+        let keys = ApiKeys::load(&path).unwrap();
+        let (key, record) = keys.mint("ci", vec![], None).unwrap();
+        let auth = ApiKeyAuth::new(keys, Arc::new(NoAuth));
+
+        let identity = auth.identity(&key).await;
+        assert_eq!(identity.subject.as_deref(), Some("apikey:ci"));
+        assert_eq!(identity.token_id, Some(record.id));
+        assert_eq!(auth.identity("pgk_unknown").await.subject, None);
+        std::fs::remove_file(path).unwrap();
+    }
+}
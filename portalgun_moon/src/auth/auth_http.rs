@@ -0,0 +1,82 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Delegate the decision to an external authorizer.
+//!
+//! For every sub-domain a client asks for, the authorizer gets a `POST` with
+//! `{"token": "...", "sub_domain": "..."}` and answers with a status:
+//! `2xx` allows it, `401` rejects the token, `403` rejects the sub-domain.
+//! Anything else is treated as the authorizer being unavailable. The body of
+//! a rejection is passed on to the client.
+
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::time::Duration;
+use url::Url;
+
+use super::{AuthError, AuthResult, AuthService, LoginMethod};
+
+/// How long the authorizer gets to answer
+const CALLOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+struct AuthRequest<'a> {
+    token: &'a str,
+    sub_domain: &'a str,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpAuth {
+    url: Url,
+    client: reqwest::Client,
+}
+
+impl HttpAuth {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthService for HttpAuth {
+    /// Authenticate a subdomain with an AuthKey
+    async fn auth_sub_domain(
+        &self,
+        auth_key: &str,
+        subdomain: &str,
+    ) -> Result<AuthResult, AuthError> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .timeout(CALLOUT_TIMEOUT)
+            .json(&AuthRequest {
+                token: auth_key,
+                sub_domain: subdomain,
+            })
+            .send()
+            .await
+            .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(AuthResult::Available);
+        }
+
+        let reason = response.text().await.unwrap_or_default();
+        tracing::debug!(%status, %reason, "authorizer rejected the client");
+        match status {
+            StatusCode::UNAUTHORIZED => Err(AuthError::UnknownToken),
+            StatusCode::FORBIDDEN => Err(AuthError::Denied(reason)),
+            _ => Err(AuthError::Unavailable(format!("status {}", status))),
+        }
+    }
+
+    fn login_method(&self) -> LoginMethod {
+        LoginMethod::Token
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Static access tokens, i.e. API keys handed out by the operator.
+//!
+//! The file lists each token with the sub-domain patterns it may use, and
+//! optionally who it belongs to, for reserving sub-domains:
+//!
+//! ```json
+//! [
+//!   { "token": "s3cr3t", "sub_domains": ["^demo$", "^staging-.*"], "owner": "ci" }
+//! ]
+//! ```
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::{match_sub_domain, AuthError, AuthResult, AuthService, Identity, LoginMethod};
+
+#[derive(Debug, Clone, Deserialize)]
+struct TokenEntry {
+    token: String,
+    sub_domains: Vec<String>,
+    #[serde(default)]
+    owner: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenFileAuth {
+    tokens: HashMap<String, TokenEntry>,
+}
+
+impl TokenFileAuth {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let entries: Vec<TokenEntry> = serde_json::from_slice(&std::fs::read(path)?)?;
+        tracing::info!(tokens = entries.len(), path = %path.display(), "loaded access tokens");
+
+        Ok(Self {
+            tokens: entries
+                .into_iter()
+                .map(|entry| (entry.token.clone(), entry))
+                .collect(),
+        })
+    }
+}
+
+#[async_trait]
+impl AuthService for TokenFileAuth {
+    /// Authenticate a subdomain with an AuthKey
+    async fn auth_sub_domain(
+        &self,
+        auth_key: &str,
+        subdomain: &str,
+    ) -> Result<AuthResult, AuthError> {
+        let entry = self.tokens.get(auth_key).ok_or(AuthError::UnknownToken)?;
+        match_sub_domain(&entry.sub_domains, subdomain)
+    }
+
+    async fn identity(&self, auth_key: &str) -> Identity {
+        Identity {
+            subject: self
+                .tokens
+                .get(auth_key)
+                .and_then(|entry| entry.owner.clone()),
+            ..Identity::default()
+        }
+    }
+
+    fn login_method(&self) -> LoginMethod {
+        LoginMethod::Token
+    }
+}
@@ -0,0 +1,245 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Which sub-domains the claims of a token grant.
+//!
+//! Sub-domains are regex patterns, taken from the token in one or more ways:
+//! listed in a claim, granted to the groups listed in a claim, or built from
+//! a template filled in with claims, i.e. `{preferred_username}(-.*)?`.
+//! Filled in templates must match the whole sub-domain.
+//! Claims are addressed by dotted paths into nested objects, i.e.
+//! `realm_access.roles`.
+
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Which sub-domains an authenticated user may use
+#[derive(Debug, Clone)]
+pub enum SubDomainPolicy {
+    /// Any authenticated user may use any sub-domain
+    Any,
+    /// The union of the patterns granted by each source
+    Claims(Vec<SubDomainSource>),
+}
+
+impl Default for SubDomainPolicy {
+    /// The `portalgun_subdomains` claim, at the top level or nested in `claims`
+    fn default() -> Self {
+        SubDomainPolicy::Claims(vec![
+            SubDomainSource::Claim("portalgun_subdomains".to_string()),
+            SubDomainSource::Claim("claims.portalgun_subdomains".to_string()),
+        ])
+    }
+}
+
+/// A way to get sub-domain patterns from the claims of a token
+#[derive(Debug, Clone)]
+pub enum SubDomainSource {
+    /// Patterns listed in a claim
+    Claim(String),
+    /// Patterns granted to each of the groups listed in `claim`
+    Groups {
+        claim: String,
+        patterns: HashMap<String, Vec<String>>,
+    },
+    /// A pattern with `{claim}` placeholders, filled in with regex escaped claim values
+    /// and anchored to match whole sub-domains
+    Template(String),
+}
+
+impl SubDomainPolicy {
+    /// Patterns of the sub-domains granted by `claims`, `None` if any sub-domain may be used
+    pub fn patterns(&self, claims: &Map<String, Value>) -> Option<Vec<String>> {
+        let sources = match self {
+            SubDomainPolicy::Any => return None,
+            SubDomainPolicy::Claims(sources) => sources,
+        };
+
+        Some(
+            sources
+                .iter()
+                .flat_map(|source| source.patterns(claims))
+                .collect(),
+        )
+    }
+}
+
+impl SubDomainSource {
+    fn patterns(&self, claims: &Map<String, Value>) -> Vec<String> {
+        match self {
+            SubDomainSource::Claim(path) => claim_strings(claims, path),
+            SubDomainSource::Groups { claim, patterns } => claim_strings(claims, claim)
+                .iter()
+                .filter_map(|group| patterns.get(group))
+                .flatten()
+                .cloned()
+                .collect(),
+            SubDomainSource::Template(template) => {
+                fill_template(template, claims).into_iter().collect()
+            }
+        }
+    }
+}
+
+/// Look up a claim by its dotted path
+fn claim<'a>(claims: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let (first, rest) = match path.split_once('.') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (path, None),
+    };
+
+    match (claims.get(first)?, rest) {
+        (value, None) => Some(value),
+        (Value::Object(nested), Some(rest)) => claim(nested, rest),
+        _ => None,
+    }
+}
+
+/// The string values of a claim, which may be a single string or an array of them
+fn claim_strings(claims: &Map<String, Value>, path: &str) -> Vec<String> {
+    match claim(claims, path) {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_owned)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Fill in the `{claim}` placeholders of `template`, `None` if a claim is missing.
+/// Braces that don't name a claim, i.e. regex repetitions like `{2,4}`, are kept.
+/// The pattern is anchored, so `{preferred_username}` doesn't grant `evil-alice`.
+fn fill_template(template: &str, claims: &Map<String, Value>) -> Option<String> {
+    let mut filled = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        let name = &rest[start + 1..end];
+        let is_claim = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+
+        filled.push_str(&rest[..start]);
+        if is_claim {
+            let value = match claim(claims, name)? {
+                Value::String(value) => value.clone(),
+                Value::Number(value) => value.to_string(),
+                _ => return None,
+            };
+            filled.push_str(&regex::escape(&value.to_lowercase()));
+        } else {
+            filled.push_str(&rest[start..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+
+    filled.push_str(rest);
+    Some(format!("^(?:{})$", filled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use serde_json::json;
+
+    fn claims(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(claims) => claims,
+            _ => unreachable!(),
+        }
+    }
+
+    fn grants(template: &str, claims: &Map<String, Value>, subdomain: &str) -> bool {
+        let pattern = fill_template(template, claims).unwrap();
+        Regex::new(&pattern).unwrap().is_match(subdomain)
+    }
+
+    #[test]
+    fn fills_in_claims() {
+        let claims = claims(json!({"preferred_username": "alice", "org": {"id": 7}}));
+        assert_eq!(
+            fill_template("{preferred_username}-{org.id}", &claims).as_deref(),
+            Some("^(?:alice-7)$")
+        );
+    }
+
+    #[test]
+    fn missing_claims_grant_nothing() {
+        let claims = claims(json!({"preferred_username": "alice", "groups": ["a"]}));
+        assert_eq!(fill_template("{email}", &claims), None);
+        assert_eq!(fill_template("{groups}", &claims), None);
+    }
+
+    #[test]
+    fn escapes_claim_values() {
+        let claims = claims(json!({"preferred_username": "a.b+c"}));
+        assert_eq!(
+            fill_template("{preferred_username}", &claims).as_deref(),
+            Some(r"^(?:a\.b\+c)$")
+        );
+        assert!(grants("{preferred_username}", &claims, "a.b+c"));
+        assert!(!grants("{preferred_username}", &claims, "axb+c"));
+        assert!(!grants("{preferred_username}", &claims, "a.bbc"));
+    }
+
+    #[test]
+    fn lowercases_claim_values() {
+        let claims = claims(json!({"preferred_username": "Alice"}));
+        assert!(grants("{preferred_username}", &claims, "alice"));
+    }
+
+    #[test]
+    fn keeps_regex_repetitions() {
+        let claims = claims(json!({"preferred_username": "alice"}));
+        assert_eq!(
+            fill_template("{preferred_username}[0-9]{2,4}", &claims).as_deref(),
+            Some("^(?:alice[0-9]{2,4})$")
+        );
+        assert!(grants(
+            "{preferred_username}[0-9]{2,4}",
+            &claims,
+            "alice123"
+        ));
+        assert!(!grants("{preferred_username}[0-9]{2,4}", &claims, "alice1"));
+    }
+
+    #[test]
+    fn anchors_patterns() {
+        let claims = claims(json!({"preferred_username": "foo"}));
+        assert!(grants("{preferred_username}", &claims, "foo"));
+        assert!(!grants("{preferred_username}", &claims, "evilfoo"));
+        assert!(!grants("{preferred_username}", &claims, "foo-evil"));
+
+        assert!(grants("{preferred_username}(-.*)?", &claims, "foo-dev"));
+        assert!(!grants(
+            "{preferred_username}(-.*)?",
+            &claims,
+            "evilfoo-dev"
+        ));
+    }
+
+    #[test]
+    fn anchors_alternations_as_a_whole() {
+        let claims = claims(json!({"preferred_username": "foo"}));
+        assert!(!grants("{preferred_username}|bar", &claims, "evilbar"));
+        assert!(!grants("{preferred_username}|bar", &claims, "foo-evil"));
+        assert!(grants("{preferred_username}|bar", &claims, "bar"));
+    }
+
+    #[test]
+    fn accepts_templates_anchored_already() {
+        let claims = claims(json!({"preferred_username": "foo"}));
+        assert!(grants("^{preferred_username}(-.*)?$", &claims, "foo-dev"));
+        assert!(!grants("^{preferred_username}(-.*)?$", &claims, "evilfoo"));
+    }
+}
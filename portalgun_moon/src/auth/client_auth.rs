@@ -4,21 +4,71 @@
 // SPDX-License-Identifier: MIT
 
 use crate::auth::reconnect_token::ReconnectTokenPayload;
-use crate::auth::{AuthResult, AuthService};
-use crate::{ReconnectToken, CONFIG};
+use crate::auth::{AuthError, AuthResult, AuthService, ClientCertificate, Identity, LoginMethod};
+use crate::{Config, ConnectedClient, ReconnectToken, ServerState};
+use chrono::{DateTime, Utc};
 use futures::{SinkExt, StreamExt};
-use portalgun_lib::{ClientHello, ClientId, ClientType, ServerHello};
+use portalgun_lib::{
+    Capabilities, Capability, ClientHello, ClientId, ClientType, ErrorCode, SecretKey, ServerHello,
+    TunnelBinding, TunnelType, PROTOCOL_VERSION,
+};
+use std::collections::HashMap;
+use std::net::IpAddr;
 use tracing::{error, info};
 use warp::filters::ws::{Message, WebSocket};
 
+/// Most additional tunnels a single client may bind
+const MAX_TUNNEL_BINDINGS: usize = 16;
+
 pub struct ClientHandshake {
     pub id: ClientId,
     pub sub_domain: String,
     pub is_anonymous: bool,
+    pub protocol_version: u32,
+    pub capabilities: Capabilities,
+    pub tunnel_type: TunnelType,
+    /// additional sub-domains, mapped to the name of their binding
+    pub bindings: HashMap<String, String>,
+    /// when the tunnel is closed, for anonymous clients
+    pub expires: Option<DateTime<Utc>>,
+    /// who the client authenticated as
+    pub identity: Identity,
+}
+
+/// What an authenticated client proved who it is with
+enum Credential {
+    Token(SecretKey),
+    /// the verified certificate of the control connection
+    Certificate(ClientCertificate),
+}
+
+impl Credential {
+    async fn auth_sub_domain(
+        &self,
+        auth: &dyn AuthService,
+        sub_domain: &str,
+    ) -> Result<AuthResult, AuthError> {
+        match self {
+            Credential::Token(key) => auth.auth_sub_domain(&key.0, sub_domain).await,
+            Credential::Certificate(certificate) => {
+                auth.auth_certificate(certificate, sub_domain).await
+            }
+        }
+    }
+
+    async fn identity(&self, auth: &dyn AuthService) -> Identity {
+        match self {
+            Credential::Token(key) => auth.identity(&key.0).await,
+            Credential::Certificate(certificate) => certificate.identity(),
+        }
+    }
 }
 
-#[tracing::instrument(skip(websocket))]
+#[tracing::instrument(skip(state, certificate, websocket))]
 pub async fn auth_client_handshake(
+    state: &ServerState,
+    client_ip: IpAddr,
+    certificate: Option<ClientCertificate>,
     mut websocket: WebSocket,
 ) -> Option<(WebSocket, ClientHandshake)> {
     let client_hello_data = match websocket.next().await {
@@ -29,16 +79,26 @@ pub async fn auth_client_handshake(
         }
     };
 
-    auth_client(client_hello_data.as_bytes(), websocket).await
+    auth_client(
+        state,
+        client_ip,
+        certificate,
+        client_hello_data.as_bytes(),
+        websocket,
+    )
+    .await
 }
 
-#[tracing::instrument(skip(client_hello_data, websocket))]
+#[tracing::instrument(skip(state, certificate, client_hello_data, websocket))]
 async fn auth_client(
+    state: &ServerState,
+    client_ip: IpAddr,
+    certificate: Option<ClientCertificate>,
     client_hello_data: &[u8],
     mut websocket: WebSocket,
 ) -> Option<(WebSocket, ClientHandshake)> {
     // parse the client hello
-    let client_hello: ClientHello = match serde_json::from_slice(client_hello_data) {
+    let mut client_hello: ClientHello = match serde_json::from_slice(client_hello_data) {
         Ok(ch) => ch,
         Err(error) => {
             error!(?error, "invalid client hello");
@@ -50,70 +110,192 @@ async fn auth_client(
 
     info!(?client_hello, "got client hello");
 
-    let (auth_key, client_id, requested_sub_domain) = match client_hello.client_type {
-        ClientType::Anonymous => {
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+    // agree on a protocol version and feature set before anything else
+    let config = &state.config;
+    let (protocol_version, capabilities) = match negotiate_protocol(config, &client_hello) {
+        Some(negotiated) => negotiated,
+        None => {
+            error!(
+                client_version = client_hello.protocol_version,
+                "unsupported client protocol version"
+            );
+            let server_hello = if client_hello.protocol_version == 0 {
+                // legacy clients don't understand the typed rejection
+                ServerHello::Error(format!(
+                    "Unsupported client version, please upgrade to protocol version {} or newer.",
+                    config.min_protocol_version
+                ))
+            } else {
+                ServerHello::UnsupportedProtocolVersion {
+                    min_version: config.min_protocol_version,
+                    max_version: PROTOCOL_VERSION,
+                }
+            };
+            let data = serde_json::to_vec(&server_hello).unwrap_or_default();
             let _ = websocket.send(Message::binary(data)).await;
             return None;
+        }
+    };
 
-            // // determine the client and subdomain
-            // let (client_id, sub_domain) =
-            //     match (client_hello.reconnect_token, client_hello.sub_domain) {
-            //         (Some(token), _) => {
-            //             return handle_reconnect_token(token, websocket).await;
-            //         }
-            //         (None, Some(sd)) => (
-            //             ClientId::generate(),
-            //             ServerHello::prefixed_random_domain(&sd),
-            //         ),
-            //         (None, None) => (ClientId::generate(), ServerHello::random_domain()),
-            //     };
-
-            // return Some((
-            //     websocket,
-            //     ClientHandshake {
-            //         id: client_id,
-            //         sub_domain,
-            //         is_anonymous: true,
-            //     },
-            // ));
-        }
-        ClientType::Auth { key } => match client_hello.sub_domain {
-            Some(requested_sub_domain) => {
-                let client_id = key.client_id();
-                let (ws, sub_domain) = match sanitize_sub_domain_and_pre_validate(
-                    websocket,
-                    requested_sub_domain,
-                    &client_id,
+    let tunnel_type = client_hello.tunnel_type;
+    if let Some(required) = tunnel_type.capability() {
+        if !capabilities.has(required) {
+            error!(%tunnel_type, "client requested a disabled tunnel type");
+            reject(
+                &mut websocket,
+                &capabilities,
+                ErrorCode::TunnelTypeDisabled,
+                format!("{} tunnels are not enabled on this server.", tunnel_type),
+            )
+            .await;
+            return None;
+        }
+    }
+
+    let tunnels = std::mem::take(&mut client_hello.tunnels);
+    let reserve = client_hello.reserve_sub_domains && capabilities.has(Capability::Reservations);
+    let (credential, client_id) = match client_hello.client_type {
+        ClientType::Anonymous => {
+            let limits = match &config.anonymous {
+                Some(limits) => limits,
+                None => {
+                    reject(
+                        &mut websocket,
+                        &capabilities,
+                        ErrorCode::AnonymousNotAllowed,
+                        "This server does not accept anonymous clients.",
+                    )
+                    .await;
+                    return None;
+                }
+            };
+
+            if !tunnels.is_empty() {
+                reject(
+                    &mut websocket,
+                    &capabilities,
+                    ErrorCode::InvalidTunnels,
+                    "Anonymous clients can't open additional tunnels.",
                 )
-                .await
-                {
-                    Some(s) => s,
-                    None => return None,
+                .await;
+                return None;
+            }
+
+            // determine the client and subdomain, reconnecting clients keep theirs
+            let expires = Utc::now() + chrono::Duration::from_std(limits.max_lifetime).ok()?;
+            let (client_id, sub_domain, expires) =
+                match (client_hello.reconnect_token, client_hello.sub_domain) {
+                    (Some(token), _) => {
+                        let (ws, payload) =
+                            handle_reconnect_token(config, token, websocket, &capabilities).await?;
+                        websocket = ws;
+                        (
+                            payload.client_id,
+                            payload.sub_domain,
+                            payload.tunnel_expires.unwrap_or(expires),
+                        )
+                    }
+                    (None, Some(sub_domain)) => {
+                        let client_id = ClientId::generate();
+                        let (ws, sub_domain) = sanitize_sub_domain_and_pre_validate(
+                            state,
+                            websocket,
+                            &capabilities,
+                            ServerHello::prefixed_random_domain(&sub_domain),
+                            &client_id,
+                        )
+                        .await?;
+                        websocket = ws;
+                        (client_id, sub_domain, expires)
+                    }
+                    (None, None) => (ClientId::generate(), ServerHello::random_domain(), expires),
                 };
-                websocket = ws;
 
-                (key, client_id, sub_domain)
+            if Utc::now() >= expires {
+                reject(
+                    &mut websocket,
+                    &capabilities,
+                    ErrorCode::TunnelExpired,
+                    "This anonymous tunnel has expired, please open a new one.",
+                )
+                .await;
+                return None;
+            }
+
+            if !state
+                .anonymous_clients
+                .admit(&client_id, client_ip, limits.max_tunnels_per_ip)
+            {
+                error!(%client_ip, "too many anonymous tunnels");
+                reject(
+                    &mut websocket,
+                    &capabilities,
+                    ErrorCode::TooManyTunnels,
+                    format!(
+                        "At most {} anonymous tunnels may be open at once.",
+                        limits.max_tunnels_per_ip
+                    ),
+                )
+                .await;
+                return None;
             }
-            None => {
-                info!(?key, "Using key: ");
-                if let Some(token) = client_hello.reconnect_token {
-                    return handle_reconnect_token(token, websocket).await;
-                } else {
-                    let sub_domain = ServerHello::random_domain();
-                    let client_id = key.client_id();
-                    (key, client_id, sub_domain)
+
+            return Some((
+                websocket,
+                ClientHandshake {
+                    id: client_id,
+                    sub_domain,
+                    is_anonymous: true,
+                    protocol_version,
+                    capabilities,
+                    tunnel_type,
+                    bindings: HashMap::new(),
+                    expires: Some(expires),
+                    identity: Identity::default(),
+                },
+            ));
+        }
+        ClientType::Auth { key } => {
+            let client_id = key.client_id();
+            (Credential::Token(key), client_id)
+        }
+        ClientType::Certificate => {
+            let certificate = match certificate {
+                Some(certificate) => certificate,
+                None => {
+                    error!("certificate client without a client certificate");
+                    reject(
+                        &mut websocket,
+                        &capabilities,
+                        ErrorCode::TokenInvalid,
+                        "No client certificate was presented.",
+                    )
+                    .await;
+                    return None;
                 }
-            }
-        },
+            };
+            let client_id = certificate.client_id();
+            (Credential::Certificate(certificate), client_id)
+        }
         ClientType::AuthInfo => {
             // Send the auth information
-            let (discovery, client_id, scopes) =
-                crate::AUTH_DB_SERVICE.read().await.get_configuration();
-            let authinfo = ServerHello::AuthInfo {
-                oidc_client_id: client_id,
-                oidc_discovery: discovery,
-                oidc_scopes: scopes,
+            let authinfo = match state.auth.login_method() {
+                LoginMethod::Oidc {
+                    discovery_url,
+                    client_id,
+                    scopes,
+                } => ServerHello::AuthInfo {
+                    oidc_client_id: client_id,
+                    oidc_discovery: discovery_url,
+                    oidc_scopes: scopes,
+                },
+                LoginMethod::Token if capabilities.has(Capability::TokenLogin) => {
+                    ServerHello::TokenAuthInfo
+                }
+                LoginMethod::Token => ServerHello::Error(
+                    "This server does not use OpenID Connect, please upgrade portalgun to log in."
+                        .to_string(),
+                ),
             };
             let data = serde_json::to_vec(&authinfo).unwrap_or_default();
             let _ = websocket.send(Message::binary(data)).await;
@@ -121,62 +303,375 @@ async fn auth_client(
         }
     };
 
+    let identity = credential.identity(state.auth.as_ref()).await;
+    if is_denied(state, &identity) {
+        error!(?identity, "client credentials are denied");
+        reject(
+            &mut websocket,
+            &capabilities,
+            ErrorCode::TokenRevoked,
+            "Your credentials were revoked.",
+        )
+        .await;
+        return None;
+    }
+
+    let requested_sub_domain = match client_hello.sub_domain {
+        Some(requested_sub_domain) => {
+            let (ws, sub_domain) = sanitize_sub_domain_and_pre_validate(
+                state,
+                websocket,
+                &capabilities,
+                requested_sub_domain,
+                &client_id,
+            )
+            .await?;
+            websocket = ws;
+            sub_domain
+        }
+        None => ServerHello::random_domain(),
+    };
+
+    let (websocket, sub_domain) = auth_sub_domain(
+        state,
+        websocket,
+        &capabilities,
+        &credential,
+        &identity,
+        requested_sub_domain,
+        reserve,
+    )
+    .await?;
+
+    let handshake = ClientHandshake {
+        id: client_id,
+        sub_domain,
+        is_anonymous: false,
+        protocol_version,
+        capabilities,
+        tunnel_type,
+        bindings: HashMap::new(),
+        expires: None,
+        identity,
+    };
+    bind_tunnels(state, websocket, handshake, &credential, tunnels, reserve).await
+}
+
+/// Check the client's credential may use the requested sub-domain, and that nobody
+/// else reserved it. With `reserve`, the sub-domain is reserved for the client.
+async fn auth_sub_domain(
+    state: &ServerState,
+    mut websocket: WebSocket,
+    capabilities: &Capabilities,
+    credential: &Credential,
+    identity: &Identity,
+    requested_sub_domain: String,
+    reserve: bool,
+) -> Option<(WebSocket, String)> {
     tracing::info!(requested_sub_domain=%requested_sub_domain, "will auth sub domain");
 
     // next authenticate the sub-domain
-    let sub_domain = match crate::AUTH_DB_SERVICE
-        .read()
-        .await
-        .auth_sub_domain(&auth_key.0, &requested_sub_domain)
+    let auth_result = match credential
+        .auth_sub_domain(state.auth.as_ref(), &requested_sub_domain)
         .await
     {
+        Ok(AuthResult::Available) => {
+            check_reservation(state, identity, &requested_sub_domain, reserve)
+        }
+        result => result.map_err(ReservationError::Auth),
+    };
+
+    let sub_domain = match auth_result {
         Ok(AuthResult::Available) | Ok(AuthResult::ReservedByYou) => requested_sub_domain,
         Ok(AuthResult::ReservedByYouButDelinquent) | Ok(AuthResult::PaymentRequired) => {
             // note: delinquent payments get a random suffix
             // ServerHello::prefixed_random_domain(&requested_sub_domain)
             // TODO: create free trial domain
             tracing::info!(requested_sub_domain=%requested_sub_domain, "payment required");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            reject(
+                &mut websocket,
+                capabilities,
+                ErrorCode::PaymentRequired,
+                format!(
+                    "The sub-domain '{}' requires payment.",
+                    requested_sub_domain
+                ),
+            )
+            .await;
             return None;
         }
         Ok(AuthResult::ReservedByOther) => {
-            let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            reject(
+                &mut websocket,
+                capabilities,
+                ErrorCode::SubDomainInUse,
+                format!("The sub-domain '{}' is reserved.", requested_sub_domain),
+            )
+            .await;
             return None;
         }
-        Err(error) => {
+        Err(ReservationError::Auth(error)) => {
             error!(?error, "error auth-ing user");
-            tracing::error!(?error, "error auth-ing user");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            reject(
+                &mut websocket,
+                capabilities,
+                error.code(),
+                error.to_string(),
+            )
+            .await;
+            return None;
+        }
+        Err(ReservationError::Failed(detail)) => {
+            reject(
+                &mut websocket,
+                capabilities,
+                ErrorCode::ReservationFailed,
+                detail,
+            )
+            .await;
             return None;
         }
     };
 
     tracing::info!(subdomain=%sub_domain, "did auth sub_domain");
 
-    Some((
-        websocket,
-        ClientHandshake {
-            id: client_id,
-            sub_domain,
-            is_anonymous: false,
-        },
-    ))
+    Some((websocket, sub_domain))
+}
+
+enum ReservationError {
+    Auth(AuthError),
+    /// the sub-domain is free but could not be reserved, with the reason
+    Failed(String),
+}
+
+/// Whether an available sub-domain is reserved, by whom, and reserve it if asked to
+fn check_reservation(
+    state: &ServerState,
+    identity: &Identity,
+    sub_domain: &str,
+    reserve: bool,
+) -> Result<AuthResult, ReservationError> {
+    let reservations = match &state.reservations {
+        Some(reservations) => reservations,
+        None => return Ok(AuthResult::Available),
+    };
+
+    let owner = reservations.owner(sub_domain);
+    if owner.is_none() && !reserve {
+        return Ok(AuthResult::Available);
+    }
+
+    let subject = &identity.subject;
+    let owner = match (owner, subject) {
+        (Some(owner), _) => owner,
+        (None, None) => {
+            return Err(ReservationError::Failed(
+                "Your credentials don't identify you, so they can't reserve sub-domains."
+                    .to_string(),
+            ))
+        }
+        // someone may have reserved it since we looked, then it stays theirs
+        (None, Some(subject)) => reservations.reserve(sub_domain, subject).map_err(|error| {
+            error!(?error, %sub_domain, "failed to reserve sub-domain");
+            ReservationError::Failed(format!(
+                "The sub-domain '{}' could not be reserved.",
+                sub_domain
+            ))
+        })?,
+    };
+
+    match subject.as_ref() == Some(&owner) {
+        true => Ok(AuthResult::ReservedByYou),
+        false => Ok(AuthResult::ReservedByOther),
+    }
+}
+
+/// Authenticate the additional tunnels requested along with the primary one
+async fn bind_tunnels(
+    state: &ServerState,
+    mut websocket: WebSocket,
+    mut handshake: ClientHandshake,
+    credential: &Credential,
+    tunnels: Vec<TunnelBinding>,
+    reserve: bool,
+) -> Option<(WebSocket, ClientHandshake)> {
+    if tunnels.is_empty() {
+        return Some((websocket, handshake));
+    }
+
+    let capabilities = handshake.capabilities.clone();
+    let error = if !capabilities.has(Capability::MultipleTunnels)
+        || !capabilities.has(Capability::StreamMetadata)
+    {
+        Some("Multiple tunnels are not supported by this server.".to_string())
+    } else if handshake.tunnel_type != TunnelType::Http {
+        Some("Additional tunnels can only be bound to http tunnels.".to_string())
+    } else if tunnels.len() > MAX_TUNNEL_BINDINGS {
+        Some(format!(
+            "Too many tunnels requested, at most {} may be added.",
+            MAX_TUNNEL_BINDINGS
+        ))
+    } else {
+        None
+    };
+
+    if let Some(error) = error {
+        error!(%error, "rejecting tunnel bindings");
+        reject(
+            &mut websocket,
+            &capabilities,
+            ErrorCode::InvalidTunnels,
+            error,
+        )
+        .await;
+        return None;
+    }
+
+    for binding in tunnels {
+        if binding.name.is_empty() || handshake.bindings.values().any(|n| n == &binding.name) {
+            error!(name=%binding.name, "invalid or duplicate tunnel binding name");
+            reject(
+                &mut websocket,
+                &capabilities,
+                ErrorCode::InvalidTunnels,
+                format!("Invalid or duplicate tunnel name: '{}'.", binding.name),
+            )
+            .await;
+            return None;
+        }
+
+        let (ws, sub_domain) = match binding.sub_domain {
+            Some(requested_sub_domain) => {
+                let (ws, sub_domain) = sanitize_sub_domain_and_pre_validate(
+                    state,
+                    websocket,
+                    &capabilities,
+                    requested_sub_domain,
+                    &handshake.id,
+                )
+                .await?;
+                auth_sub_domain(
+                    state,
+                    ws,
+                    &capabilities,
+                    credential,
+                    &handshake.identity,
+                    sub_domain,
+                    reserve,
+                )
+                .await?
+            }
+            None => (websocket, ServerHello::random_domain()),
+        };
+        websocket = ws;
+
+        if sub_domain == handshake.sub_domain || handshake.bindings.contains_key(&sub_domain) {
+            error!(%sub_domain, "sub-domain requested twice");
+            reject(
+                &mut websocket,
+                &capabilities,
+                ErrorCode::SubDomainInUse,
+                format!("The sub-domain '{}' was requested twice.", sub_domain),
+            )
+            .await;
+            return None;
+        }
+
+        tracing::info!(name=%binding.name, %sub_domain, "bound additional tunnel");
+        handshake.bindings.insert(sub_domain, binding.name);
+    }
+
+    Some((websocket, handshake))
+}
+
+/// Whether the admins denied the subject or the credential of `identity`
+fn is_denied(state: &ServerState, identity: &Identity) -> bool {
+    state
+        .denylist
+        .as_ref()
+        .is_some_and(|denylist| denylist.is_denied(identity))
+}
+
+/// Check a fresh token sent by a connected client, which keeps the client's
+/// tunnels open until the new token expires. The token must belong to the same
+/// subject, grant every sub-domain of the client and outlast the token in use.
+pub async fn reauthenticate(
+    state: &ServerState,
+    client: &ConnectedClient,
+    key: SecretKey,
+) -> Result<Identity, AuthError> {
+    let credential = Credential::Token(key);
+    let identity = credential.identity(state.auth.as_ref()).await;
+    if identity.subject != client.identity.lock().unwrap().subject {
+        return Err(AuthError::OtherSubject);
+    }
+    if is_denied(state, &identity) {
+        return Err(AuthError::Revoked);
+    }
+
+    let hosts = std::iter::once(&client.host).chain(client.bindings.keys());
+    for host in hosts {
+        match credential
+            .auth_sub_domain(state.auth.as_ref(), host)
+            .await?
+        {
+            AuthResult::Available | AuthResult::ReservedByYou => {}
+            _ => return Err(AuthError::SubDomainNotAllowed),
+        }
+    }
+
+    // a token checked after this one may have been applied meanwhile
+    let mut current = client.identity.lock().unwrap();
+    if !identity.outlasts(&current) {
+        return Err(AuthError::Outdated);
+    }
+    *current = identity.clone();
+    Ok(identity)
+}
+
+/// Pick the protocol version and capabilities to use with this client,
+/// or `None` if the client is too old to be served.
+fn negotiate_protocol(config: &Config, client_hello: &ClientHello) -> Option<(u32, Capabilities)> {
+    if client_hello.protocol_version < config.min_protocol_version {
+        return None;
+    }
+
+    let protocol_version = client_hello.protocol_version.min(PROTOCOL_VERSION);
+    let mut supported = Capabilities::supported();
+    if config.tcp_port_range.is_none() {
+        supported = supported.without(Capability::TcpTunnel);
+    }
+    if config.udp_port_range.is_none() {
+        supported = supported.without(Capability::UdpTunnel);
+    }
+    if config.reservations_file.is_none() {
+        supported = supported.without(Capability::Reservations);
+    }
+    let capabilities = supported.negotiate(&client_hello.capabilities);
+
+    tracing::debug!(%protocol_version, %capabilities, "negotiated protocol");
+    Some((protocol_version, capabilities))
 }
 
-#[tracing::instrument(skip(token, websocket))]
+/// Check the reconnect token of an anonymous client
+#[tracing::instrument(skip(config, token, websocket, capabilities))]
 async fn handle_reconnect_token(
+    config: &Config,
     token: ReconnectToken,
     mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
-    let payload = match ReconnectTokenPayload::verify(token, &CONFIG.master_sig_key) {
+    capabilities: &Capabilities,
+) -> Option<(WebSocket, ReconnectTokenPayload)> {
+    let payload = match ReconnectTokenPayload::verify(token, &config.sig_keys) {
         Ok(payload) => payload,
         Err(error) => {
             error!(?error, "invalid reconnect token");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            reject(
+                &mut websocket,
+                capabilities,
+                ErrorCode::ReconnectTokenInvalid,
+                "The reconnect token is invalid.",
+            )
+            .await;
             return None;
         }
     };
@@ -186,18 +681,13 @@ async fn handle_reconnect_token(
         "accepting reconnect token from client",
     );
 
-    Some((
-        websocket,
-        ClientHandshake {
-            id: payload.client_id,
-            sub_domain: payload.sub_domain,
-            is_anonymous: true,
-        },
-    ))
+    Some((websocket, payload))
 }
 
 async fn sanitize_sub_domain_and_pre_validate(
+    state: &ServerState,
     mut websocket: WebSocket,
+    capabilities: &Capabilities,
     requested_sub_domain: String,
     client_id: &ClientId,
 ) -> Option<(WebSocket, String)> {
@@ -211,28 +701,43 @@ async fn sanitize_sub_domain_and_pre_validate(
         > 0
     {
         error!("invalid client hello: only alphanumeric/hyphen chars allowed!");
-        let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
+        reject(
+            &mut websocket,
+            capabilities,
+            ErrorCode::SubDomainInvalid,
+            "Sub-domains may only contain alphanumeric characters and hyphens.",
+        )
+        .await;
         return None;
     }
 
     // ensure it's not a restricted one
-    if CONFIG.blocked_sub_domains.contains(&sub_domain) {
+    if state.config.blocked_sub_domains.contains(&sub_domain) {
         error!("invalid client hello: sub-domain restrict!");
-        let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
+        reject(
+            &mut websocket,
+            capabilities,
+            ErrorCode::SubDomainBlocked,
+            format!("The sub-domain '{}' is not available.", sub_domain),
+        )
+        .await;
         return None;
     }
 
     // ensure this sub-domain isn't taken
     // check all instances
-    match crate::network::instance_for_host(&sub_domain).await {
+    match crate::network::instance_for_host(&state.config, &sub_domain).await {
         Err(crate::network::Error::DoesNotServeHost) => {}
         Ok((_, existing_client)) => {
             if &existing_client != client_id {
                 error!("invalid client hello: requested sub domain in use already!");
-                let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                reject(
+                    &mut websocket,
+                    capabilities,
+                    ErrorCode::SubDomainInUse,
+                    format!("The sub-domain '{}' is already in use.", sub_domain),
+                )
+                .await;
                 return None;
             }
         }
@@ -243,3 +748,174 @@ async fn sanitize_sub_domain_and_pre_validate(
 
     Some((websocket, sub_domain))
 }
+
+/// Tell the client why its handshake was refused. Clients without structured
+/// errors get the closest legacy hello instead.
+pub async fn reject(
+    websocket: &mut WebSocket,
+    capabilities: &Capabilities,
+    code: ErrorCode,
+    detail: impl Into<String>,
+) {
+    let detail = detail.into();
+    let server_hello = if capabilities.has(Capability::StructuredErrors) {
+        ServerHello::Rejected { code, detail }
+    } else {
+        match code {
+            ErrorCode::SubDomainInUse | ErrorCode::SubDomainBlocked => ServerHello::SubDomainInUse,
+            ErrorCode::SubDomainInvalid => ServerHello::InvalidSubDomain,
+            ErrorCode::TunnelTypeDisabled
+            | ErrorCode::NoPortsAvailable
+            | ErrorCode::InvalidTunnels
+            | ErrorCode::ReservationFailed
+            | ErrorCode::TunnelExpired
+            | ErrorCode::TooManyTunnels => ServerHello::Error(detail),
+            _ => ServerHello::AuthFailed,
+        }
+    };
+
+    let data = serde_json::to_vec(&server_hello).unwrap_or_default();
+    let _ = websocket.send(Message::binary(data)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::json_file::tests::temporary_file;
+    use crate::reservations::Reservations;
+
+    fn hello(protocol_version: u32, capabilities: Capabilities) -> ClientHello {
+        let mut hello = ClientHello::generate(None, ClientType::Anonymous);
+        hello.protocol_version = protocol_version;
+        hello.capabilities = capabilities;
+        hello
+    }
+
+    #[test]
+    fn rejects_clients_below_the_minimum_version() {
+        let config = Config {
+            min_protocol_version: 1,
+            ..Config::default()
+        };
+        assert!(negotiate_protocol(&config, &hello(0, Capabilities::default())).is_none());
+        assert!(negotiate_protocol(&config, &hello(1, Capabilities::default())).is_some());
+    }
+
+    #[test]
+    fn speaks_the_lower_version() {
+        let config = Config::default();
+        let (version, _) = negotiate_protocol(&config, &hello(0, Capabilities::default())).unwrap();
+        assert_eq!(version, 0);
+        let (version, _) = negotiate_protocol(
+            &config,
+            &hello(PROTOCOL_VERSION + 1, Capabilities::default()),
+        )
+        .unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn legacy_clients_get_no_capabilities() {
+        let (_, capabilities) =
+            negotiate_protocol(&Config::default(), &hello(0, Capabilities::default())).unwrap();
+        assert!(!capabilities.has(Capability::FlowControl));
+        assert!(!capabilities.has(Capability::Reauthentication));
+    }
+
+    #[test]
+    fn uses_capabilities_both_sides_support() {
+        let offered = Capabilities::supported().without(Capability::HalfClose);
+        let (_, capabilities) =
+            negotiate_protocol(&Config::default(), &hello(PROTOCOL_VERSION, offered)).unwrap();
+        assert!(capabilities.has(Capability::FlowControl));
+        assert!(capabilities.has(Capability::MultipleTunnels));
+        assert!(!capabilities.has(Capability::HalfClose));
+    }
+
+    #[test]
+    fn withholds_capabilities_the_server_is_not_configured_for() {
+        let offered = || hello(PROTOCOL_VERSION, Capabilities::supported());
+
+        let (_, capabilities) = negotiate_protocol(&Config::default(), &offered()).unwrap();
+        assert!(!capabilities.has(Capability::TcpTunnel));
+        assert!(!capabilities.has(Capability::UdpTunnel));
+        assert!(!capabilities.has(Capability::Reservations));
+
+        let config = Config {
+            tcp_port_range: Some(20000..=20100),
+            udp_port_range: Some(30000..=30100),
+            reservations_file: Some("reservations.json".into()),
+            ..Config::default()
+        };
+        let (_, capabilities) = negotiate_protocol(&config, &offered()).unwrap();
+        assert!(capabilities.has(Capability::TcpTunnel));
+        assert!(capabilities.has(Capability::UdpTunnel));
+        assert!(capabilities.has(Capability::Reservations));
+    }
+
+    fn identity(subject: Option<&str>) -> Identity {
+        Identity {
+            subject: subject.map(str::to_owned),
+            ..Identity::default()
+        }
+    }
+
+    /// A server keeping reservations in a fresh file, along with its path
+    fn reserving_state() -> (ServerState, std::path::PathBuf) {
+        let path = temporary_file();
+        let mut state = ServerState::for_tests(Config::default());
+        state.reservations = Some(Reservations::load(&path).unwrap());
+        (state, path)
+    }
+
+    #[test]
+    fn first_claim_reserves() {
+        let (state, path) = reserving_state();
+        let alice = identity(Some("alice"));
+
+        let result = check_reservation(&state, &alice, "alice-api", true);
+        assert!(matches!(result, Ok(AuthResult::ReservedByYou)));
+        let result = check_reservation(&state, &alice, "alice-api", false);
+        assert!(matches!(result, Ok(AuthResult::ReservedByYou)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn others_find_the_sub_domain_reserved() {
+        let (state, path) = reserving_state();
+        check_reservation(&state, &identity(Some("alice")), "alice-api", true).ok();
+
+        let bob = identity(Some("bob"));
+        let result = check_reservation(&state, &bob, "alice-api", false);
+        assert!(matches!(result, Ok(AuthResult::ReservedByOther)));
+        let result = check_reservation(&state, &bob, "alice-api", true);
+        assert!(matches!(result, Ok(AuthResult::ReservedByOther)));
+        let result = check_reservation(&state, &identity(None), "alice-api", false);
+        assert!(matches!(result, Ok(AuthResult::ReservedByOther)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn credentials_without_subject_cannot_reserve() {
+        let (state, path) = reserving_state();
+
+        let result = check_reservation(&state, &identity(None), "anon-api", true);
+        assert!(matches!(result, Err(ReservationError::Failed(_))));
+        assert_eq!(state.reservations.as_ref().unwrap().owner("anon-api"), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unreserved_sub_domains_stay_available() {
+        let (state, path) = reserving_state();
+
+        let result = check_reservation(&state, &identity(Some("alice")), "free-api", false);
+        assert!(matches!(result, Ok(AuthResult::Available)));
+        assert_eq!(state.reservations.as_ref().unwrap().owner("free-api"), None);
+        assert!(!path.exists());
+
+        let state = ServerState::for_tests(Config::default());
+        let result = check_reservation(&state, &identity(Some("alice")), "free-api", true);
+        assert!(matches!(result, Ok(AuthResult::Available)));
+    }
+}
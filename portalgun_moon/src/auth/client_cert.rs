@@ -0,0 +1,360 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Clients authenticated by the certificate they opened the control connection with.
+//!
+//! The control server verifies certificates against the configured client CA.
+//! Sub-domain patterns are granted to the names of a certificate, its common
+//! name and its DNS and email subject alternative names, i.e.
+//! `{"agent-01.ci.example.com": ["^ci-"]}`. Clients are known by the first of
+//! these names, prefixed by its kind, i.e. `cert:cn:agent-01.ci.example.com`.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use portalgun_lib::{ClientId, SecretKey};
+use simple_asn1::{oid, ASN1Block, ASN1Class, BigUint};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{match_sub_domain, AuthError, AuthResult, AuthService, Identity, LoginMethod};
+
+/// The names of a verified client certificate
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+    pub emails: Vec<String>,
+    /// hex encoded SHA-256 of the certificate
+    pub fingerprint: String,
+    /// when the certificate stops being valid
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+impl ClientCertificate {
+    /// Read the names of a DER encoded certificate, `None` if it is malformed
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let blocks = simple_asn1::from_der(der).ok()?;
+        let certificate = match blocks.as_slice() {
+            [ASN1Block::Sequence(_, certificate)] => certificate,
+            _ => return None,
+        };
+        let tbs_certificate = match certificate.first()? {
+            ASN1Block::Sequence(_, tbs_certificate) => tbs_certificate,
+            _ => return None,
+        };
+
+        // the version is optional, then come the serial number, signature
+        // algorithm, issuer and validity before the subject
+        let fields = match tbs_certificate.first()? {
+            ASN1Block::Explicit(ASN1Class::ContextSpecific, _, tag, _) if tag == &tag_number(0) => {
+                &tbs_certificate[1..]
+            }
+            _ => &tbs_certificate[..],
+        };
+        let (validity, subject) = match (fields.get(3)?, fields.get(4)?) {
+            (ASN1Block::Sequence(_, validity), ASN1Block::Sequence(_, subject)) => {
+                (validity, subject)
+            }
+            _ => return None,
+        };
+
+        let common_name = subject
+            .iter()
+            .filter_map(|rdn| match rdn {
+                ASN1Block::Set(_, attributes) => Some(attributes),
+                _ => None,
+            })
+            .flatten()
+            .find_map(|attribute| match attribute {
+                ASN1Block::Sequence(_, parts) => match parts.as_slice() {
+                    [ASN1Block::ObjectIdentifier(_, oid), value] if oid == oid!(2, 5, 4, 3) => {
+                        text(value)
+                    }
+                    _ => None,
+                },
+                _ => None,
+            });
+
+        let mut certificate = ClientCertificate {
+            common_name,
+            dns_names: vec![],
+            emails: vec![],
+            fingerprint: hex::encode(hmac_sha256::Hash::hash(der)),
+            not_after: validity.get(1).and_then(time),
+        };
+
+        // skip the public key and unique ids to the extensions
+        let extensions = fields.iter().find_map(|field| match field {
+            ASN1Block::Explicit(ASN1Class::ContextSpecific, _, tag, extensions)
+                if tag == &tag_number(3) =>
+            {
+                match extensions.as_ref() {
+                    ASN1Block::Sequence(_, extensions) => Some(extensions),
+                    _ => None,
+                }
+            }
+            _ => None,
+        });
+        for extension in extensions.into_iter().flatten() {
+            let parts = match extension {
+                ASN1Block::Sequence(_, parts) => parts,
+                _ => return None,
+            };
+            match parts.first()? {
+                ASN1Block::ObjectIdentifier(_, oid) if oid == oid!(2, 5, 29, 17) => {}
+                _ => continue,
+            }
+
+            // the value follows an optional critical flag
+            let value = parts.iter().find_map(|part| match part {
+                ASN1Block::OctetString(_, value) => Some(value),
+                _ => None,
+            })?;
+            let names = match simple_asn1::from_der(value).ok()?.pop()? {
+                ASN1Block::Sequence(_, names) => names,
+                _ => return None,
+            };
+            for name in names {
+                // rfc822Name and dNSName are implicitly tagged IA5Strings
+                if let ASN1Block::Unknown(ASN1Class::ContextSpecific, false, _, tag, value) = name {
+                    let Ok(value) = String::from_utf8(value) else {
+                        continue;
+                    };
+                    if tag == tag_number(1) {
+                        certificate.emails.push(value);
+                    } else if tag == tag_number(2) {
+                        certificate.dns_names.push(value);
+                    }
+                }
+            }
+        }
+
+        Some(certificate)
+    }
+
+    /// All names of the certificate
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.common_name
+            .iter()
+            .chain(&self.dns_names)
+            .chain(&self.emails)
+    }
+
+    /// Who the certificate identifies, i.e. the owner of the sub-domains it reserves. Its first
+    /// name, prefixed by its kind so certificates can't pass for users of the identity provider.
+    pub fn subject(&self) -> Option<String> {
+        let common_name = self.common_name.iter().map(|name| (NAME_PREFIX_CN, name));
+        let dns_names = self.dns_names.iter().map(|name| (NAME_PREFIX_DNS, name));
+        let emails = self.emails.iter().map(|name| (NAME_PREFIX_EMAIL, name));
+        common_name
+            .chain(dns_names)
+            .chain(emails)
+            .next()
+            .map(|(prefix, name)| format!("{}{}", prefix, name))
+    }
+
+    /// Stable id of clients using this certificate, like the ids of token clients
+    pub fn client_id(&self) -> ClientId {
+        SecretKey(self.fingerprint.clone()).client_id()
+    }
+
+    /// The identity of clients using this certificate, the fingerprint identifies the credential
+    pub fn identity(&self) -> Identity {
+        Identity {
+            subject: self.subject(),
+            token_id: Some(self.fingerprint.clone()),
+            expires: self.not_after,
+        }
+    }
+}
+
+/// Prefixes of certificate subjects, by the kind of name they come from
+const NAME_PREFIX_CN: &str = "cert:cn:";
+const NAME_PREFIX_DNS: &str = "cert:dns:";
+const NAME_PREFIX_EMAIL: &str = "cert:email:";
+
+fn tag_number(tag: u8) -> BigUint {
+    BigUint::from(tag)
+}
+
+/// The value of a directory string, whichever string type it is encoded as
+fn text(block: &ASN1Block) -> Option<String> {
+    match block {
+        ASN1Block::UTF8String(_, text)
+        | ASN1Block::PrintableString(_, text)
+        | ASN1Block::TeletexString(_, text)
+        | ASN1Block::IA5String(_, text)
+        | ASN1Block::UniversalString(_, text)
+        | ASN1Block::BMPString(_, text) => Some(text.clone()),
+        _ => None,
+    }
+}
+
+/// A UTCTime or GeneralizedTime, as used for the validity of certificates
+fn time(block: &ASN1Block) -> Option<DateTime<Utc>> {
+    match block {
+        ASN1Block::UTCTime(_, time) | ASN1Block::GeneralizedTime(_, time) => {
+            DateTime::from_timestamp(time.assume_utc().unix_timestamp(), 0)
+        }
+        _ => None,
+    }
+}
+
+/// Accepts client certificates, and hands every other credential to another auth service
+pub struct ClientCertAuth {
+    /// sub-domain patterns by certificate name
+    sub_domains: HashMap<String, Vec<String>>,
+    inner: Arc<dyn AuthService>,
+}
+
+impl ClientCertAuth {
+    pub fn new(sub_domains: HashMap<String, Vec<String>>, inner: Arc<dyn AuthService>) -> Self {
+        Self { sub_domains, inner }
+    }
+}
+
+#[async_trait]
+impl AuthService for ClientCertAuth {
+    /// Authenticate a subdomain with an AuthKey
+    async fn auth_sub_domain(
+        &self,
+        auth_key: &str,
+        subdomain: &str,
+    ) -> Result<AuthResult, AuthError> {
+        self.inner.auth_sub_domain(auth_key, subdomain).await
+    }
+
+    async fn auth_certificate(
+        &self,
+        certificate: &ClientCertificate,
+        subdomain: &str,
+    ) -> Result<AuthResult, AuthError> {
+        let patterns: Vec<_> = certificate
+            .names()
+            .filter_map(|name| self.sub_domains.get(name))
+            .flatten()
+            .cloned()
+            .collect();
+        if patterns.is_empty() {
+            return Err(AuthError::CertificateNotAllowed);
+        }
+
+        match_sub_domain(&patterns, subdomain)
+    }
+
+    async fn identity(&self, auth_key: &str) -> Identity {
+        self.inner.identity(auth_key).await
+    }
+
+    fn login_method(&self) -> LoginMethod {
+        self.inner.login_method()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    /// CN agent-01.ci.example.com, DNS and email alternative names, until 2034 (UTCTime)
+    const AGENT_CERT: &str = concat!(
+        "MIIB/TCCAaOgAwIBAgIUZd+1Usypo3u56aPNtn0jYTqM00AwCgYIKoZIzj0EAwIwNDEQMA4GA1UECgwHRXhhbXBs",
+        "ZTEgMB4GA1UEAwwXYWdlbnQtMDEuY2kuZXhhbXBsZS5jb20wHhcNMjQwMTAxMDAwMDAwWhcNMzQwMTAxMDAwMDAw",
+        "WjA0MRAwDgYDVQQKDAdFeGFtcGxlMSAwHgYDVQQDDBdhZ2VudC0wMS5jaS5leGFtcGxlLmNvbTBZMBMGByqGSM49",
+        "AgEGCCqGSM49AwEHA0IABFlUQb14WL3yRqDSxAOd31PaNRKBtOFFBAvkhNkHTcTfY6W6fw6EJ9U+4MXuY65YITHF",
+        "wL3NSvaFpsJ92T0DjPajgZIwgY8wHQYDVR0OBBYEFGD9Sm3wys6MDWXVp3KPnOaJ2fi7MB8GA1UdIwQYMBaAFGD9",
+        "Sm3wys6MDWXVp3KPnOaJ2fi7MA8GA1UdEwEB/wQFMAMBAf8wPAYDVR0RBDUwM4IXYWdlbnQtMDEuY2kuZXhhbXBs",
+        "ZS5jb22CCGFnZW50LTAxgQ5jaUBleGFtcGxlLmNvbTAKBggqhkjOPQQDAgNIADBFAiEAyuvUulO+13WC+1BPSvE0",
+        "J2FdmBSCiyzQNVKCz6Ul008CIE7fHt8waKew7+pQKQxmvmvrT5dzMrM+pcS97Uc6u6RP",
+    );
+
+    /// No CN, only an email alternative name and a critical extension, until 2050 (GeneralizedTime)
+    const BUILD_CERT: &str = concat!(
+        "MIIBljCCATygAwIBAgIUfj+KUMfhgrYT4EkTzR9tewEgCK0wCgYIKoZIzj0EAwIwEjEQMA4GA1UECgwHRXhhbXBs",
+        "ZTAgFw0yNDAxMDEwMDAwMDBaGA8yMDUwMDEwMTAwMDAwMFowEjEQMA4GA1UECgwHRXhhbXBsZTBZMBMGByqGSM49",
+        "AgEGCCqGSM49AwEHA0IABMpTgAY3rKgxV4BXzMxvbssimExWrF6KD5Asi/l/Ba2IZaTUmd35K3lpBH9gHleBpvQZ",
+        "Nmd3TMROsJr8JjK0MFijbjBsMB0GA1UdDgQWBBQRjdLkCsX7tLDM5c07AN/jYsMQcjAfBgNVHSMEGDAWgBQRjdLk",
+        "CsX7tLDM5c07AN/jYsMQcjAcBgNVHREEFTATgRFidWlsZEBleGFtcGxlLmNvbTAMBgNVHRMBAf8EAjAAMAoGCCqG",
+        "SM49BAMCA0gAMEUCIFU27ldPNP61QdK6Dx2x4cklgijYmL/THbXRVH+9HRr7AiEA9clfjc7SekYVvPRaAQguHzKx",
+        "FdJSVXjvfRxwo4f+xEw=",
+    );
+
+    fn certificate(base64: &str) -> ClientCertificate {
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(base64)
+            .unwrap();
+        ClientCertificate::from_der(&der).unwrap()
+    }
+
+    fn utc(year: i32) -> DateTime<Utc> {
+        chrono::NaiveDate::from_ymd_opt(year, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn reads_names() {
+        let certificate = certificate(AGENT_CERT);
+        assert_eq!(
+            certificate.common_name.as_deref(),
+            Some("agent-01.ci.example.com")
+        );
+        assert_eq!(
+            certificate.dns_names,
+            ["agent-01.ci.example.com", "agent-01"]
+        );
+        assert_eq!(certificate.emails, ["ci@example.com"]);
+        assert_eq!(certificate.names().count(), 4);
+    }
+
+    #[test]
+    fn reads_validity() {
+        assert_eq!(certificate(AGENT_CERT).not_after, Some(utc(2034)));
+        assert_eq!(certificate(BUILD_CERT).not_after, Some(utc(2050)));
+    }
+
+    #[test]
+    fn prefixes_subjects_by_name_kind() {
+        assert_eq!(
+            certificate(AGENT_CERT).subject().as_deref(),
+            Some("cert:cn:agent-01.ci.example.com")
+        );
+        assert_eq!(
+            certificate(BUILD_CERT).subject().as_deref(),
+            Some("cert:email:build@example.com")
+        );
+
+        let dns_only = ClientCertificate {
+            common_name: None,
+            emails: vec![],
+            ..certificate(AGENT_CERT)
+        };
+        assert_eq!(
+            dns_only.subject().as_deref(),
+            Some("cert:dns:agent-01.ci.example.com")
+        );
+    }
+
+    #[test]
+    fn identifies_certificates_by_fingerprint() {
+        let (agent, build) = (certificate(AGENT_CERT), certificate(BUILD_CERT));
+        assert_eq!(agent.fingerprint.len(), 64);
+        assert_ne!(agent.fingerprint, build.fingerprint);
+        assert_eq!(agent.client_id(), certificate(AGENT_CERT).client_id());
+        assert_eq!(agent.identity().token_id, Some(agent.fingerprint.clone()));
+    }
+
+    #[test]
+    fn rejects_malformed_certificates() {
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(AGENT_CERT)
+            .unwrap();
+        assert!(ClientCertificate::from_der(&der[..der.len() - 1]).is_none());
+        assert!(ClientCertificate::from_der(&der[4..]).is_none());
+        assert!(ClientCertificate::from_der(&[]).is_none());
+        assert!(ClientCertificate::from_der(&[0x30, 0x00]).is_none());
+    }
+}
@@ -0,0 +1,247 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Subjects and credentials denied by the admins, i.e. of a user who left.
+//!
+//! The denylist is a JSON file managed with `portalgun_moon denylist`:
+//!
+//! ```json
+//! { "subjects": ["2f9a6a3e-5d8c-4f6e-9c55-0f1d7c3b1a20"], "token_ids": ["a1b2c3d4"] }
+//! ```
+//!
+//! Token ids are the `jti` of access tokens, the id of API keys or the
+//! fingerprint of client certificates. Running servers pick up changes to the
+//! file within seconds, and disconnect the clients it matches.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use super::json_file::{self, modified};
+use super::Identity;
+use crate::ServerState;
+
+/// How often running servers look for changes to the denylist
+const DENYLIST_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the denylist denies
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DenylistEntries {
+    #[serde(default)]
+    pub subjects: BTreeSet<String>,
+    #[serde(default)]
+    pub token_ids: BTreeSet<String>,
+}
+
+impl DenylistEntries {
+    /// Whether the subject or the credential of `identity` is denied
+    pub fn denies(&self, identity: &Identity) -> bool {
+        identity
+            .subject
+            .as_ref()
+            .is_some_and(|subject| self.subjects.contains(subject))
+            || identity
+                .token_id
+                .as_ref()
+                .is_some_and(|token_id| self.token_ids.contains(token_id))
+    }
+}
+
+/// The denylist of a file, reloaded whenever it changes
+#[derive(Debug)]
+pub struct Denylist {
+    path: PathBuf,
+    loaded: RwLock<(DenylistEntries, Option<SystemTime>)>,
+}
+
+impl Denylist {
+    /// Load the denylist from `path`, denying nobody if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let denylist = Self {
+            path: path.to_owned(),
+            loaded: RwLock::new(Default::default()),
+        };
+        denylist.reload()?;
+        Ok(denylist)
+    }
+
+    /// The latest entries of the file
+    pub fn entries(&self) -> DenylistEntries {
+        if let Err(error) = self.reload_if_changed() {
+            tracing::warn!(
+                ?error,
+                "failed to reload the denylist, keeping the one we have"
+            );
+        }
+        self.loaded.read().unwrap().0.clone()
+    }
+
+    /// Whether the subject or the credential of `identity` is denied
+    pub fn is_denied(&self, identity: &Identity) -> bool {
+        self.entries().denies(identity)
+    }
+
+    /// Change the entries on disk, starting from the latest version of the file
+    pub fn update(&self, change: impl FnOnce(&mut DenylistEntries)) -> Result<(), anyhow::Error> {
+        self.reload()?;
+        let mut loaded = self.loaded.write().unwrap();
+        change(&mut loaded.0);
+        json_file::save_atomic(&self.path, &loaded.0)?;
+        loaded.1 = modified(&self.path)?;
+        Ok(())
+    }
+
+    fn reload_if_changed(&self) -> Result<(), anyhow::Error> {
+        let modified = modified(&self.path)?;
+        if modified != self.loaded.read().unwrap().1 {
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<(), anyhow::Error> {
+        let modified = modified(&self.path)?;
+        let entries: DenylistEntries = json_file::load(&self.path)?;
+        tracing::info!(
+            subjects = entries.subjects.len(),
+            token_ids = entries.token_ids.len(),
+            path = %self.path.display(),
+            "loaded denylist"
+        );
+
+        *self.loaded.write().unwrap() = (entries, modified);
+        Ok(())
+    }
+}
+
+/// Disconnect the clients matching the denylist as it changes, until the server stops
+pub(crate) async fn enforce(state: Arc<ServerState>) {
+    let denylist = match &state.denylist {
+        Some(denylist) => denylist,
+        None => return,
+    };
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(DENYLIST_CHECK_INTERVAL) => {}
+            _ = state.stopped() => return,
+        }
+
+        let entries = denylist.entries();
+        for client in state.connections.clients() {
+            let identity = client.identity.lock().unwrap().clone();
+            if entries.denies(&identity) {
+                tracing::info!(subdomain=%client.host, ?identity.subject, "client is denied, disconnecting it");
+                state.remove_client(&client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connected_clients::{ConnectedClient, CLIENT_QUEUE_LEN};
+    use crate::Config;
+    use futures::channel::mpsc::channel;
+    use portalgun_lib::ClientId;
+    use std::sync::Mutex;
+
+    fn identity(subject: &str, token_id: &str) -> Identity {
+        Identity {
+            subject: Some(subject.to_owned()),
+            token_id: Some(token_id.to_owned()),
+            ..Identity::default()
+        }
+    }
+
+    fn entries(subjects: &[&str], token_ids: &[&str]) -> DenylistEntries {
+        DenylistEntries {
+            subjects: subjects.iter().map(|s| s.to_string()).collect(),
+            token_ids: token_ids.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    fn client(host: &str, identity: Identity) -> ConnectedClient {
+        ConnectedClient {
+            id: ClientId::generate(),
+            host: host.to_owned(),
+            is_anonymous: false,
+            protocol_version: 0,
+            capabilities: Default::default(),
+            tunnel_type: Default::default(),
+            bindings: Default::default(),
+            expires: None,
+            identity: Arc::new(Mutex::new(identity)),
+            tx: channel(CLIENT_QUEUE_LEN).0,
+        }
+    }
+
+    #[test]
+    fn denies_subjects() {
+        let entries = entries(&["alice"], &[]);
+        assert!(entries.denies(&identity("alice", "a1b2c3d4")));
+        assert!(!entries.denies(&identity("bob", "a1b2c3d4")));
+    }
+
+    #[test]
+    fn denies_token_ids() {
+        let entries = entries(&[], &["a1b2c3d4"]);
+        assert!(entries.denies(&identity("alice", "a1b2c3d4")));
+        assert!(!entries.denies(&identity("alice", "e5f6a7b8")));
+    }
+
+    #[test]
+    fn denies_nobody_without_identity() {
+        let entries = entries(&["alice"], &["a1b2c3d4"]);
+        assert!(!entries.denies(&Identity::default()));
+    }
+
+    #[test]
+    fn picks_up_updates() {
+        let path = json_file::tests::temporary_file();
+        let denylist = Denylist::load(&path).unwrap();
+        assert!(!denylist.is_denied(&identity("alice", "a1b2c3d4")));
+
+        Denylist::load(&path)
+            .unwrap()
+            .update(|entries| {
+                entries.subjects.insert("alice".into());
+            })
+            .unwrap();
+        assert!(denylist.is_denied(&identity("alice", "a1b2c3d4")));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_denied_clients() {
+        let path = json_file::tests::temporary_file();
+        let denylist = Denylist::load(&path).unwrap();
+        denylist
+            .update(|entries| {
+                entries.subjects.insert("alice".into());
+            })
+            .unwrap();
+
+        let mut state = ServerState::for_tests(Config::default());
+        state.denylist = Some(denylist);
+        let state = Arc::new(state);
+        let alice = client("alice-api", identity("alice", "a1b2c3d4"));
+        let bob = client("bob-api", identity("bob", "e5f6a7b8"));
+        state.connections.add(alice.clone());
+        state.connections.add(bob.clone());
+
+        let enforcing = tokio::spawn(enforce(state.clone()));
+        tokio::time::sleep(DENYLIST_CHECK_INTERVAL * 2).await;
+
+        assert!(state.connections.get(&alice.id).is_none());
+        assert!(state.connections.get(&bob.id).is_some());
+
+        let _ = state.stopped.send(true);
+        enforcing.await.unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
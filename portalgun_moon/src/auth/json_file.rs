@@ -0,0 +1,73 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! JSON files kept by the server and edited by its admin commands, i.e. API
+//! keys, the denylist and sub-domain reservations.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Read the file at `path`, the default value if it doesn't exist yet
+pub(crate) fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T, anyhow::Error> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Replace the file at `path` with `value`. It is written to a temporary file
+/// first, so a crash never leaves half of it.
+pub(crate) fn save_atomic<T: Serialize + ?Sized>(
+    path: &Path,
+    value: &T,
+) -> Result<(), anyhow::Error> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// When the file was last changed, `None` if it doesn't exist
+pub(crate) fn modified(path: &Path) -> Result<Option<SystemTime>, anyhow::Error> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.modified()?)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    /// A path in the temporary directory, removed again by the test
+    pub(crate) fn temporary_file() -> PathBuf {
+        std::env::temp_dir().join(format!("portalgun-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn missing_files_are_empty() {
+        let path = temporary_file();
+        let value: HashMap<String, String> = load(&path).unwrap();
+        assert!(value.is_empty());
+        assert_eq!(modified(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn saved_files_load_again() {
+        let path = temporary_file();
+        let value = HashMap::from([("alice-api".to_owned(), "alice".to_owned())]);
+        save_atomic(&path, &value).unwrap();
+
+        assert_eq!(load::<HashMap<String, String>>(&path).unwrap(), value);
+        assert!(modified(&path).unwrap().is_some());
+        assert!(!path.with_extension("tmp").exists());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -4,15 +4,36 @@
 // SPDX-License-Identifier: MIT
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use portalgun_lib::ErrorCode;
 use rand::Rng;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::fmt::Formatter;
+use std::path::PathBuf;
+use std::sync::Arc;
+use url::Url;
 
+pub mod api_keys;
+pub mod auth_http;
 pub mod auth_oidc;
+pub mod auth_token_file;
+pub mod claim_mapping;
 pub mod client_auth;
+pub mod client_cert;
+pub mod denylist;
+pub(crate) mod json_file;
 pub mod reconnect_token;
 
+pub use self::api_keys::{ApiKey, ApiKeyAuth, ApiKeys};
+pub use self::auth_http::HttpAuth;
+pub use self::auth_oidc::{AuthOidcService, IntrospectionClient};
+pub use self::auth_token_file::TokenFileAuth;
+pub use self::claim_mapping::{SubDomainPolicy, SubDomainSource};
+pub use self::client_cert::{ClientCertAuth, ClientCertificate};
+pub use self::denylist::{Denylist, DenylistEntries};
+
 #[derive(Clone)]
 pub struct SigKey([u8; 32]);
 
@@ -31,12 +52,10 @@ impl SigKey {
         SigKey(rand::thread_rng().gen::<[u8; 32]>())
     }
 
-    pub fn from_hex(hex: &str) -> Result<Self, ()> {
-        let bytes = hex::decode(hex)
-            .map_err(|_| ())?
-            .try_into()
-            .map_err(|_| ())?;
-        Ok(SigKey(bytes))
+    /// Parse a hex encoded key, `None` if it is not hex or not 32 bytes long
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let bytes = hex::decode(hex).ok()?.try_into().ok()?;
+        Some(SigKey(bytes))
     }
 
     pub fn sign(&self, data: &[u8]) -> Signature {
@@ -49,23 +68,255 @@ impl SigKey {
             Ok(s) => s,
             Err(_) => return false,
         };
-        let expected = hmac_sha256::HMAC::mac(data, self.0).to_vec();
-        signature == expected
+        let expected = hmac_sha256::HMAC::mac(data, self.0);
+        constant_time_eq(&signature, &expected)
+    }
+
+    /// Identifies the key in the tokens it signs, without revealing it
+    pub fn id(&self) -> String {
+        let id = hmac_sha256::HMAC::mac(b"portalgun key id", self.0);
+        hex::encode(&id[..8])
+    }
+}
+
+/// Compare without exiting early, so the time taken doesn't reveal how much of a signature matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The keys of reconnect tokens: an active key signing new tokens, and previous
+/// keys still accepted, so keys can be rotated without disconnecting clients
+#[derive(Debug, Clone)]
+pub struct SigKeyRing {
+    active: SigKey,
+    previous: Vec<SigKey>,
+}
+
+impl SigKeyRing {
+    pub fn new(active: SigKey) -> Self {
+        SigKeyRing {
+            active,
+            previous: vec![],
+        }
+    }
+
+    /// Also accept tokens signed with `key`
+    pub fn with_previous(mut self, key: SigKey) -> Self {
+        self.previous.push(key);
+        self
+    }
+
+    /// The key signing new tokens
+    pub fn active(&self) -> &SigKey {
+        &self.active
+    }
+
+    /// The key with `id`, whether active or previous
+    pub fn get(&self, id: &str) -> Option<&SigKey> {
+        self.keys().find(|key| key.id() == id)
+    }
+
+    /// All accepted keys, the active one first
+    pub fn keys(&self) -> impl Iterator<Item = &SigKey> {
+        std::iter::once(&self.active).chain(&self.previous)
     }
 }
 
 /// Define the required behavior of an Authentication Service
 #[async_trait]
-pub trait AuthService {
-    type Error;
-    type AuthKey;
-
+pub trait AuthService: Send + Sync {
     /// Authenticate a subdomain with an AuthKey
     async fn auth_sub_domain(
         &self,
-        auth_key: &Self::AuthKey,
+        auth_key: &str,
         subdomain: &str,
-    ) -> Result<AuthResult, Self::Error>;
+    ) -> Result<AuthResult, AuthError>;
+
+    /// Authenticate a subdomain with the verified client certificate of the connection
+    async fn auth_certificate(
+        &self,
+        _certificate: &ClientCertificate,
+        _subdomain: &str,
+    ) -> Result<AuthResult, AuthError> {
+        Err(AuthError::CertificatesNotAccepted)
+    }
+
+    /// Who `auth_key` belongs to and until when it is valid, as far as the service knows
+    async fn identity(&self, _auth_key: &str) -> Identity {
+        Identity::default()
+    }
+
+    /// How clients get credentials for this service
+    fn login_method(&self) -> LoginMethod;
+}
+
+/// Who a credential belongs to, and until when
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// i.e. the OIDC subject. Only credentials with a subject can reserve sub-domains.
+    /// Subjects not coming from the identity provider are prefixed by where they came
+    /// from, i.e. `apikey:ci`, so they can't pass for one of its users.
+    pub subject: Option<String>,
+    /// Identifies the credential itself, i.e. the `jti` of a JWT or the id of an API key
+    pub token_id: Option<String>,
+    /// When the credential stops being valid, tunnels opened with it are closed then
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Identity {
+    /// Whether this credential stays valid for longer than `other`
+    pub fn outlasts(&self, other: &Identity) -> bool {
+        match (self.expires, other.expires) {
+            (None, Some(_)) => true,
+            (Some(expires), Some(other)) => expires > other,
+            (_, None) => false,
+        }
+    }
+}
+
+/// How clients get credentials, sent to them in the `AuthInfo` handshake
+#[derive(Debug, Clone)]
+pub enum LoginMethod {
+    /// Log in with an OpenID Connect provider
+    Oidc {
+        discovery_url: String,
+        client_id: String,
+        scopes: Vec<String>,
+    },
+    /// Use an access token issued out of band
+    Token,
+}
+
+/// Authentication backend to use, see [`from_config`]
+#[derive(Debug, Clone)]
+pub enum AuthBackend {
+    /// Verify OIDC access tokens
+    Oidc {
+        discovery_url: String,
+        client_id: String,
+        scopes: String,
+        /// Which sub-domains the claims of a token grant
+        sub_domains: SubDomainPolicy,
+        /// Accept opaque access tokens too, introspecting them as this client
+        introspection: Option<IntrospectionClient>,
+    },
+    /// Accept any token, for local development
+    NoAuth,
+    /// Accept the tokens listed in a file, see [`TokenFileAuth`]
+    TokenFile(PathBuf),
+    /// Ask an external authorizer, see [`HttpAuth`]
+    Http(Url),
+}
+
+/// Set up the configured authentication backend
+pub async fn from_config(backend: &AuthBackend) -> Result<Arc<dyn AuthService>, anyhow::Error> {
+    Ok(match backend {
+        AuthBackend::Oidc {
+            discovery_url,
+            client_id,
+            scopes,
+            sub_domains,
+            introspection,
+        } => {
+            let mut auth = AuthOidcService::new(discovery_url, client_id, scopes)
+                .with_sub_domains(sub_domains.clone());
+            if let Some(introspection) = introspection {
+                auth = auth.with_introspection(introspection.clone());
+            }
+            auth.init().await?;
+            Arc::new(auth)
+        }
+        AuthBackend::NoAuth => {
+            tracing::warn!("WARNING! authentication is disabled!");
+            Arc::new(NoAuth)
+        }
+        AuthBackend::TokenFile(path) => Arc::new(TokenFileAuth::load(path)?),
+        AuthBackend::Http(url) => Arc::new(HttpAuth::new(url.clone())),
+    })
+}
+
+/// Why a client's credentials were not accepted
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("auth service is not ready")]
+    NotInitialized,
+    #[error("token has no key id")]
+    MissingKeyId,
+    #[error("token signed by unknown key {0}")]
+    UnknownKey(String),
+    #[error("token signed with an unsupported algorithm")]
+    UnsupportedAlgorithm,
+    #[error("token algorithm does not match its signing key")]
+    AlgorithmMismatch,
+    #[error("invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+    #[error("token issued by another issuer")]
+    InvalidIssuer,
+    #[error("token issued for another client")]
+    InvalidAudience,
+    #[error("token authorized for another client")]
+    InvalidAuthorizedParty,
+    #[error("token has expired")]
+    Expired,
+    #[error("token issued in the future")]
+    IssuedInFuture,
+    #[error("token grants no sub-domains")]
+    NoSubDomainClaim,
+    #[error("token has an invalid sub-domain pattern: {0}")]
+    InvalidSubDomainPattern(#[from] regex::Error),
+    #[error("token does not allow this sub-domain")]
+    SubDomainNotAllowed,
+    #[error("client certificates are not accepted")]
+    CertificatesNotAccepted,
+    #[error("certificate grants no sub-domains")]
+    CertificateNotAllowed,
+    #[error("unknown token")]
+    UnknownToken,
+    #[error("authorizer denied the sub-domain: {0}")]
+    Denied(String),
+    #[error("authorizer is unavailable: {0}")]
+    Unavailable(String),
+    #[error("credentials were revoked")]
+    Revoked,
+    #[error("token belongs to someone else")]
+    OtherSubject,
+    #[error("token expires before the one in use")]
+    Outdated,
+}
+
+impl AuthError {
+    /// Error code reported to the client
+    pub fn code(&self) -> ErrorCode {
+        use jsonwebtoken::errors::ErrorKind;
+
+        match self {
+            AuthError::NotInitialized | AuthError::Unavailable(_) => ErrorCode::AuthUnavailable,
+            AuthError::MissingKeyId | AuthError::UnknownKey(_) => ErrorCode::UnknownSigningKey,
+            AuthError::InvalidToken(error)
+                if matches!(error.kind(), ErrorKind::ExpiredSignature) =>
+            {
+                ErrorCode::TokenExpired
+            }
+            AuthError::Expired => ErrorCode::TokenExpired,
+            AuthError::UnsupportedAlgorithm
+            | AuthError::AlgorithmMismatch
+            | AuthError::InvalidToken(_)
+            | AuthError::InvalidIssuer
+            | AuthError::InvalidAudience
+            | AuthError::InvalidAuthorizedParty
+            | AuthError::IssuedInFuture
+            | AuthError::InvalidSubDomainPattern(_)
+            | AuthError::UnknownToken
+            | AuthError::CertificatesNotAccepted
+            | AuthError::OtherSubject
+            | AuthError::Outdated => ErrorCode::TokenInvalid,
+            AuthError::NoSubDomainClaim
+            | AuthError::SubDomainNotAllowed
+            | AuthError::CertificateNotAllowed
+            | AuthError::Denied(_) => ErrorCode::SubDomainNotAllowed,
+            AuthError::Revoked => ErrorCode::TokenRevoked,
+        }
+    }
 }
 
 /// A result for authenticating a subdomain
@@ -77,20 +328,117 @@ pub enum AuthResult {
     Available,
 }
 
+/// Accepts every token for every sub-domain
 #[derive(Debug, Clone, Copy)]
 pub struct NoAuth;
 
 #[async_trait]
 impl AuthService for NoAuth {
-    type Error = ();
-    type AuthKey = String;
-
     /// Authenticate a subdomain with an AuthKey
     async fn auth_sub_domain(
         &self,
-        _auth_key: &Self::AuthKey,
+        _auth_key: &str,
         _subdomain: &str,
-    ) -> Result<AuthResult, Self::Error> {
+    ) -> Result<AuthResult, AuthError> {
         Ok(AuthResult::Available)
     }
+
+    fn login_method(&self) -> LoginMethod {
+        LoginMethod::Token
+    }
+}
+
+/// Check `subdomain` against the allowed sub-domain patterns of a credential
+fn match_sub_domain(patterns: &[String], subdomain: &str) -> Result<AuthResult, AuthError> {
+    for pattern in patterns {
+        if Regex::new(pattern)?.is_match(subdomain) {
+            return Ok(AuthResult::Available);
+        }
+    }
+
+    Err(AuthError::SubDomainNotAllowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expiring(minutes: i64) -> Identity {
+        Identity {
+            expires: Some(Utc::now() + chrono::Duration::minutes(minutes)),
+            ..Identity::default()
+        }
+    }
+
+    #[test]
+    fn later_expiry_outlasts() {
+        let (sooner, later) = (expiring(5), expiring(60));
+        assert!(later.outlasts(&sooner));
+        assert!(!sooner.outlasts(&later));
+        assert!(!later.outlasts(&later));
+    }
+
+    #[test]
+    fn never_expiring_outlasts_expiring() {
+        let never = Identity::default();
+        assert!(never.outlasts(&expiring(60)));
+        assert!(!expiring(60).outlasts(&never));
+        assert!(!never.outlasts(&never));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"signature", b"signature"));
+        assert!(!constant_time_eq(b"signature", b"signaturE"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn constant_time_eq_rejects_different_lengths() {
+        assert!(!constant_time_eq(b"signature", b"signatur"));
+        assert!(!constant_time_eq(b"signatur", b"signature"));
+        assert!(!constant_time_eq(b"", b"signature"));
+    }
+
+    #[test]
+    fn verifies_own_signatures_only() {
+        let (key, other) = (SigKey::generate(), SigKey::generate());
+        let signature = key.sign(b"payload");
+
+        assert!(key.verify(b"payload", &signature));
+        assert!(!key.verify(b"tampered", &signature));
+        assert!(!other.verify(b"payload", &signature));
+    }
+
+    #[test]
+    fn rejects_truncated_signatures() {
+        let key = SigKey::generate();
+        let Signature(signature) = key.sign(b"payload");
+
+        // a whole byte short, and half a byte short, which isn't even hex
+        let truncated = Signature(signature[..signature.len() - 2].to_string());
+        assert!(!key.verify(b"payload", &truncated));
+        let truncated = Signature(signature[..signature.len() - 1].to_string());
+        assert!(!key.verify(b"payload", &truncated));
+        assert!(!key.verify(b"payload", &Signature(String::new())));
+    }
+
+    #[test]
+    fn key_ring_finds_active_and_previous_keys() {
+        let (old, new) = (SigKey::generate(), SigKey::generate());
+        let ring = SigKeyRing::new(new.clone()).with_previous(old.clone());
+
+        assert_eq!(ring.active().id(), new.id());
+        assert_eq!(ring.get(&new.id()).map(SigKey::id), Some(new.id()));
+        assert_eq!(ring.get(&old.id()).map(SigKey::id), Some(old.id()));
+        assert!(ring.get(&SigKey::generate().id()).is_none());
+    }
+
+    #[test]
+    fn key_ids_are_stable_and_distinct() {
+        let key = SigKey::from_hex(&"ab".repeat(32)).unwrap();
+        assert_eq!(key.id(), SigKey::from_hex(&"ab".repeat(32)).unwrap().id());
+        assert_ne!(key.id(), SigKey::generate().id());
+        assert!(SigKey::from_hex(&"ab".repeat(31)).is_none());
+    }
 }
@@ -3,7 +3,7 @@
 //
 // SPDX-License-Identifier: MIT
 
-use crate::auth::{SigKey, Signature};
+use crate::auth::{SigKeyRing, Signature};
 use base64::Engine;
 use chrono::{DateTime, Utc};
 use portalgun_lib::{ClientId, ReconnectToken};
@@ -21,6 +21,9 @@ pub enum Error {
     #[error("invalid reconnect token (signature)")]
     InvalidSignature,
 
+    #[error("reconnect token signed by an unknown key")]
+    UnknownKey,
+
     #[error("reconnect token expired")]
     Expired,
 }
@@ -30,21 +33,40 @@ pub struct ReconnectTokenPayload {
     pub sub_domain: String,
     pub client_id: ClientId,
     pub expires: DateTime<Utc>,
+    /// when the tunnel itself is closed, reconnects or not
+    #[serde(default)]
+    pub tunnel_expires: Option<DateTime<Utc>>,
 }
 impl ReconnectTokenPayload {
-    pub fn into_token(self, key: &SigKey) -> Result<ReconnectToken, Error> {
+    /// Sign the payload with the active key
+    pub fn into_token(self, keys: &SigKeyRing) -> Result<ReconnectToken, Error> {
         let payload = serde_json::to_string(&self)?;
+        let key = keys.active();
         let sig = key.sign(payload.as_bytes());
-        let tok = ReconnectTokenInner { payload, sig };
+        let tok = ReconnectTokenInner {
+            payload,
+            sig,
+            key_id: Some(key.id()),
+        };
         let tok = base64::engine::general_purpose::STANDARD.encode(serde_json::to_vec(&tok)?);
         Ok(ReconnectToken(tok))
     }
 
-    pub fn verify(tok: ReconnectToken, key: &SigKey) -> Result<ReconnectTokenPayload, Error> {
+    /// Verify the token with the key it names, or with any key for tokens from before key ids
+    pub fn verify(tok: ReconnectToken, keys: &SigKeyRing) -> Result<ReconnectTokenPayload, Error> {
         let tok = base64::engine::general_purpose::STANDARD.decode(tok.0.as_str())?;
         let tok: ReconnectTokenInner = serde_json::from_slice(&tok)?;
 
-        if !key.verify(tok.payload.as_bytes(), &tok.sig) {
+        let valid = match &tok.key_id {
+            Some(id) => {
+                let key = keys.get(id).ok_or(Error::UnknownKey)?;
+                key.verify(tok.payload.as_bytes(), &tok.sig)
+            }
+            None => keys
+                .keys()
+                .any(|key| key.verify(tok.payload.as_bytes(), &tok.sig)),
+        };
+        if !valid {
             return Err(Error::InvalidSignature);
         }
 
@@ -62,4 +84,110 @@ impl ReconnectTokenPayload {
 struct ReconnectTokenInner {
     payload: String,
     sig: Signature,
+    /// id of the signing key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::SigKey;
+    use portalgun_lib::SecretKey;
+
+    fn payload() -> ReconnectTokenPayload {
+        ReconnectTokenPayload {
+            sub_domain: "alice".to_string(),
+            client_id: SecretKey::generate().client_id(),
+            expires: Utc::now() + chrono::Duration::minutes(2),
+            tunnel_expires: None,
+        }
+    }
+
+    fn inner(token: &ReconnectToken) -> ReconnectTokenInner {
+        let token = base64::engine::general_purpose::STANDARD
+            .decode(&token.0)
+            .unwrap();
+        serde_json::from_slice(&token).unwrap()
+    }
+
+    fn encode(inner: &ReconnectTokenInner) -> ReconnectToken {
+        ReconnectToken(
+            base64::engine::general_purpose::STANDARD.encode(serde_json::to_vec(inner).unwrap()),
+        )
+    }
+
+    #[test]
+    fn verifies_with_the_previous_key_after_rotation() {
+        let (old, new) = (SigKey::generate(), SigKey::generate());
+        let token = payload().into_token(&SigKeyRing::new(old.clone())).unwrap();
+
+        let rotated = SigKeyRing::new(new).with_previous(old);
+        let verified = ReconnectTokenPayload::verify(token, &rotated).unwrap();
+        assert_eq!(verified.sub_domain, "alice");
+    }
+
+    #[test]
+    fn rejects_tokens_of_retired_keys() {
+        let (old, new) = (SigKey::generate(), SigKey::generate());
+        let token = payload().into_token(&SigKeyRing::new(old)).unwrap();
+
+        let result = ReconnectTokenPayload::verify(token, &SigKeyRing::new(new));
+        assert!(matches!(result, Err(Error::UnknownKey)));
+    }
+
+    #[test]
+    fn rejects_unknown_key_ids() {
+        let keys = SigKeyRing::new(SigKey::generate());
+        let mut token = inner(&payload().into_token(&keys).unwrap());
+        token.key_id = Some("0123456789abcdef".to_string());
+
+        let result = ReconnectTokenPayload::verify(encode(&token), &keys);
+        assert!(matches!(result, Err(Error::UnknownKey)));
+    }
+
+    #[test]
+    fn rejects_truncated_signatures() {
+        let keys = SigKeyRing::new(SigKey::generate());
+        let mut token = inner(&payload().into_token(&keys).unwrap());
+        token.sig.0.truncate(token.sig.0.len() - 2);
+
+        let result = ReconnectTokenPayload::verify(encode(&token), &keys);
+        assert!(matches!(result, Err(Error::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_tampered_payloads() {
+        let keys = SigKeyRing::new(SigKey::generate());
+        let mut token = inner(&payload().into_token(&keys).unwrap());
+        token.payload = token.payload.replace("alice", "mallory");
+
+        let result = ReconnectTokenPayload::verify(encode(&token), &keys);
+        assert!(matches!(result, Err(Error::InvalidSignature)));
+    }
+
+    #[test]
+    fn verifies_tokens_without_key_id_with_any_key() {
+        let (old, new) = (SigKey::generate(), SigKey::generate());
+        let mut token = inner(&payload().into_token(&SigKeyRing::new(old.clone())).unwrap());
+        token.key_id = None;
+
+        let rotated = SigKeyRing::new(new.clone()).with_previous(old);
+        assert!(ReconnectTokenPayload::verify(encode(&token), &rotated).is_ok());
+
+        let result = ReconnectTokenPayload::verify(encode(&token), &SigKeyRing::new(new));
+        assert!(matches!(result, Err(Error::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let keys = SigKeyRing::new(SigKey::generate());
+        let expired = ReconnectTokenPayload {
+            expires: Utc::now() - chrono::Duration::seconds(1),
+            ..payload()
+        };
+
+        let result = ReconnectTokenPayload::verify(expired.into_token(&keys).unwrap(), &keys);
+        assert!(matches!(result, Err(Error::Expired)));
+    }
 }
@@ -3,12 +3,19 @@
 //
 // SPDX-License-Identifier: MIT
 
-use crate::auth::SigKey;
+use crate::auth::{
+    AuthBackend, IntrospectionClient, SigKey, SigKeyRing, SubDomainPolicy, SubDomainSource,
+};
+use ipnet::IpNet;
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 use uuid::Uuid;
 
-/// Global service configuration
+/// Service configuration
 pub struct Config {
     /// What hosts do we allow tunnels on:
     /// i.e:    baz.com => *.baz.com
@@ -28,8 +35,8 @@ pub struct Config {
     /// internal port for instance-to-instance gossip coms
     pub internal_network_port: u16,
 
-    /// our signature key
-    pub master_sig_key: SigKey,
+    /// keys signing the reconnect tokens
+    pub sig_keys: SigKeyRing,
 
     /// Instance DNS discovery domain for gossip protocol
     pub gossip_dns_host: Option<String>,
@@ -40,23 +47,112 @@ pub struct Config {
     /// Blocked IP addresses
     pub blocked_ips: Vec<IpAddr>,
 
+    /// Proxies in front of the control server, i.e. 172.16.0.0/12
+    /// Only their requests may name the client with `Fly-Client-IP` or `X-Forwarded-For`
+    pub trusted_proxies: Vec<IpNet>,
+
     /// The host on which we create tunnels on
     pub tunnel_host: String,
 
-    /// Disable Portalgun custom attribute validation
-    pub disable_attribute_validation: bool,
+    /// How clients are authenticated
+    pub auth_backend: AuthBackend,
+
+    /// Oldest client protocol version we accept, `0` accepts legacy clients
+    pub min_protocol_version: u32,
+
+    /// Public ports handed out to raw TCP tunnels, i.e: 20000-20099
+    /// TCP tunnels are disabled when unset
+    pub tcp_port_range: Option<RangeInclusive<u16>>,
+
+    /// Public ports handed out to UDP tunnels, i.e: 20100-20199
+    /// UDP tunnels are disabled when unset
+    pub udp_port_range: Option<RangeInclusive<u16>>,
+
+    /// How long in-flight streams get to finish when shutting down
+    pub drain_timeout: Duration,
+
+    /// Limits on anonymous clients, which are refused when unset
+    pub anonymous: Option<AnonymousLimits>,
+
+    /// File keeping the sub-domains reserved by their owners
+    /// Reservations are disabled when unset
+    pub reservations_file: Option<PathBuf>,
+
+    /// File keeping the API keys issued with `portalgun_moon api-key`
+    /// API keys are not accepted when unset
+    pub api_keys_file: Option<PathBuf>,
+
+    /// File listing the subjects and tokens denied with `portalgun_moon denylist`
+    /// Nobody is denied when unset
+    pub denylist_file: Option<PathBuf>,
 
-    /// OIDC Discovery URL for token validation
-    pub oidc_discovery_url: String,
+    /// Serve the control endpoint over TLS instead of plain websockets
+    pub control_tls: Option<ControlTls>,
 
-    /// OIDC client id
-    pub oidc_client_id: String,
+    /// Sub-domain patterns granted to the names of client certificates
+    pub client_cert_sub_domains: HashMap<String, Vec<String>>,
+}
+
+impl Default for Config {
+    /// The defaults of [`Config::from_env`], but with authentication disabled
+    /// and an ephemeral signature key
+    fn default() -> Self {
+        Config {
+            allowed_hosts: vec![],
+            blocked_sub_domains: vec![],
+            remote_port: 8080,
+            control_port: 5000,
+            internal_network_port: 6000,
+            sig_keys: SigKeyRing::new(SigKey::generate()),
+            gossip_dns_host: None,
+            instance_id: Uuid::new_v4().to_string(),
+            blocked_ips: vec![],
+            trusted_proxies: vec![],
+            tunnel_host: "tunnelto.dev".to_string(),
+            auth_backend: AuthBackend::NoAuth,
+            min_protocol_version: 0,
+            tcp_port_range: None,
+            udp_port_range: None,
+            drain_timeout: Duration::from_secs(30),
+            anonymous: None,
+            reservations_file: None,
+            api_keys_file: None,
+            denylist_file: None,
+            control_tls: None,
+            client_cert_sub_domains: HashMap::new(),
+        }
+    }
+}
+
+/// Certificates of the control endpoint, when it terminates TLS itself
+#[derive(Debug, Clone)]
+pub struct ControlTls {
+    /// PEM encoded certificate chain of the server
+    pub cert_file: PathBuf,
+    /// PEM encoded private key of the server
+    pub key_file: PathBuf,
+    /// PEM encoded CA certificates verifying client certificates,
+    /// which are not asked for when unset
+    pub client_ca_file: Option<PathBuf>,
+}
 
-    /// OIDC scopes
-    pub oidc_scopes: String,
+/// Limits on anonymous clients
+#[derive(Debug, Clone)]
+pub struct AnonymousLimits {
+    /// How long an anonymous tunnel lives, reconnects included
+    pub max_lifetime: Duration,
+    /// Most anonymous tunnels open at once from a single IP address
+    pub max_tunnels_per_ip: usize,
 }
 
 impl Config {
+    /// Whether `ip` is one of the proxies in front of the control server
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|proxy| proxy.contains(&ip.to_canonical()))
+    }
+
     pub fn from_env() -> Config {
         let allowed_hosts = std::env::var("ALLOWED_HOSTS")
             .map(|s| s.split(',').map(String::from).collect())
@@ -72,6 +168,16 @@ impl Config {
             tracing::warn!("WARNING! generating ephemeral signature key!");
             SigKey::generate()
         };
+        // keys rotated out, still accepted for the reconnect tokens they signed
+        let mut sig_keys = SigKeyRing::new(master_sig_key);
+        if let Ok(keys) = std::env::var("PREVIOUS_SIG_KEYS") {
+            for key in keys.split(',') {
+                sig_keys = sig_keys.with_previous(
+                    SigKey::from_hex(key.trim())
+                        .expect("invalid previous key: not hex or length incorrect"),
+                );
+            }
+        }
 
         let gossip_dns_host = std::env::var("FLY_APP_NAME")
             .map(|app_name| format!("global.{}.internal", app_name))
@@ -87,23 +193,84 @@ impl Config {
             })
             .unwrap_or_default();
 
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .map(|s| {
+                s.split(',')
+                    .map(|proxy| {
+                        let proxy = proxy.trim();
+                        IpNet::from_str(proxy)
+                            .or_else(|_| IpAddr::from_str(proxy).map(IpNet::from))
+                            .expect("invalid TRUSTED_PROXIES: not an IP address or network")
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
         let tunnel_host = std::env::var("TUNNEL_HOST").unwrap_or("tunnelto.dev".to_string());
 
-        let disable_attribute_validation =
-            std::env::var("DISABLE_ATTRIBUTE_VALIDATION").unwrap_or("0".to_string()) == "1";
+        let auth_backend = get_auth_backend();
 
-        let oidc_discovery_url =
-            std::env::var("OIDC_DISCOVERY").expect("OIDC_DISCOVERY is required");
+        let min_protocol_version = std::env::var("MIN_PROTOCOL_VERSION")
+            .map(|v| {
+                v.parse()
+                    .expect("invalid MIN_PROTOCOL_VERSION: not a number")
+            })
+            .unwrap_or(0);
 
-        let oidc_client_id = std::env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID is required");
+        let tcp_port_range = get_port_range("TCP_PORT_RANGE");
+        let udp_port_range = get_port_range("UDP_PORT_RANGE");
 
-        let oidc_scopes = std::env::var("OIDC_SCOPES").unwrap_or(
-            match disable_attribute_validation {
-                false => "openid,portalgun",
-                true => "openid",
-            }
-            .to_owned(),
-        );
+        let drain_timeout = std::env::var("DRAIN_TIMEOUT")
+            .map(|v| {
+                v.parse()
+                    .expect("invalid DRAIN_TIMEOUT: not a number of seconds")
+            })
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(30));
+
+        let anonymous = match std::env::var("ANONYMOUS_TUNNELS").as_deref() {
+            Ok("1") => Some(AnonymousLimits {
+                max_lifetime: std::env::var("ANONYMOUS_MAX_LIFETIME")
+                    .map(|v| {
+                        v.parse()
+                            .expect("invalid ANONYMOUS_MAX_LIFETIME: not a number of seconds")
+                    })
+                    .map(Duration::from_secs)
+                    .unwrap_or(Duration::from_secs(60 * 60)),
+                max_tunnels_per_ip: std::env::var("ANONYMOUS_MAX_TUNNELS_PER_IP")
+                    .map(|v| {
+                        v.parse()
+                            .expect("invalid ANONYMOUS_MAX_TUNNELS_PER_IP: not a number")
+                    })
+                    .unwrap_or(2),
+            }),
+            _ => None,
+        };
+
+        let reservations_file = std::env::var("RESERVATIONS_FILE").map(PathBuf::from).ok();
+        let api_keys_file = std::env::var("API_KEYS_FILE").map(PathBuf::from).ok();
+        let denylist_file = std::env::var("DENYLIST_FILE").map(PathBuf::from).ok();
+
+        let control_tls = match (
+            std::env::var("CONTROL_TLS_CERT"),
+            std::env::var("CONTROL_TLS_KEY"),
+        ) {
+            (Ok(cert_file), Ok(key_file)) => Some(ControlTls {
+                cert_file: cert_file.into(),
+                key_file: key_file.into(),
+                client_ca_file: std::env::var("CONTROL_TLS_CLIENT_CA")
+                    .map(PathBuf::from)
+                    .ok(),
+            }),
+            (Err(_), Err(_)) => None,
+            _ => panic!("CONTROL_TLS_CERT and CONTROL_TLS_KEY must be set together"),
+        };
+        let client_cert_sub_domains = std::env::var("CLIENT_CERT_SUBDOMAINS")
+            .map(|patterns| {
+                serde_json::from_str(&patterns)
+                    .expect("invalid CLIENT_CERT_SUBDOMAINS: not a JSON object of pattern lists")
+            })
+            .unwrap_or_default();
 
         Config {
             allowed_hosts,
@@ -111,19 +278,109 @@ impl Config {
             control_port: get_port("CTRL_PORT", 5000),
             remote_port: get_port("PORT", 8080),
             internal_network_port: get_port("NET_PORT", 6000),
-            master_sig_key,
+            sig_keys,
             gossip_dns_host,
             instance_id,
             blocked_ips,
+            trusted_proxies,
             tunnel_host,
-            disable_attribute_validation,
-            oidc_discovery_url,
-            oidc_client_id,
-            oidc_scopes,
+            auth_backend,
+            min_protocol_version,
+            tcp_port_range,
+            udp_port_range,
+            drain_timeout,
+            anonymous,
+            reservations_file,
+            api_keys_file,
+            denylist_file,
+            control_tls,
+            client_cert_sub_domains,
         }
     }
 }
 
+fn get_auth_backend() -> AuthBackend {
+    match std::env::var("AUTH_BACKEND").as_deref() {
+        Ok("oidc") | Err(_) => {
+            let discovery_url =
+                std::env::var("OIDC_DISCOVERY").expect("OIDC_DISCOVERY is required");
+
+            let client_id = std::env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID is required");
+
+            let sub_domains = get_sub_domain_policy();
+
+            let scopes = std::env::var("OIDC_SCOPES").unwrap_or(
+                match sub_domains {
+                    SubDomainPolicy::Claims(_) => "openid,portalgun",
+                    SubDomainPolicy::Any => "openid",
+                }
+                .to_owned(),
+            );
+
+            // opaque access tokens are introspected as the client itself, unless configured otherwise
+            let introspection = std::env::var("OIDC_INTROSPECTION_CLIENT_SECRET")
+                .map(|client_secret| IntrospectionClient {
+                    client_id: std::env::var("OIDC_INTROSPECTION_CLIENT_ID")
+                        .unwrap_or(client_id.clone()),
+                    client_secret,
+                })
+                .ok();
+
+            AuthBackend::Oidc {
+                discovery_url,
+                client_id,
+                scopes,
+                sub_domains,
+                introspection,
+            }
+        }
+        Ok("none") => AuthBackend::NoAuth,
+        Ok("token_file") => AuthBackend::TokenFile(
+            std::env::var("AUTH_TOKEN_FILE")
+                .expect("AUTH_TOKEN_FILE is required")
+                .into(),
+        ),
+        Ok("http") => AuthBackend::Http(
+            std::env::var("AUTH_CALLOUT_URL")
+                .expect("AUTH_CALLOUT_URL is required")
+                .parse()
+                .expect("invalid AUTH_CALLOUT_URL"),
+        ),
+        Ok(backend) => panic!("invalid auth backend ENV AUTH_BACKEND={}", backend),
+    }
+}
+
+/// Which sub-domains OIDC tokens grant: any with `DISABLE_ATTRIBUTE_VALIDATION=1`,
+/// else those from the configured claims, or the `portalgun_subdomains` claim
+fn get_sub_domain_policy() -> SubDomainPolicy {
+    if std::env::var("DISABLE_ATTRIBUTE_VALIDATION").unwrap_or("0".to_string()) == "1" {
+        return SubDomainPolicy::Any;
+    }
+
+    let mut sources = vec![];
+
+    if let Ok(claim) = std::env::var("OIDC_SUBDOMAIN_CLAIM") {
+        sources.push(SubDomainSource::Claim(claim));
+    }
+
+    if let Ok(claim) = std::env::var("OIDC_GROUPS_CLAIM") {
+        let patterns = std::env::var("OIDC_GROUP_SUBDOMAINS")
+            .expect("OIDC_GROUP_SUBDOMAINS is required with OIDC_GROUPS_CLAIM");
+        let patterns = serde_json::from_str(&patterns)
+            .expect("invalid OIDC_GROUP_SUBDOMAINS: not a JSON object of pattern lists");
+        sources.push(SubDomainSource::Groups { claim, patterns });
+    }
+
+    if let Ok(template) = std::env::var("OIDC_SUBDOMAIN_TEMPLATE") {
+        sources.push(SubDomainSource::Template(template));
+    }
+
+    match sources.is_empty() {
+        true => SubDomainPolicy::default(),
+        false => SubDomainPolicy::Claims(sources),
+    }
+}
+
 fn get_port(var: &'static str, default: u16) -> u16 {
     if let Ok(port) = std::env::var(var) {
         port.parse().unwrap_or_else(|_| {
@@ -133,3 +390,22 @@ fn get_port(var: &'static str, default: u16) -> u16 {
         default
     }
 }
+
+fn get_port_range(var: &'static str) -> Option<RangeInclusive<u16>> {
+    std::env::var(var)
+        .map(|range| {
+            parse_port_range(&range).unwrap_or_else(|| {
+                panic!("invalid port range ENV {}={}", var, range);
+            })
+        })
+        .ok()
+}
+
+fn parse_port_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    if start > end {
+        return None;
+    }
+    Some(start..=end)
+}
@@ -3,15 +3,43 @@
 // SPDX-License-Identifier: MIT
 
 use super::*;
+use crate::auth::Identity;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
+use std::collections::HashMap;
 use std::fmt::Formatter;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Most control packets queued for a client before their senders wait for the websocket
+pub const CLIENT_QUEUE_LEN: usize = 1024;
 
 #[derive(Clone)]
 pub struct ConnectedClient {
     pub id: ClientId,
     pub host: String,
     pub is_anonymous: bool,
-    pub tx: UnboundedSender<ControlPacket>,
+    pub protocol_version: u32,
+    pub capabilities: Capabilities,
+    pub tunnel_type: TunnelType,
+    /// additional sub-domains served by this client, mapped to their binding name
+    pub bindings: HashMap<String, String>,
+    /// when the tunnel is closed, whether the client is still connected or not
+    pub expires: Option<DateTime<Utc>>,
+    /// who the client authenticated as, updated when it re-authenticates
+    pub identity: Arc<Mutex<Identity>>,
+    pub tx: Sender<ControlPacket>,
+}
+
+impl ConnectedClient {
+    /// Name of the binding serving `host`, `None` for the primary tunnel
+    pub fn binding_for_host(&self, host: &str) -> Option<String> {
+        self.bindings.get(host).cloned()
+    }
+
+    fn hosts(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.host).chain(self.bindings.keys())
+    }
 }
 
 impl std::fmt::Debug for ConnectedClient {
@@ -20,6 +48,11 @@ impl std::fmt::Debug for ConnectedClient {
             .field("id", &self.id)
             .field("sub", &self.host)
             .field("anon", &self.is_anonymous)
+            .field("protocol_version", &self.protocol_version)
+            .field("tunnel_type", &self.tunnel_type)
+            .field("bindings", &self.bindings)
+            .field("expires", &self.expires)
+            .field("identity", &self.identity)
             .finish()
     }
 }
@@ -43,57 +76,120 @@ impl Connections {
         Self::default()
     }
 
-    pub fn update_host(client: &ConnectedClient) {
-        CONNECTIONS
-            .hosts
-            .insert(client.host.clone(), client.clone());
+    pub fn update_host(&self, client: &ConnectedClient) {
+        for host in client.hosts() {
+            self.hosts.insert(host.clone(), client.clone());
+        }
+    }
+
+    /// Stop routing the client's hosts to it, while keeping it connected
+    pub fn release_hosts(&self, client: &ConnectedClient) {
+        for host in client.hosts() {
+            // ensure another client isn't using this host
+            if self.hosts.get(host).is_some_and(|c| c.id == client.id) {
+                tracing::debug!("dropping sub-domain: {}", host);
+                self.hosts.remove(host);
+            };
+        }
     }
 
-    pub fn remove(client: &ConnectedClient) {
-        client.tx.close_channel();
+    /// Drop the client from the registry, see [`ServerState::remove_client`]
+    pub fn remove(&self, client: &ConnectedClient) {
+        client.tx.clone().close_channel();
 
-        // ensure another client isn't using this host
-        if CONNECTIONS
-            .hosts
-            .get(&client.host)
-            .map_or(false, |c| c.id == client.id)
-        {
-            tracing::debug!("dropping sub-domain: {}", &client.host);
-            CONNECTIONS.hosts.remove(&client.host);
-        };
+        self.release_hosts(client);
 
-        CONNECTIONS.clients.remove(&client.id);
+        self.clients.remove(&client.id);
         tracing::debug!("rm client: {}", &client.id);
+    }
 
-        // // drop all the streams
-        // // if there are no more tunnel clients
-        // if CONNECTIONS.clients.is_empty() {
-        //     let mut streams = ACTIVE_STREAMS.;
-        //     for (_, stream) in streams.drain() {
-        //         stream.tx.close_channel();
-        //     }
-        // }
+    pub fn client_for_host(&self, host: &String) -> Option<ClientId> {
+        self.hosts.get(host).map(|c| c.id.clone())
     }
 
-    pub fn client_for_host(host: &String) -> Option<ClientId> {
-        CONNECTIONS.hosts.get(host).map(|c| c.id.clone())
+    pub fn clients(&self) -> Vec<ConnectedClient> {
+        self.clients.iter().map(|c| c.value().clone()).collect()
     }
 
-    pub fn get(client_id: &ClientId) -> Option<ConnectedClient> {
-        CONNECTIONS
-            .clients
-            .get(client_id)
-            .map(|c| c.value().clone())
+    pub fn get(&self, client_id: &ClientId) -> Option<ConnectedClient> {
+        self.clients.get(client_id).map(|c| c.value().clone())
     }
 
-    pub fn find_by_host(host: &String) -> Option<ConnectedClient> {
-        CONNECTIONS.hosts.get(host).map(|c| c.value().clone())
+    pub fn find_by_host(&self, host: &String) -> Option<ConnectedClient> {
+        self.hosts.get(host).map(|c| c.value().clone())
     }
 
-    pub fn add(client: ConnectedClient) {
-        CONNECTIONS
-            .clients
-            .insert(client.id.clone(), client.clone());
-        CONNECTIONS.hosts.insert(client.host.clone(), client);
+    pub fn add(&self, client: ConnectedClient) {
+        self.clients.insert(client.id.clone(), client.clone());
+        self.update_host(&client);
+    }
+}
+
+/// The address of each anonymous client, to limit how many tunnels an address opens
+#[derive(Default)]
+pub struct AnonymousClients {
+    ips: Mutex<HashMap<ClientId, IpAddr>>,
+}
+
+impl AnonymousClients {
+    /// Count the client against its address, unless the address has `limit` clients
+    /// already. A reconnecting client keeps its place.
+    pub fn admit(&self, client_id: &ClientId, ip: IpAddr, limit: usize) -> bool {
+        let mut ips = self.ips.lock().unwrap();
+        if !ips.contains_key(client_id) && ips.values().filter(|i| **i == ip).count() >= limit {
+            return false;
+        }
+
+        ips.insert(client_id.clone(), ip);
+        true
+    }
+
+    pub fn remove(&self, client_id: &ClientId) {
+        self.ips.lock().unwrap().remove(client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOME: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OFFICE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn admits_up_to_the_limit_per_address() {
+        let clients = AnonymousClients::default();
+
+        assert!(clients.admit(&ClientId::generate(), HOME, 2));
+        assert!(clients.admit(&ClientId::generate(), HOME, 2));
+        assert!(!clients.admit(&ClientId::generate(), HOME, 2));
+    }
+
+    #[test]
+    fn counts_addresses_separately() {
+        let clients = AnonymousClients::default();
+
+        assert!(clients.admit(&ClientId::generate(), HOME, 1));
+        assert!(!clients.admit(&ClientId::generate(), HOME, 1));
+        assert!(clients.admit(&ClientId::generate(), OFFICE, 1));
+    }
+
+    #[test]
+    fn reconnecting_clients_keep_their_place() {
+        let clients = AnonymousClients::default();
+        let client_id = ClientId::generate();
+
+        assert!(clients.admit(&client_id, HOME, 1));
+        assert!(clients.admit(&client_id, HOME, 1));
+    }
+
+    #[test]
+    fn removing_a_client_frees_its_place() {
+        let clients = AnonymousClients::default();
+        let client_id = ClientId::generate();
+
+        assert!(clients.admit(&client_id, HOME, 1));
+        clients.remove(&client_id);
+        assert!(clients.admit(&ClientId::generate(), HOME, 1));
     }
 }
@@ -3,98 +3,307 @@
 //
 // SPDX-License-Identifier: MIT
 
-pub use super::*;
+use super::*;
 use crate::auth::reconnect_token::ReconnectTokenPayload;
+use crate::auth::ClientCertificate;
 use crate::client_auth::ClientHandshake;
+use crate::config::ControlTls;
+use crate::tcp_tunnel::TcpTunnelListener;
+use crate::udp_tunnel::UdpTunnelSocket;
+use anyhow::Context;
 use chrono::Utc;
+use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::Mutex;
 use std::time::Duration;
+use tokio::sync::watch;
+use tokio_rustls::rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore};
+use tokio_rustls::TlsAcceptor;
 use tracing::error;
-use warp::Rejection;
+use warp::{Rejection, Reply};
 
-pub fn spawn<A: Into<SocketAddr>>(addr: A) {
+/// Serve the control endpoint until the server starts draining, over TLS with `tls`
+pub fn spawn<A: Into<SocketAddr>>(
+    state: Arc<ServerState>,
+    addr: A,
+    tls: Option<TlsAcceptor>,
+) -> Result<SocketAddr, anyhow::Error> {
+    let tls = match tls {
+        Some(tls) => tls,
+        None => {
+            // spawn our websocket control server, it stops taking new clients once we drain
+            let routes = routes(state.clone(), warp::addr::remote(), None);
+            let (addr, server) = warp::serve(routes)
+                .try_bind_with_graceful_shutdown(addr.into(), async move {
+                    drain::started(&state).await
+                })?;
+            tokio::spawn(server);
+            return Ok(addr);
+        }
+    };
+
+    let listener = std::net::TcpListener::bind(addr.into())?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(accept_tls(state, listener, tls));
+    Ok(addr)
+}
+
+/// Accept TLS connections, each serving the control endpoint, until the server starts draining
+async fn accept_tls(state: Arc<ServerState>, listener: TcpListener, tls: TlsAcceptor) {
+    let draining = drain::started(&state);
+    tokio::pin!(draining);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut draining => return,
+        };
+
+        let (socket, remote) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                error!(?error, "failed to accept control connection");
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let stream = match tls.accept(socket).await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    tracing::debug!(?error, %remote, "TLS handshake failed");
+                    return;
+                }
+            };
+
+            // only certificates of the client CA get this far
+            let certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(|certificate| ClientCertificate::from_der(&certificate.0));
+
+            let routes = routes(state, warp::any().map(move || Some(remote)), certificate);
+            let connection = hyper::server::conn::Http::new()
+                .http1_only(true)
+                .serve_connection(stream, warp::service(routes))
+                .with_upgrades();
+            if let Err(error) = connection.await {
+                tracing::debug!(?error, %remote, "control connection failed");
+            }
+        });
+    }
+}
+
+/// Load the certificates of the control endpoint
+pub fn tls_acceptor(tls: &ControlTls) -> Result<TlsAcceptor, anyhow::Error> {
+    let certs = read_pem(&tls.cert_file, rustls_pemfile::certs)?;
+    let key = read_pem(&tls.key_file, rustls_pemfile::read_all)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key in {}", tls.key_file.display()))?;
+
+    let config = rustls::ServerConfig::builder().with_safe_defaults();
+    let config = match &tls.client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_pem(client_ca_file, rustls_pemfile::certs)? {
+                roots.add(&Certificate(ca))?;
+            }
+            // clients without a certificate may still log in with a token
+            config.with_client_cert_verifier(
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+            )
+        }
+        None => config.with_no_client_auth(),
+    };
+    let config = config.with_single_cert(certs.into_iter().map(Certificate).collect(), key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_pem<T>(
+    path: &std::path::Path,
+    parse: impl FnOnce(&mut dyn std::io::BufRead) -> std::io::Result<Vec<T>>,
+) -> Result<Vec<T>, anyhow::Error> {
+    let pem = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    parse(&mut &pem[..]).with_context(|| format!("invalid PEM in {}", path.display()))
+}
+
+/// The control endpoint, for clients connecting from `remote` with `certificate`
+fn routes<R>(
+    state: Arc<ServerState>,
+    remote: R,
+    certificate: Option<ClientCertificate>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    R: Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Health Check #2 triggered");
         "ok"
     });
-    let client_conn = warp::path("wormhole").and(client_ip()).and(warp::ws()).map(
-        move |client_ip: IpAddr, ws: Ws| {
-            ws.on_upgrade(move |w| async move { handle_new_connection(client_ip, w).await })
-        },
-    );
-
-    let routes = client_conn.or(health_check);
+    let client_conn = warp::path("wormhole")
+        .and(client_ip(state.clone(), remote))
+        .and(warp::ws())
+        .map(move |client_ip: IpAddr, ws: Ws| {
+            let state = state.clone();
+            let certificate = certificate.clone();
+            ws.on_upgrade(move |w| async move {
+                handle_new_connection(state, client_ip, certificate, w).await
+            })
+        });
 
-    // spawn our websocket control server
-    tokio::spawn(warp::serve(routes).run(addr.into()));
+    client_conn.or(health_check)
 }
 
-fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
+/// The address of the client, as told by the proxy it connected through if that is a trusted one.
+/// Anyone else could make up the headers, i.e. to get around the limits on anonymous clients.
+fn client_ip<R>(
+    state: Arc<ServerState>,
+    remote: R,
+) -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Clone
+where
+    R: Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
     warp::any()
         .and(warp::header::optional("Fly-Client-IP"))
         .and(warp::header::optional("X-Forwarded-For"))
-        .and(warp::addr::remote())
+        .and(remote)
         .map(
-            |client_ip: Option<String>, fwd: Option<String>, remote: Option<SocketAddr>| {
-                let client_ip = client_ip.and_then(|s| IpAddr::from_str(&s).ok());
-                let fwd = fwd.and_then(|s| {
-                    s.split(',')
-                        .next()
-                        .map(IpAddr::from_str)
-                        .and_then(Result::ok)
-                });
-                let remote = remote.map(|r| r.ip());
-                client_ip
-                    .or(fwd)
-                    .or(remote)
-                    .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+            move |client_ip: Option<String>, fwd: Option<String>, remote: Option<SocketAddr>| {
+                let remote = remote.map(|r| r.ip()).unwrap_or(IpAddr::from([0, 0, 0, 0]));
+                resolve_client_ip(&state.config, remote, client_ip, fwd)
             },
         )
 }
 
-#[tracing::instrument(skip(websocket))]
-async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
+/// The client connected from `remote`, or the one behind it when `remote` is a trusted proxy
+fn resolve_client_ip(
+    config: &Config,
+    remote: IpAddr,
+    client_ip: Option<String>,
+    fwd: Option<String>,
+) -> IpAddr {
+    let remote = remote.to_canonical();
+    if !config.is_trusted_proxy(remote) {
+        return remote;
+    }
+
+    let client_ip = client_ip.and_then(|s| IpAddr::from_str(s.trim()).ok());
+    let fwd = fwd.and_then(|s| forwarded_client(config, &s));
+    client_ip.or(fwd).unwrap_or(remote)
+}
+
+/// The client in an `X-Forwarded-For` header: proxies append the address they were
+/// connected from, so it is the last address that isn't one of our proxies
+fn forwarded_client(config: &Config, header: &str) -> Option<IpAddr> {
+    for address in header.rsplit(',') {
+        let address = IpAddr::from_str(address.trim()).ok()?;
+        if !config.is_trusted_proxy(address) {
+            return Some(address);
+        }
+    }
+    None
+}
+
+#[tracing::instrument(skip(state, certificate, websocket))]
+async fn handle_new_connection(
+    state: Arc<ServerState>,
+    client_ip: IpAddr,
+    certificate: Option<ClientCertificate>,
+    websocket: WebSocket,
+) {
     // check if this client is blocked
-    if CONFIG.blocked_ips.contains(&client_ip) {
+    if state.config.blocked_ips.contains(&client_ip) {
         tracing::warn!(?client_ip, "client ip is on block list, denying connection");
         let _ = websocket.close().await;
         return;
     }
 
-    let (websocket, handshake) = match try_client_handshake(websocket).await {
-        Some(ws) => ws,
-        None => return,
-    };
+    if drain::is_draining(&state) {
+        tracing::info!(?client_ip, "draining, denying connection");
+        let _ = websocket.close().await;
+        return;
+    }
+
+    let (websocket, handshake, port_tunnel) =
+        match try_client_handshake(&state, client_ip, certificate, websocket).await {
+            Some(ws) => ws,
+            None => return,
+        };
 
     tracing::info!(client_ip=%client_ip, subdomain=%handshake.sub_domain, "open tunnel");
 
-    let (tx, rx) = unbounded::<ControlPacket>();
+    let (tx, rx) = channel::<ControlPacket>(CLIENT_QUEUE_LEN);
     let mut client = ConnectedClient {
         id: handshake.id,
         host: handshake.sub_domain,
         is_anonymous: handshake.is_anonymous,
+        protocol_version: handshake.protocol_version,
+        capabilities: handshake.capabilities,
+        tunnel_type: handshake.tunnel_type,
+        bindings: handshake.bindings,
+        expires: handshake.expires,
+        identity: Arc::new(Mutex::new(handshake.identity)),
         tx,
     };
-    Connections::add(client.clone());
+    state.connections.add(client.clone());
+
+    match port_tunnel {
+        Some(PortTunnel::Tcp(listener)) => TcpTunnels::spawn(&state, client.clone(), listener),
+        Some(PortTunnel::Udp(socket)) => UdpTunnels::spawn(&state, client.clone(), socket),
+        None => {}
+    }
 
     let (sink, stream) = websocket.split();
 
     let client_clone = client.clone();
+    let state_clone = state.clone();
 
     tokio::spawn(async move {
-        tunnel_client(client_clone, sink, rx).await;
+        tunnel_client(state_clone, client_clone, sink, rx).await;
     });
 
     let client_clone = client.clone();
+    let state_clone = state.clone();
 
     tokio::spawn(async move {
-        process_client_messages(client_clone, stream).await;
+        process_client_messages(state_clone, client_clone, stream).await;
     });
 
     // play ping pong
     tokio::spawn(async move {
         loop {
+            if client.expires.is_some_and(|expires| Utc::now() >= expires) {
+                tracing::info!(subdomain=%client.host, "tunnel expired, closing it");
+                state.remove_client(&client);
+                return;
+            }
+
+            // clients keep their tunnels open by re-authenticating before their credentials expire
+            let identity = client.identity.lock().unwrap().clone();
+            if identity
+                .expires
+                .is_some_and(|expires| Utc::now() >= expires)
+            {
+                tracing::info!(subdomain=%client.host, ?identity.subject, "credentials expired, closing tunnel");
+                state.remove_client(&client);
+                return;
+            }
+
             tracing::trace!("sending ping");
 
             // create a new reconnect token for anonymous clients
@@ -103,8 +312,9 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
                     sub_domain: client.host.clone(),
                     client_id: client.id.clone(),
                     expires: Utc::now() + chrono::Duration::minutes(2),
+                    tunnel_expires: client.expires,
                 }
-                .into_token(&CONFIG.master_sig_key)
+                .into_token(&state.config.sig_keys)
                 .map_err(|e| error!("unable to create reconnect token: {:?}", e))
                 .ok()
             } else {
@@ -115,7 +325,7 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
                 Ok(_) => {}
                 Err(e) => {
                     tracing::debug!("Failed to send ping: {:?}, removing client", e);
-                    Connections::remove(&client);
+                    state.remove_client(&client);
                     return;
                 }
             };
@@ -125,22 +335,94 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
     });
 }
 
-#[tracing::instrument(skip(websocket))]
-async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, ClientHandshake)> {
+/// Public socket bound during the handshake of a port based tunnel
+enum PortTunnel {
+    Tcp(TcpTunnelListener),
+    Udp(UdpTunnelSocket),
+}
+
+impl PortTunnel {
+    fn port(&self) -> u16 {
+        match self {
+            PortTunnel::Tcp(listener) => listener.port(),
+            PortTunnel::Udp(socket) => socket.port(),
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, certificate, websocket))]
+async fn try_client_handshake(
+    state: &ServerState,
+    client_ip: IpAddr,
+    certificate: Option<ClientCertificate>,
+    websocket: WebSocket,
+) -> Option<(WebSocket, ClientHandshake, Option<PortTunnel>)> {
+    let config = &state.config;
+
     // Authenticate client handshake
-    let (mut websocket, client_handshake) = client_auth::auth_client_handshake(websocket).await?;
+    let (mut websocket, client_handshake) =
+        client_auth::auth_client_handshake(state, client_ip, certificate, websocket).await?;
+
+    // tcp and udp tunnels get their own public port
+    let port_tunnel = match client_handshake.tunnel_type {
+        TunnelType::Http => None,
+        TunnelType::Tcp => state
+            .tcp_tunnels
+            .allocate(config)
+            .await
+            .map(PortTunnel::Tcp),
+        TunnelType::Udp => state
+            .udp_tunnels
+            .allocate(config)
+            .await
+            .map(PortTunnel::Udp),
+    };
+
+    let hostname = match (client_handshake.tunnel_type, &port_tunnel) {
+        (TunnelType::Http, _) => {
+            format!("{}.{}", &client_handshake.sub_domain, config.tunnel_host)
+        }
+        (_, Some(_)) => config.tunnel_host.clone(),
+        (tunnel_type, None) => {
+            state.anonymous_clients.remove(&client_handshake.id);
+            client_auth::reject(
+                &mut websocket,
+                &client_handshake.capabilities,
+                ErrorCode::NoPortsAvailable,
+                format!(
+                    "No {} tunnel ports available, try again later.",
+                    tunnel_type
+                ),
+            )
+            .await;
+            return None;
+        }
+    };
 
     // Send server hello success
     let data = serde_json::to_vec(&ServerHello::Success {
         sub_domain: client_handshake.sub_domain.clone(),
-        hostname: format!("{}.{}", &client_handshake.sub_domain, CONFIG.tunnel_host),
+        hostname,
         client_id: client_handshake.id.clone(),
+        protocol_version: client_handshake.protocol_version,
+        capabilities: client_handshake.capabilities.clone(),
+        port: port_tunnel.as_ref().map(PortTunnel::port),
+        tunnels: client_handshake
+            .bindings
+            .iter()
+            .map(|(sub_domain, name)| BoundTunnel {
+                name: name.clone(),
+                sub_domain: sub_domain.clone(),
+                hostname: format!("{}.{}", sub_domain, config.tunnel_host),
+            })
+            .collect(),
     })
     .unwrap_or_default();
 
     let send_result = websocket.send(Message::binary(data)).await;
     if let Err(error) = send_result {
         error!(?error, "aborting...failed to write server hello");
+        state.anonymous_clients.remove(&client_handshake.id);
         return None;
     }
 
@@ -153,15 +435,25 @@ async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, Client
             ""
         }
     );
-    Some((websocket, client_handshake))
+    Some((websocket, client_handshake, port_tunnel))
 }
 
 /// Send the client a "stream init" message
-pub async fn send_client_stream_init(mut stream: ActiveStream) {
+pub async fn send_client_stream_init(
+    state: &ServerState,
+    mut stream: ActiveStream,
+    metadata: StreamMetadata,
+) {
+    let metadata = stream
+        .client
+        .capabilities
+        .has(Capability::StreamMetadata)
+        .then_some(metadata);
+
     match stream
         .client
         .tx
-        .send(ControlPacket::Init(stream.id.clone()))
+        .send(ControlPacket::Init(stream.id.clone(), metadata))
         .await
     {
         Ok(_) => {
@@ -169,14 +461,45 @@ pub async fn send_client_stream_init(mut stream: ActiveStream) {
         }
         Err(_) => {
             tracing::debug!("removing disconnected client: {}", &stream.client.id);
-            Connections::remove(&stream.client);
+            state.remove_client(&stream.client);
+        }
+    }
+}
+
+/// Check the tokens a client sends to re-authenticate one at a time, skipping to
+/// the latest. Stops once the client is gone.
+async fn reauthenticate(
+    state: Arc<ServerState>,
+    client: ConnectedClient,
+    mut keys: watch::Receiver<SecretKey>,
+) {
+    loop {
+        let key = keys.borrow_and_update().clone();
+        match client_auth::reauthenticate(&state, &client, key).await {
+            Ok(identity) => {
+                tracing::info!(subdomain=%client.host, ?identity.expires, "client re-authenticated")
+            }
+            Err(error) => {
+                error!(subdomain=%client.host, ?error, "client failed to re-authenticate")
+            }
+        }
+
+        if keys.changed().await.is_err() {
+            return;
         }
     }
 }
 
 /// Process client control messages
-#[tracing::instrument(skip(client_conn))]
-async fn process_client_messages(client: ConnectedClient, mut client_conn: SplitStream<WebSocket>) {
+#[tracing::instrument(skip(state, client_conn))]
+async fn process_client_messages(
+    state: Arc<ServerState>,
+    client: ConnectedClient,
+    mut client_conn: SplitStream<WebSocket>,
+) {
+    // the latest token sent for re-authentication, once the client sent one
+    let mut reauth_keys: Option<watch::Sender<SecretKey>> = None;
+
     loop {
         let result = client_conn.next().await;
 
@@ -188,12 +511,12 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
             // handle close with reason
             Some(Ok(msg)) if msg.is_close() && !msg.as_bytes().is_empty() => {
                 tracing::debug!(close_reason=?msg, "got close");
-                Connections::remove(&client);
+                state.remove_client(&client);
                 return;
             }
             _ => {
                 tracing::debug!(?client.id, "goodbye client");
-                Connections::remove(&client);
+                state.remove_client(&client);
                 return;
             }
         };
@@ -211,36 +534,105 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
                 tracing::debug!(?stream_id, num_bytes=?data.len(),"forwarding to stream");
                 (stream_id, StreamMessage::Data(data))
             }
+            ControlPacket::Datagram(stream_id, data) => {
+                state
+                    .udp_tunnels
+                    .send_to_peer(&client.id, &stream_id, &data)
+                    .await;
+                continue;
+            }
+            ControlPacket::WindowUpdate(stream_id, credit) => {
+                tracing::trace!(?stream_id, %credit, "window update");
+                if let Some(stream) = state.active_streams.get(&stream_id) {
+                    stream.send_window.grant(credit);
+                }
+                continue;
+            }
             ControlPacket::Refused(stream_id) => {
                 tracing::debug!("tunnel says: refused");
                 (stream_id, StreamMessage::TunnelRefused)
             }
-            ControlPacket::Init(_) | ControlPacket::End(_) => {
+            ControlPacket::End(stream_id) if client.capabilities.has(Capability::HalfClose) => {
+                tracing::debug!(?stream_id, "client closed its side of the stream");
+                (stream_id, StreamMessage::End)
+            }
+            ControlPacket::Reset(stream_id) => {
+                tracing::debug!(?stream_id, "client reset the stream");
+                (stream_id, StreamMessage::Reset)
+            }
+            ControlPacket::Reauthenticate(_) if client.is_anonymous => {
+                error!("anonymous clients can't re-authenticate");
+                continue;
+            }
+            ControlPacket::Reauthenticate(key) => {
+                // the auth service may take a while, don't hold up the streams meanwhile. A
+                // token sent while another is checked replaces any token still waiting.
+                match &reauth_keys {
+                    Some(keys) => {
+                        keys.send_replace(key);
+                    }
+                    None => {
+                        let (keys, latest) = watch::channel(key);
+                        tokio::spawn(reauthenticate(state.clone(), client.clone(), latest));
+                        reauth_keys = Some(keys);
+                    }
+                }
+                continue;
+            }
+            ControlPacket::Init(_, _) | ControlPacket::End(_) | ControlPacket::GoAway(_) => {
                 error!("invalid protocol control::init message");
                 continue;
             }
             ControlPacket::Ping(_) => {
                 tracing::trace!("pong");
-                Connections::add(client.clone());
+                // a draining server has already given up the client's hosts
+                if !drain::is_draining(&state) {
+                    state.connections.add(client.clone());
+                }
                 continue;
             }
         };
 
-        let stream = ACTIVE_STREAMS.get(&stream_id).map(|s| s.value().clone());
+        let stream = state
+            .active_streams
+            .get(&stream_id)
+            .map(|s| s.value().clone());
 
         if let Some(mut stream) = stream {
-            let _ = stream.tx.send(message).await.map_err(|error| {
-                tracing::trace!(?error, "Failed to send to stream tx");
-            });
+            // without flow control every window is unlimited
+            if let StreamMessage::Data(ref data) = message {
+                if !stream.recv_window.consume(data.len()) {
+                    error!(
+                        ?stream_id,
+                        "client overran its stream window, resetting stream"
+                    );
+                    remote::reset_stream(&state, &mut stream).await;
+                    continue;
+                }
+            }
+
+            // waiting for a slow visitor would hold up every other stream of the client
+            match stream.tx.try_send(message) {
+                Ok(_) => {}
+                Err(error) if error.is_full() => {
+                    error!(
+                        ?stream_id,
+                        "client flooded the stream queue, resetting stream"
+                    );
+                    remote::reset_stream(&state, &mut stream).await;
+                }
+                Err(error) => tracing::trace!(?error, "Failed to send to stream tx"),
+            }
         }
     }
 }
 
-#[tracing::instrument(skip(sink, queue))]
+#[tracing::instrument(skip(state, sink, queue))]
 async fn tunnel_client(
+    state: Arc<ServerState>,
     client: ConnectedClient,
     mut sink: SplitSink<WebSocket, Message>,
-    mut queue: UnboundedReceiver<ControlPacket>,
+    mut queue: Receiver<ControlPacket>,
 ) {
     loop {
         match queue.next().await {
@@ -248,14 +640,94 @@ async fn tunnel_client(
                 let result = sink.send(Message::binary(packet.serialize())).await;
                 if let Err(error) = result {
                     tracing::trace!(?error, "client disconnected: aborting.");
-                    Connections::remove(&client);
+                    state.remove_client(&client);
                     return;
                 }
             }
             None => {
                 tracing::debug!("ending client tunnel");
+                let _ = sink.close().await;
                 return;
             }
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(trusted_proxies: &[&str]) -> Config {
+        Config {
+            trusted_proxies: trusted_proxies.iter().map(|p| p.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn header(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let config = config(&["10.0.0.0/8"]);
+        let remote = ip("203.0.113.7");
+        let resolved = resolve_client_ip(
+            &config,
+            remote,
+            header("198.51.100.1"),
+            header("198.51.100.2"),
+        );
+        assert_eq!(resolved, remote);
+    }
+
+    #[test]
+    fn ignores_headers_without_trusted_proxies() {
+        let config = config(&[]);
+        let remote = ip("10.0.0.2");
+        assert_eq!(
+            resolve_client_ip(&config, remote, header("198.51.100.1"), None),
+            remote
+        );
+    }
+
+    #[test]
+    fn takes_the_fly_client_ip_from_trusted_proxies() {
+        let config = config(&["10.0.0.0/8"]);
+        let resolved = resolve_client_ip(
+            &config,
+            ip("10.0.0.2"),
+            header("198.51.100.1"),
+            header("198.51.100.2"),
+        );
+        assert_eq!(resolved, ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn takes_the_last_untrusted_forwarded_address() {
+        let config = config(&["10.0.0.0/8"]);
+        let fwd = header("192.0.2.66, 198.51.100.1, 10.0.0.3");
+        let resolved = resolve_client_ip(&config, ip("10.0.0.2"), None, fwd);
+        assert_eq!(resolved, ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn falls_back_to_the_proxy_on_malformed_headers() {
+        let config = config(&["10.0.0.0/8"]);
+        let fwd = header("198.51.100.1, not-an-address");
+        let resolved = resolve_client_ip(&config, ip("10.0.0.2"), header("nope"), fwd);
+        assert_eq!(resolved, ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_peers() {
+        let config = config(&["10.0.0.2/32"]);
+        let resolved =
+            resolve_client_ip(&config, ip("::ffff:10.0.0.2"), header("198.51.100.1"), None);
+        assert_eq!(resolved, ip("198.51.100.1"));
+    }
+}
@@ -0,0 +1,83 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Graceful shutdown: stop taking new tunnels and streams, tell clients to
+//! reconnect elsewhere and give in-flight streams some time to finish.
+
+use super::*;
+use std::time::Duration;
+
+/// Time given to control connections to flush their last messages
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+pub fn is_draining(state: &ServerState) -> bool {
+    *state.draining.borrow()
+}
+
+/// Resolves once the server has started draining
+pub async fn started(state: &ServerState) {
+    let mut draining = state.draining.subscribe();
+    let _ = draining.wait_for(|draining| *draining).await;
+}
+
+/// Resolves on SIGTERM or ctrl-c
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = sigterm.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Send every client away and wait for their streams to finish, up to `config.drain_timeout`
+pub async fn drain(state: &ServerState) {
+    let _ = state.draining.send(true);
+
+    let deadline = state.config.drain_timeout;
+    tracing::info!(?deadline, "draining clients");
+
+    for mut client in state.connections.clients() {
+        // new visitors should find the client on the instance it moves to
+        state.connections.release_hosts(&client);
+        state.tcp_tunnels.close(&client.id);
+        state.udp_tunnels.close(&client.id);
+
+        // best effort, a client that can't keep up with its queue is dropped at the deadline
+        if client.capabilities.has(Capability::GoAway) {
+            let packet = ControlPacket::GoAway(deadline.as_secs() as u32);
+            if let Err(error) = client.tx.try_send(packet) {
+                tracing::debug!(client_id = %client.id, ?error, "failed to send go away");
+            }
+        }
+    }
+
+    let waiting = async {
+        while !state.active_streams.is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+
+    match tokio::time::timeout(deadline, waiting).await {
+        Ok(_) => tracing::info!("all streams finished"),
+        Err(_) => tracing::warn!(
+            streams = state.active_streams.len(),
+            "drain deadline reached, dropping streams"
+        ),
+    }
+
+    for client in state.connections.clients() {
+        state.remove_client(&client);
+    }
+    tokio::time::sleep(CLOSE_GRACE).await;
+}
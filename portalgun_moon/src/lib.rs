@@ -0,0 +1,73 @@
+// SPDX-FileCopyrightText: 2023 perillamint <perillamint@silicon.moe>
+// SPDX-FileCopyrightText: 2020-2022 Alex Grinman <me@alexgr.in>
+//
+// SPDX-License-Identifier: MIT
+
+//! The portalgun tunnel server.
+//!
+//! Every [`Server`] owns its configuration, clients and streams, so several
+//! of them can run in one process:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), anyhow::Error> {
+//! use portalgun_moon::{Config, Server};
+//!
+//! let server = Server::builder(Config::from_env()).build().await?;
+//! let handle = server.start().await?;
+//! println!("control server listening on {}", handle.control_addr());
+//!
+//! portalgun_moon::shutdown_signal().await;
+//! handle.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use futures::{SinkExt, StreamExt};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::Filter;
+
+use dashmap::DashMap;
+use portalgun_lib::*;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::stream::{SplitSink, SplitStream};
+
+mod connected_clients;
+use self::connected_clients::*;
+mod active_stream;
+use self::active_stream::*;
+
+mod auth;
+use self::auth::client_auth;
+
+pub use self::auth::{
+    ApiKey, ApiKeyAuth, ApiKeys, AuthBackend, AuthError, AuthOidcService, AuthResult, AuthService,
+    ClientCertAuth, ClientCertificate, Denylist, DenylistEntries, HttpAuth, Identity,
+    IntrospectionClient, LoginMethod, NoAuth, SigKey, SigKeyRing, SubDomainPolicy, SubDomainSource,
+    TokenFileAuth,
+};
+
+mod control_server;
+mod drain;
+pub use self::drain::shutdown_signal;
+mod port_pool;
+mod remote;
+mod reservations;
+use self::reservations::Reservations;
+mod tcp_tunnel;
+use self::tcp_tunnel::TcpTunnels;
+mod udp_tunnel;
+use self::udp_tunnel::UdpTunnels;
+
+mod config;
+pub use self::config::{AnonymousLimits, Config, ControlTls};
+mod network;
+
+mod server;
+use self::server::ServerState;
+pub use self::server::{Server, ServerBuilder, ServerHandle};
+
+use tracing::{error, info};
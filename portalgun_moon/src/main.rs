@@ -3,100 +3,191 @@
 //
 // SPDX-License-Identifier: MIT
 
-use futures::{SinkExt, StreamExt};
-use tokio::sync::RwLock;
-use warp::ws::{Message, WebSocket, Ws};
-use warp::Filter;
-
-use dashmap::DashMap;
-pub use portalgun_lib::*;
-use std::sync::Arc;
-
-use tokio::net::TcpListener;
-
-use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use futures::stream::{SplitSink, SplitStream};
-use lazy_static::lazy_static;
-
-mod connected_clients;
-use self::connected_clients::*;
-mod active_stream;
-use self::active_stream::*;
-
-mod auth;
-pub use self::auth::auth_oidc;
-pub use self::auth::client_auth;
-
-pub use self::auth_oidc::AuthOidcService;
-
-mod control_server;
-mod remote;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use portalgun_moon::{ApiKeys, Config, Denylist, Server};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(name = "portalgun_moon")]
+#[command(about = "The portalgun tunnel server, configured with environment variables.", long_about = None)]
+struct Opts {
+    #[command(subcommand)]
+    command: Option<SubCommand>,
+}
 
-mod config;
-pub use self::config::Config;
-mod network;
+#[derive(Debug, Subcommand)]
+enum SubCommand {
+    /// Manage the API keys of headless clients, i.e. CI runners
+    ApiKey {
+        /// File keeping the API keys, the one the server reads
+        #[clap(long = "file", env = "API_KEYS_FILE")]
+        file: PathBuf,
+
+        #[command(subcommand)]
+        command: ApiKeyCommand,
+    },
+    /// Deny subjects or credentials, i.e. of a user who left
+    Denylist {
+        /// File keeping the denylist, the one the server reads
+        #[clap(long = "file", env = "DENYLIST_FILE")]
+        file: PathBuf,
+
+        #[command(subcommand)]
+        command: DenylistCommand,
+    },
+}
 
-use tracing::{error, info};
+#[derive(Debug, Subcommand)]
+enum ApiKeyCommand {
+    /// Issue a new key and print it. It can't be shown again.
+    Create {
+        /// Who the key is for, clients using it are known as `apikey:<owner>`
+        #[clap(long = "owner")]
+        owner: String,
+
+        /// Regex pattern of the sub-domains the key may use, can be used multiple times
+        #[clap(long = "sub-domain", required = true)]
+        sub_domains: Vec<String>,
+
+        /// Days until the key expires, it never does if unset
+        #[clap(long = "expires-in-days")]
+        expires_in_days: Option<u32>,
+    },
+    /// List the issued keys
+    List,
+    /// Revoke a key, running servers stop accepting it right away
+    Revoke {
+        /// Id of the key, as listed
+        id: String,
+    },
+}
 
-lazy_static! {
-    pub static ref CONNECTIONS: Connections = Connections::new();
-    pub static ref ACTIVE_STREAMS: ActiveStreams = Arc::new(DashMap::new());
-    pub static ref CONFIG: Config = Config::from_env();
-    pub static ref AUTH_DB_SERVICE: RwLock<AuthOidcService> = RwLock::new(AuthOidcService::new(
-        &CONFIG.oidc_discovery_url,
-        &CONFIG.oidc_client_id,
-        &CONFIG.oidc_scopes,
-    ));
+#[derive(Debug, Subcommand)]
+enum DenylistCommand {
+    /// Deny subjects or credentials, running servers disconnect their clients within seconds
+    Add(DenylistEntries),
+    /// Allow subjects or credentials again
+    Remove(DenylistEntries),
+    /// List the denied subjects and credentials
+    List,
+}
 
-    // To disable all authentication:
-    // pub static ref AUTH_DB_SERVICE: crate::auth::NoAuth = crate::auth::NoAuth;
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("entries").required(true).multiple(true)))]
+struct DenylistEntries {
+    /// Subject, i.e. the OIDC `sub`, `apikey:<owner>` or `cert:cn:<name>`, can be used multiple times
+    #[clap(long = "subject", group = "entries")]
+    subjects: Vec<String>,
+
+    /// Token id, i.e. the `jti` of an access token, the id of an API key or the
+    /// fingerprint of a client certificate, can be used multiple times
+    #[clap(long = "token-id", group = "entries")]
+    token_ids: Vec<String>,
 }
 
 #[tokio::main]
 async fn main() {
+    let opts = Opts::parse();
+    if let Some(command) = opts.command {
+        let result = match command {
+            SubCommand::ApiKey { file, command } => api_key(&file, command),
+            SubCommand::Denylist { file, command } => denylist(&file, command),
+        };
+        if let Err(error) = result {
+            eprintln!("Error: {:#}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     tracing_subscriber::fmt::init();
+    tracing::info!("starting server!");
 
-    // Initialize AUTH_DB_SERVICE
-    AUTH_DB_SERVICE
-        .write()
-        .await
-        .init()
+    let server = Server::builder(Config::from_env())
+        .build()
         .await
         .expect("Failed to initialize auth service");
+    let handle = server.start().await.expect("failed to bind");
 
-    tracing::info!("starting server!");
+    portalgun_moon::shutdown_signal().await;
+    handle.shutdown().await;
+}
 
-    control_server::spawn(([0, 0, 0, 0], CONFIG.control_port));
-    info!(
-        "started portalgun server (moon) on 0.0.0.0:{}",
-        CONFIG.control_port
-    );
+fn api_key(file: &std::path::Path, command: ApiKeyCommand) -> Result<(), anyhow::Error> {
+    let keys = ApiKeys::load(file)?;
+
+    match command {
+        ApiKeyCommand::Create {
+            owner,
+            sub_domains,
+            expires_in_days,
+        } => {
+            for pattern in &sub_domains {
+                regex::Regex::new(pattern)?;
+            }
 
-    network::spawn(([0, 0, 0, 0, 0, 0, 0, 0], CONFIG.internal_network_port));
-    info!(
-        "start network service on [::]:{}",
-        CONFIG.internal_network_port
-    );
+            let expires = expires_in_days
+                .map(|days| chrono::Utc::now() + chrono::Duration::days(days.into()));
+            let (key, record) = keys.mint(&owner, sub_domains, expires)?;
+            eprintln!("Created key {} for {}", record.id, record.owner);
+            println!("{}", key);
+        }
+        ApiKeyCommand::List => {
+            for key in keys.list() {
+                let expires = key
+                    .expires
+                    .map(|expires| expires.to_rfc3339())
+                    .unwrap_or_else(|| "never".to_string());
+                println!(
+                    "{}\t{}\t{}\texpires {}",
+                    key.id,
+                    key.owner,
+                    key.sub_domains.join(","),
+                    expires
+                );
+            }
+        }
+        ApiKeyCommand::Revoke { id } => {
+            if !keys.revoke(&id)? {
+                anyhow::bail!("no key with id {}", id);
+            }
+            eprintln!("Revoked key {}", id);
+        }
+    }
 
-    let listen_addr = format!("[::]:{}", CONFIG.remote_port);
-    info!("listening on: {}", &listen_addr);
+    Ok(())
+}
 
-    // create our accept any server
-    let listener = TcpListener::bind(listen_addr)
-        .await
-        .expect("failed to bind");
-
-    loop {
-        let socket = match listener.accept().await {
-            Ok((socket, _)) => socket,
-            _ => {
-                error!("failed to accept socket");
-                continue;
+fn denylist(file: &std::path::Path, command: DenylistCommand) -> Result<(), anyhow::Error> {
+    let denylist = Denylist::load(file)?;
+
+    match command {
+        DenylistCommand::Add(entries) => {
+            denylist.update(|denied| {
+                denied.subjects.extend(entries.subjects);
+                denied.token_ids.extend(entries.token_ids);
+            })?;
+        }
+        DenylistCommand::Remove(entries) => {
+            denylist.update(|denied| {
+                for subject in &entries.subjects {
+                    denied.subjects.remove(subject);
+                }
+                for token_id in &entries.token_ids {
+                    denied.token_ids.remove(token_id);
+                }
+            })?;
+        }
+        DenylistCommand::List => {
+            let denied = denylist.entries();
+            for subject in denied.subjects {
+                println!("subject\t{}", subject);
             }
-        };
-
-        tokio::spawn(async move {
-            remote::accept_connection(socket).await;
-        });
+            for token_id in denied.token_ids {
+                println!("token-id\t{}", token_id);
+            }
+        }
     }
+
+    Ok(())
 }
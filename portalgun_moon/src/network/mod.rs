@@ -12,7 +12,7 @@ pub use self::server::spawn;
 mod proxy;
 pub use self::proxy::proxy_stream;
 use crate::network::server::{HostQuery, HostQueryResponse};
-use crate::ClientId;
+use crate::{ClientId, Config};
 use reqwest::StatusCode;
 use trust_dns_resolver::TokioAsyncResolver;
 
@@ -40,8 +40,8 @@ pub struct Instance {
 
 impl Instance {
     /// get all instances where our app runs
-    async fn get_instances() -> Result<Vec<Instance>, Error> {
-        let query = if let Some(dns) = crate::CONFIG.gossip_dns_host.clone() {
+    async fn get_instances(config: &Config) -> Result<Vec<Instance>, Error> {
+        let query = if let Some(dns) = config.gossip_dns_host.clone() {
             dns
         } else {
             tracing::warn!("warning! gossip mode disabled!");
@@ -60,8 +60,8 @@ impl Instance {
     }
 
     /// query the instance and see if it runs our host
-    async fn serves_host(self, host: &str) -> Result<(Instance, ClientId), Error> {
-        let addr = SocketAddr::new(self.ip, crate::CONFIG.internal_network_port);
+    async fn serves_host(self, config: &Config, host: &str) -> Result<(Instance, ClientId), Error> {
+        let addr = SocketAddr::new(self.ip, config.internal_network_port);
         let url = format!("http://{}", addr);
         let client = reqwest::Client::new();
         let response = client
@@ -94,12 +94,12 @@ impl Instance {
 }
 
 /// get the ip address we need to connect to that runs our host
-#[tracing::instrument]
-pub async fn instance_for_host(host: &str) -> Result<(Instance, ClientId), Error> {
-    let instances = Instance::get_instances()
+#[tracing::instrument(skip(config))]
+pub async fn instance_for_host(config: &Config, host: &str) -> Result<(Instance, ClientId), Error> {
+    let instances = Instance::get_instances(config)
         .await?
         .into_iter()
-        .map(|i| i.serves_host(host).boxed());
+        .map(|i| i.serves_host(config, host).boxed());
 
     if instances.len() == 0 {
         return Err(Error::DoesNotServeHost);
@@ -4,6 +4,7 @@
 // SPDX-License-Identifier: MIT
 
 use crate::network::Instance;
+use crate::Config;
 use std::net::SocketAddr;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
@@ -11,8 +12,8 @@ use tokio::net::TcpStream;
 const HTTP_ERROR_PROXYING_TUNNEL_RESPONSE: &[u8] =
     b"HTTP/1.1 500\r\nContent-Length: 28\r\n\r\nError: Error proxying tunnel";
 
-pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
-    let addr = SocketAddr::new(instance.ip, crate::CONFIG.remote_port);
+pub async fn proxy_stream(config: &Config, instance: Instance, mut stream: TcpStream) {
+    let addr = SocketAddr::new(instance.ip, config.remote_port);
     let mut instance = match TcpStream::connect(addr).await {
         Ok(stream) => stream,
         Err(error) => {
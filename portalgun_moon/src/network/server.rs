@@ -3,12 +3,16 @@
 // SPDX-License-Identifier: MIT
 
 use super::*;
-use crate::connected_clients::Connections;
-use crate::ClientId;
+use crate::{ClientId, ServerState};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use warp::Filter;
 
-pub fn spawn<A: Into<SocketAddr>>(addr: A) {
+/// Serve host queries from other instances until the server stops
+pub fn spawn<A: Into<SocketAddr>>(
+    state: Arc<ServerState>,
+    addr: A,
+) -> Result<SocketAddr, warp::Error> {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Net svc health check triggered");
         "ok"
@@ -17,12 +21,17 @@ pub fn spawn<A: Into<SocketAddr>>(addr: A) {
     let query_svc = warp::path::end()
         .and(warp::get())
         .and(warp::query::<HostQuery>())
-        .map(|query| warp::reply::json(&handle_query(query)));
+        .map({
+            let state = state.clone();
+            move |query| warp::reply::json(&handle_query(&state, query))
+        });
 
     let routes = query_svc.or(health_check);
 
-    // spawn our websocket control server
-    tokio::spawn(warp::serve(routes).run(addr.into()));
+    let (addr, server) = warp::serve(routes)
+        .try_bind_with_graceful_shutdown(addr.into(), async move { state.stopped().await })?;
+    tokio::spawn(server);
+    Ok(addr)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,9 +44,9 @@ pub struct HostQueryResponse {
     pub client_id: Option<ClientId>,
 }
 
-fn handle_query(query: HostQuery) -> HostQueryResponse {
+fn handle_query(state: &ServerState, query: HostQuery) -> HostQueryResponse {
     tracing::debug!(host=%query.host, "got query");
     HostQueryResponse {
-        client_id: Connections::client_for_host(&query.host),
+        client_id: state.connections.client_for_host(&query.host),
     }
 }
@@ -0,0 +1,66 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Public ports handed out to port based tunnels
+
+use dashmap::DashSet;
+use rand::Rng;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+#[derive(Default)]
+pub struct PortPool {
+    /// ports currently leased to a tunnel
+    ports: Arc<DashSet<u16>>,
+}
+
+/// A port leased from a pool, returned when dropped
+pub struct PortLease {
+    ports: Arc<DashSet<u16>>,
+    pub port: u16,
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        tracing::debug!(port = self.port, "releasing tunnel port");
+        self.ports.remove(&self.port);
+    }
+}
+
+impl PortPool {
+    /// Lease a free port from `range`, starting at a random offset,
+    /// and bind it with `bind`
+    pub async fn allocate<T, F, Fut>(
+        &self,
+        range: RangeInclusive<u16>,
+        bind: F,
+    ) -> Option<(PortLease, T)>
+    where
+        F: Fn(u16) -> Fut,
+        Fut: Future<Output = std::io::Result<T>>,
+    {
+        let len = u32::from(*range.end() - *range.start()) + 1;
+        let offset = rand::thread_rng().gen_range(0..len);
+
+        for i in 0..len {
+            let port = *range.start() + ((offset + i) % len) as u16;
+            if !self.ports.insert(port) {
+                continue;
+            }
+            let lease = PortLease {
+                ports: self.ports.clone(),
+                port,
+            };
+
+            match bind(port).await {
+                Ok(bound) => return Some((lease, bound)),
+                Err(error) => tracing::debug!(?error, %port, "tunnel port unavailable"),
+            }
+        }
+
+        tracing::error!("no free ports left in {:?}", range);
+        None
+    }
+}
@@ -10,15 +10,18 @@ use tokio::net::TcpStream;
 use tracing::debug;
 use tracing::error;
 
-async fn direct_to_control(mut incoming: TcpStream) {
-    let mut control_socket =
-        match TcpStream::connect(format!("localhost:{}", CONFIG.control_port)).await {
-            Ok(s) => s,
-            Err(error) => {
-                tracing::warn!(?error, "failed to connect to local control server");
-                return;
-            }
-        };
+async fn direct_to_control(state: &ServerState, mut incoming: TcpStream) {
+    let control_port = match state.control_addr.get() {
+        Some(addr) => addr.port(),
+        None => return,
+    };
+    let mut control_socket = match TcpStream::connect(format!("localhost:{}", control_port)).await {
+        Ok(s) => s,
+        Err(error) => {
+            tracing::warn!(?error, "failed to connect to local control server");
+            return;
+        }
+    };
 
     let (mut control_r, mut control_w) = control_socket.split();
     let (mut incoming_r, mut incoming_w) = incoming.split();
@@ -34,14 +37,17 @@ async fn direct_to_control(mut incoming: TcpStream) {
     }
 }
 
-#[tracing::instrument(skip(socket))]
-pub async fn accept_connection(socket: TcpStream) {
+#[tracing::instrument(skip(state, socket))]
+pub async fn accept_connection(state: Arc<ServerState>, socket: TcpStream) {
+    let remote_addr = socket.peer_addr().ok();
+
     // peek the host of the http request
     // if health check, then handle it and return
     let StreamWithPeekedHost {
         mut socket,
         host,
         forwarded_for,
+        forwarded_proto,
     } = match peek_http_request_host(socket).await {
         Some(s) => s,
         None => return,
@@ -49,13 +55,21 @@ pub async fn accept_connection(socket: TcpStream) {
 
     tracing::info!(%host, %forwarded_for, "new remote connection");
 
+    let metadata = StreamMetadata {
+        remote_addr,
+        host: Some(host.clone()),
+        tls: forwarded_proto.eq_ignore_ascii_case("https"),
+        forwarded_for: Some(forwarded_for).filter(|f| !f.is_empty()),
+        binding: None,
+    };
+
     // parse the host string and find our client
-    if CONFIG.allowed_hosts.contains(&host) {
+    if state.config.allowed_hosts.contains(&host) {
         error!("redirect to homepage");
         let _ = socket.write_all(HTTP_REDIRECT_RESPONSE).await;
         return;
     }
-    let host = match validate_host_prefix(&host) {
+    let host = match validate_host_prefix(&state.config, &host) {
         Some(sub_domain) => sub_domain,
         None => {
             error!("invalid host specified");
@@ -66,18 +80,18 @@ pub async fn accept_connection(socket: TcpStream) {
 
     // Special case -- we redirect this tcp connection to the control server
     if host.as_str() == "wormhole" {
-        direct_to_control(socket).await;
+        direct_to_control(&state, socket).await;
         return;
     }
 
     // find the client listening for this host
-    let client = match Connections::find_by_host(&host) {
+    let client = match state.connections.find_by_host(&host) {
         Some(client) => client,
         None => {
             // check other instances that may be serving this host
-            match network::instance_for_host(&host).await {
+            match network::instance_for_host(&state.config, &host).await {
                 Ok((instance, _)) => {
-                    network::proxy_stream(instance, socket).await;
+                    network::proxy_stream(&state.config, instance, socket).await;
                     return;
                 }
                 Err(network::Error::DoesNotServeHost) => {
@@ -94,6 +108,28 @@ pub async fn accept_connection(socket: TcpStream) {
         }
     };
 
+    // raw tcp tunnels are only reachable through their own port
+    if client.tunnel_type != TunnelType::Http {
+        error!(%host, "host belongs to a non-http tunnel");
+        let _ = socket.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+        return;
+    }
+
+    let metadata = StreamMetadata {
+        binding: client.binding_for_host(&host),
+        ..metadata
+    };
+    open_stream(&state, client, host, socket, metadata);
+}
+
+/// Tunnel a remote socket to the client as a new stream
+pub fn open_stream(
+    state: &Arc<ServerState>,
+    client: ConnectedClient,
+    host: String,
+    socket: TcpStream,
+    metadata: StreamMetadata,
+) {
     // allocate a new stream for this request
     let (active_stream, queue_rx) = ActiveStream::new(client);
     let stream_id = active_stream.id.clone();
@@ -105,20 +141,25 @@ pub async fn accept_connection(socket: TcpStream) {
     let (stream, sink) = tokio::io::split(socket);
 
     // add our stream
-    ACTIVE_STREAMS.insert(stream_id.clone(), active_stream.clone());
+    state
+        .active_streams
+        .insert(stream_id.clone(), active_stream.clone());
 
     // read from socket, write to client
+    let tcp_stream = active_stream.clone();
+    let state_clone = state.clone();
     tokio::spawn(async move {
-        process_tcp_stream(active_stream, stream).await;
+        process_tcp_stream(state_clone, tcp_stream, stream, metadata).await;
     });
 
     // read from client, write to socket
+    let state = state.clone();
     tokio::spawn(async move {
-        tunnel_to_stream(host, stream_id, sink, queue_rx).await;
+        tunnel_to_stream(state, host, active_stream, sink, queue_rx).await;
     });
 }
 
-fn validate_host_prefix(host: &str) -> Option<String> {
+fn validate_host_prefix(config: &Config, host: &str) -> Option<String> {
     let url = format!("http://{}", host);
 
     let host = match url::Url::parse(&url)
@@ -136,7 +177,7 @@ fn validate_host_prefix(host: &str) -> Option<String> {
     let prefix = &domain_segments[0];
     let remaining = &domain_segments[1..].join(".");
 
-    if CONFIG.allowed_hosts.contains(remaining) {
+    if config.allowed_hosts.contains(remaining) {
         Some(prefix.to_string())
     } else {
         None
@@ -160,6 +201,7 @@ struct StreamWithPeekedHost {
     socket: TcpStream,
     host: String,
     forwarded_for: String,
+    forwarded_proto: String,
 }
 /// Filter incoming remote streams
 #[tracing::instrument(skip(socket))]
@@ -217,6 +259,15 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
         String::default()
     };
 
+    // whether a proxy in front of us terminated tls
+    let forwarded_proto = req
+        .headers
+        .iter()
+        .filter(|h| h.name.to_lowercase() == *"x-forwarded-proto")
+        .find_map(|h| std::str::from_utf8(h.value).ok())
+        .unwrap_or_default()
+        .to_string();
+
     // look for a host header
     if let Some(Ok(host)) = req
         .headers
@@ -231,6 +282,7 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
             socket,
             host: host.to_string(),
             forwarded_for,
+            forwarded_proto,
         });
     }
 
@@ -239,17 +291,22 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
 }
 
 /// Process Messages from the control path in & out of the remote stream
-#[tracing::instrument(skip(tunnel_stream, tcp_stream))]
-async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: ReadHalf<TcpStream>) {
+#[tracing::instrument(skip(state, tunnel_stream, tcp_stream, metadata))]
+async fn process_tcp_stream(
+    state: Arc<ServerState>,
+    mut tunnel_stream: ActiveStream,
+    mut tcp_stream: ReadHalf<TcpStream>,
+    metadata: StreamMetadata,
+) {
     // send initial control stream init to client
-    control_server::send_client_stream_init(tunnel_stream.clone()).await;
+    control_server::send_client_stream_init(&state, tunnel_stream.clone(), metadata).await;
 
     // now read from stream and forward to clients
     let mut buf = [0; 1024];
 
     loop {
         // client is no longer connected
-        if Connections::get(&tunnel_stream.client.id).is_none() {
+        if state.connections.get(&tunnel_stream.client.id).is_none() {
             debug!("client disconnected, closing stream");
             let _ = tunnel_stream.tx.send(StreamMessage::NoClientTunnel).await;
             tunnel_stream.tx.close_channel();
@@ -261,6 +318,7 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
             Ok(n) => n,
             Err(e) => {
                 error!("failed to read from tcp socket: {:?}", e);
+                reset_stream(&state, &mut tunnel_stream).await;
                 return;
             }
         };
@@ -275,11 +333,26 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
                 .map_err(|e| {
                     error!("failed to send end signal: {:?}", e);
                 });
+
+            // the client may still be sending its side
+            if tunnel_stream.client.capabilities.has(Capability::HalfClose)
+                && tunnel_stream.halves.finish()
+            {
+                state.active_streams.remove(&tunnel_stream.id);
+            }
             return;
         }
 
         debug!("read {} bytes", n);
 
+        // wait for the client to have room for more
+        if !tunnel_stream.send_window.reserve(n).await {
+            debug!("client went away while waiting for stream credit");
+            let _ = tunnel_stream.tx.send(StreamMessage::NoClientTunnel).await;
+            tunnel_stream.tx.close_channel();
+            return;
+        }
+
         let data = &buf[..n];
         let packet = ControlPacket::Data(tunnel_stream.id.clone(), data.to_vec());
 
@@ -287,33 +360,57 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
             Ok(_) => debug!(client_id = %tunnel_stream.client.id, "sent data packet to client"),
             Err(_) => {
                 error!("failed to forward tcp packets to disconnected client. dropping client.");
-                Connections::remove(&tunnel_stream.client);
+                state.remove_client(&tunnel_stream.client);
             }
         }
     }
 }
 
-#[tracing::instrument(skip(sink, stream_id, queue))]
+#[tracing::instrument(skip(state, sink, stream, queue))]
 async fn tunnel_to_stream(
+    state: Arc<ServerState>,
     subdomain: String,
-    stream_id: StreamId,
+    mut stream: ActiveStream,
     mut sink: WriteHalf<TcpStream>,
-    mut queue: UnboundedReceiver<StreamMessage>,
+    mut queue: Receiver<StreamMessage>,
 ) {
+    let stream_id = stream.id.clone();
+    let is_http = stream.client.tunnel_type == TunnelType::Http;
+
     loop {
         let result = queue.next().await;
 
         let result = if let Some(message) = result {
             match message {
                 StreamMessage::Data(data) => Some(data),
+                StreamMessage::End => {
+                    tracing::debug!(?stream_id, "done tunneling to sink, client finished");
+                    let _ = sink.shutdown().await.map_err(|_e| {
+                        error!("error shutting down tcp stream");
+                    });
+
+                    if stream.halves.finish() {
+                        state.active_streams.remove(&stream_id);
+                    }
+                    return;
+                }
+                StreamMessage::Reset => {
+                    tracing::debug!(?stream_id, "stream reset by client");
+                    stream.send_window.close();
+                    None
+                }
                 StreamMessage::TunnelRefused => {
                     tracing::debug!(?stream_id, "tunnel refused");
-                    let _ = sink.write_all(HTTP_TUNNEL_REFUSED_RESPONSE).await;
+                    if is_http {
+                        let _ = sink.write_all(HTTP_TUNNEL_REFUSED_RESPONSE).await;
+                    }
                     None
                 }
                 StreamMessage::NoClientTunnel => {
                     tracing::info!(%subdomain, ?stream_id, "client tunnel not found");
-                    let _ = sink.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    if is_http {
+                        let _ = sink.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    }
                     None
                 }
             }
@@ -329,7 +426,7 @@ async fn tunnel_to_stream(
                     error!("error shutting down tcp stream");
                 });
 
-                ACTIVE_STREAMS.remove(&stream_id);
+                state.active_streams.remove(&stream_id);
                 return;
             }
         };
@@ -338,7 +435,28 @@ async fn tunnel_to_stream(
 
         if let Some(error) = result.err() {
             tracing::warn!(?error, "stream closed, disconnecting");
+            reset_stream(&state, &mut stream).await;
             return;
         }
+
+        // let the client know it can send more
+        if let Some(update) = stream.recv_window.release(&stream_id, data.len()) {
+            let _ = stream.client.tx.send(update).await;
+        }
+    }
+}
+
+/// Abort a stream in both directions and let the client know
+pub async fn reset_stream(state: &ServerState, stream: &mut ActiveStream) {
+    state.active_streams.remove(&stream.id);
+    stream.send_window.close();
+    stream.tx.close_channel();
+
+    if stream.client.capabilities.has(Capability::HalfClose) {
+        let _ = stream
+            .client
+            .tx
+            .send(ControlPacket::Reset(stream.id.clone()))
+            .await;
     }
 }
@@ -0,0 +1,105 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Sub-domains reserved for their owner, i.e. the OIDC subject of the client
+//! that claimed them.
+//!
+//! Reservations are kept in a JSON file, mapping each sub-domain to its owner:
+//!
+//! ```json
+//! { "alice-api": "2f9a6a3e-5d8c-4f6e-9c55-0f1d7c3b1a20" }
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::auth::json_file;
+
+pub(crate) struct Reservations {
+    path: PathBuf,
+    /// owner by sub-domain
+    owners: Mutex<HashMap<String, String>>,
+}
+
+impl Reservations {
+    /// Load the reservations from `path`, starting with none if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let owners: HashMap<String, String> = json_file::load(path)?;
+        tracing::info!(reservations = owners.len(), path = %path.display(), "loaded reservations");
+
+        Ok(Self {
+            path: path.to_owned(),
+            owners: Mutex::new(owners),
+        })
+    }
+
+    /// Who reserved `sub_domain`, if anyone
+    pub fn owner(&self, sub_domain: &str) -> Option<String> {
+        self.owners.lock().unwrap().get(sub_domain).cloned()
+    }
+
+    /// Reserve `sub_domain` for `owner`, unless someone already did.
+    /// Returns the owner of the sub-domain.
+    pub fn reserve(&self, sub_domain: &str, owner: &str) -> Result<String, anyhow::Error> {
+        let mut owners = self.owners.lock().unwrap();
+        if let Some(existing) = owners.get(sub_domain) {
+            return Ok(existing.clone());
+        }
+
+        owners.insert(sub_domain.to_owned(), owner.to_owned());
+        if let Err(error) = json_file::save_atomic(&self.path, &*owners) {
+            owners.remove(sub_domain);
+            return Err(error);
+        }
+
+        tracing::info!(%sub_domain, %owner, "reserved sub-domain");
+        Ok(owner.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::json_file::tests::temporary_file;
+
+    #[test]
+    fn starts_empty_without_a_file() {
+        let reservations = Reservations::load(&temporary_file()).unwrap();
+        assert_eq!(reservations.owner("alice-api"), None);
+    }
+
+    #[test]
+    fn first_reservation_wins() {
+        let path = temporary_file();
+        let reservations = Reservations::load(&path).unwrap();
+
+        assert_eq!(reservations.reserve("alice-api", "alice").unwrap(), "alice");
+        assert_eq!(reservations.reserve("alice-api", "bob").unwrap(), "alice");
+        assert_eq!(reservations.owner("alice-api").as_deref(), Some("alice"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reservations_survive_a_reload() {
+        let path = temporary_file();
+        let reservations = Reservations::load(&path).unwrap();
+        reservations.reserve("alice-api", "alice").unwrap();
+        reservations.reserve("bob-api", "bob").unwrap();
+
+        let reloaded = Reservations::load(&path).unwrap();
+        assert_eq!(reloaded.owner("alice-api").as_deref(), Some("alice"));
+        assert_eq!(reloaded.owner("bob-api").as_deref(), Some("bob"));
+        assert!(!path.with_extension("tmp").exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let path = temporary_file();
+        std::fs::write(&path, "[\"alice-api\"]").unwrap();
+        assert!(Reservations::load(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! A server instance and the state shared by its tasks
+
+use super::*;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+/// State of a running server, shared by all of its tasks
+pub(crate) struct ServerState {
+    pub config: Config,
+    pub auth: Arc<dyn AuthService>,
+    pub connections: Connections,
+    pub active_streams: ActiveStreams,
+    pub tcp_tunnels: TcpTunnels,
+    pub udp_tunnels: UdpTunnels,
+    pub anonymous_clients: AnonymousClients,
+    /// sub-domains reserved by their owners, if enabled
+    pub reservations: Option<Reservations>,
+    /// subjects and credentials denied by the admins, if enabled
+    pub denylist: Option<Denylist>,
+    /// set once the server starts draining
+    pub draining: watch::Sender<bool>,
+    /// set once the server has stopped serving
+    pub stopped: watch::Sender<bool>,
+    /// where the control server is listening
+    pub control_addr: OnceLock<SocketAddr>,
+}
+
+impl ServerState {
+    /// Forget a client and close everything it was serving
+    pub fn remove_client(&self, client: &ConnectedClient) {
+        self.connections.remove(client);
+        self.tcp_tunnels.close(&client.id);
+        self.udp_tunnels.close(&client.id);
+        self.anonymous_clients.remove(&client.id);
+
+        // wake up any stream still waiting on credit from this client
+        self.active_streams
+            .iter()
+            .filter(|s| s.client.id == client.id)
+            .for_each(|s| s.send_window.close());
+    }
+
+    /// Resolves once the server has stopped serving
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.subscribe();
+        let _ = stopped.wait_for(|stopped| *stopped).await;
+    }
+}
+
+#[cfg(test)]
+impl ServerState {
+    /// State of a server that isn't listening, accepting every token
+    pub fn for_tests(config: Config) -> Self {
+        ServerState {
+            config,
+            auth: Arc::new(crate::auth::NoAuth),
+            connections: Connections::new(),
+            active_streams: Arc::new(DashMap::new()),
+            tcp_tunnels: TcpTunnels::new(),
+            udp_tunnels: UdpTunnels::new(),
+            anonymous_clients: AnonymousClients::default(),
+            reservations: None,
+            denylist: None,
+            draining: watch::channel(false).0,
+            stopped: watch::channel(false).0,
+            control_addr: OnceLock::new(),
+        }
+    }
+}
+
+/// Configures a [`Server`]
+pub struct ServerBuilder {
+    config: Config,
+    auth: Option<Arc<dyn AuthService>>,
+}
+
+impl ServerBuilder {
+    /// Authenticate clients with `auth` instead of the backend from the config.
+    /// The service is used as is, it must already be initialized.
+    pub fn auth_service(mut self, auth: impl AuthService + 'static) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Initialize the auth service and load the API keys, reservations, denylist and certificates
+    pub async fn build(self) -> Result<Server, anyhow::Error> {
+        let mut auth = match self.auth {
+            Some(auth) => auth,
+            None => auth::from_config(&self.config.auth_backend).await?,
+        };
+
+        if let Some(path) = &self.config.api_keys_file {
+            auth = Arc::new(ApiKeyAuth::new(ApiKeys::load(path)?, auth));
+        }
+
+        let control_tls = match &self.config.control_tls {
+            Some(tls) => {
+                if tls.client_ca_file.is_some() {
+                    let sub_domains = self.config.client_cert_sub_domains.clone();
+                    auth = Arc::new(ClientCertAuth::new(sub_domains, auth));
+                }
+                Some(control_server::tls_acceptor(tls)?)
+            }
+            None => None,
+        };
+
+        let reservations = match &self.config.reservations_file {
+            Some(path) => Some(Reservations::load(path)?),
+            None => None,
+        };
+
+        let denylist = match &self.config.denylist_file {
+            Some(path) => Some(Denylist::load(path)?),
+            None => None,
+        };
+
+        Ok(Server {
+            config: self.config,
+            auth,
+            reservations,
+            denylist,
+            control_tls,
+        })
+    }
+}
+
+/// A tunnel server, not listening yet
+pub struct Server {
+    config: Config,
+    auth: Arc<dyn AuthService>,
+    reservations: Option<Reservations>,
+    denylist: Option<Denylist>,
+    control_tls: Option<TlsAcceptor>,
+}
+
+impl Server {
+    pub fn builder(config: Config) -> ServerBuilder {
+        ServerBuilder { config, auth: None }
+    }
+
+    /// Bind the control, remote and network ports and start serving
+    pub async fn start(self) -> Result<ServerHandle, anyhow::Error> {
+        let Server {
+            config,
+            auth,
+            reservations,
+            denylist,
+            control_tls,
+        } = self;
+
+        let listener = TcpListener::bind(format!("[::]:{}", config.remote_port)).await?;
+        let remote_addr = listener.local_addr()?;
+
+        let state = Arc::new(ServerState {
+            config,
+            auth,
+            connections: Connections::new(),
+            active_streams: Arc::new(DashMap::new()),
+            tcp_tunnels: TcpTunnels::new(),
+            udp_tunnels: UdpTunnels::new(),
+            anonymous_clients: AnonymousClients::default(),
+            reservations,
+            denylist,
+            draining: watch::channel(false).0,
+            stopped: watch::channel(false).0,
+            control_addr: OnceLock::new(),
+        });
+
+        let control_addr = control_server::spawn(
+            state.clone(),
+            ([0, 0, 0, 0], state.config.control_port),
+            control_tls,
+        )?;
+        let _ = state.control_addr.set(control_addr);
+        info!("started portalgun server (moon) on {}", control_addr);
+
+        let network_addr = network::spawn(
+            state.clone(),
+            ([0, 0, 0, 0, 0, 0, 0, 0], state.config.internal_network_port),
+        )?;
+        info!("start network service on {}", network_addr);
+
+        tokio::spawn(auth::denylist::enforce(state.clone()));
+
+        info!("listening on: {}", remote_addr);
+        let task = tokio::spawn(accept_remote(state.clone(), listener));
+
+        Ok(ServerHandle {
+            state,
+            control_addr,
+            remote_addr,
+            network_addr,
+            task,
+        })
+    }
+}
+
+/// A running server
+pub struct ServerHandle {
+    state: Arc<ServerState>,
+    control_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    network_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Address of the control server clients connect to
+    pub fn control_addr(&self) -> SocketAddr {
+        self.control_addr
+    }
+
+    /// Address visitors of the tunnels connect to
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Address of the instance-to-instance network service
+    pub fn network_addr(&self) -> SocketAddr {
+        self.network_addr
+    }
+
+    /// Stop taking new clients and visitors, send the clients away and wait
+    /// for in-flight streams to finish, up to the configured drain timeout
+    pub async fn shutdown(mut self) {
+        let _ = self.state.draining.send(true);
+        let _ = (&mut self.task).await;
+
+        drain::drain(&self.state).await;
+        let _ = self.state.stopped.send(true);
+        info!("shutting down");
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = self.state.draining.send(true);
+        let _ = self.state.stopped.send(true);
+    }
+}
+
+async fn accept_remote(state: Arc<ServerState>, listener: TcpListener) {
+    let draining = drain::started(&state);
+    tokio::pin!(draining);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            // stop taking new visitors, the drain lets the ones we have finish
+            _ = &mut draining => return,
+        };
+
+        let socket = match accepted {
+            Ok((socket, _)) => socket,
+            _ => {
+                error!("failed to accept socket");
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            remote::accept_connection(state, socket).await;
+        });
+    }
+}
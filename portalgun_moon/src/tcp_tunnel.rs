@@ -0,0 +1,96 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! Raw TCP tunnels, each served on its own public port
+
+use super::*;
+use crate::port_pool::{PortLease, PortPool};
+use tokio::task::AbortHandle;
+
+#[derive(Default)]
+pub struct TcpTunnels {
+    ports: PortPool,
+    /// accept loops of running tunnels
+    listeners: DashMap<ClientId, AbortHandle>,
+}
+
+/// A listener bound on a leased public port
+pub struct TcpTunnelListener {
+    lease: PortLease,
+    listener: TcpListener,
+}
+
+impl TcpTunnelListener {
+    pub fn port(&self) -> u16 {
+        self.lease.port
+    }
+}
+
+impl TcpTunnels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a listener on a free port from the configured range
+    pub async fn allocate(&self, config: &Config) -> Option<TcpTunnelListener> {
+        let range = config.tcp_port_range.clone()?;
+        let (lease, listener) = self
+            .ports
+            .allocate(range, |port| TcpListener::bind(format!("[::]:{}", port)))
+            .await?;
+        Some(TcpTunnelListener { lease, listener })
+    }
+
+    /// Start accepting visitors for `client` on its allocated port
+    pub fn spawn(state: &Arc<ServerState>, client: ConnectedClient, listener: TcpTunnelListener) {
+        let client_id = client.id.clone();
+        let handle = tokio::spawn(accept_connections(state.clone(), client, listener));
+        state
+            .tcp_tunnels
+            .listeners
+            .insert(client_id, handle.abort_handle());
+    }
+
+    /// Stop the listener of a client, if it has one
+    pub fn close(&self, client_id: &ClientId) {
+        if let Some((_, handle)) = self.listeners.remove(client_id) {
+            handle.abort();
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, client, tunnel), fields(port = tunnel.port()))]
+async fn accept_connections(
+    state: Arc<ServerState>,
+    client: ConnectedClient,
+    tunnel: TcpTunnelListener,
+) {
+    loop {
+        let (socket, remote) = match tunnel.listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                error!(?error, "failed to accept tcp tunnel socket");
+                continue;
+            }
+        };
+
+        if state.config.blocked_ips.contains(&remote.ip()) {
+            tracing::warn!(%remote, "remote ip is on block list, dropping connection");
+            continue;
+        }
+
+        tracing::info!(%remote, subdomain = %client.host, "new tcp tunnel connection");
+        let metadata = StreamMetadata {
+            remote_addr: Some(remote),
+            ..Default::default()
+        };
+        remote::open_stream(
+            &state,
+            client.clone(),
+            client.host.clone(),
+            socket,
+            metadata,
+        );
+    }
+}
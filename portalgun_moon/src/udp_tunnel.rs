@@ -0,0 +1,196 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+//! UDP tunnels, each served on its own public port.
+//!
+//! Every remote peer talking to the port gets a synthetic stream id, which
+//! the client uses to keep a separate local session per peer.
+
+use super::*;
+use crate::port_pool::{PortLease, PortPool};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::AbortHandle;
+
+/// Forget peers that have been quiet for this long
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Most peers a tunnel keeps track of, datagrams from further peers are dropped
+/// until some go quiet
+const MAX_PEERS_PER_TUNNEL: usize = 256;
+
+/// Largest datagram we will receive
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+#[derive(Default)]
+pub struct UdpTunnels {
+    ports: PortPool,
+    tunnels: DashMap<ClientId, UdpTunnel>,
+}
+
+struct UdpTunnel {
+    socket: Arc<UdpSocket>,
+    peers: Arc<DashMap<StreamId, Peer>>,
+    task: AbortHandle,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    addr: SocketAddr,
+    last_seen: Instant,
+}
+
+/// A socket bound on a leased public port
+pub struct UdpTunnelSocket {
+    lease: PortLease,
+    socket: UdpSocket,
+}
+
+impl UdpTunnelSocket {
+    pub fn port(&self) -> u16 {
+        self.lease.port
+    }
+}
+
+impl UdpTunnels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a socket on a free port from the configured range
+    pub async fn allocate(&self, config: &Config) -> Option<UdpTunnelSocket> {
+        let range = config.udp_port_range.clone()?;
+        let (lease, socket) = self
+            .ports
+            .allocate(range, |port| UdpSocket::bind(format!("[::]:{}", port)))
+            .await?;
+        Some(UdpTunnelSocket { lease, socket })
+    }
+
+    /// Start relaying datagrams for `client` from its allocated port
+    pub fn spawn(state: &Arc<ServerState>, client: ConnectedClient, socket: UdpTunnelSocket) {
+        let UdpTunnelSocket { lease, socket } = socket;
+        let socket = Arc::new(socket);
+        let peers = Arc::new(DashMap::new());
+
+        let client_id = client.id.clone();
+        let task = tokio::spawn(receive_datagrams(
+            state.clone(),
+            client,
+            lease,
+            socket.clone(),
+            peers.clone(),
+        ));
+
+        state.udp_tunnels.tunnels.insert(
+            client_id,
+            UdpTunnel {
+                socket,
+                peers,
+                task: task.abort_handle(),
+            },
+        );
+    }
+
+    /// Send a datagram from the client back to the remote peer
+    pub async fn send_to_peer(&self, client_id: &ClientId, stream_id: &StreamId, data: &[u8]) {
+        let (socket, addr) = match self.tunnels.get(client_id) {
+            Some(tunnel) => match tunnel.peers.get_mut(stream_id) {
+                Some(mut peer) => {
+                    peer.last_seen = Instant::now();
+                    (tunnel.socket.clone(), peer.addr)
+                }
+                None => {
+                    tracing::debug!(%stream_id, "datagram for an expired peer");
+                    return;
+                }
+            },
+            None => {
+                tracing::debug!(%client_id, "datagram for a client without udp tunnel");
+                return;
+            }
+        };
+
+        if let Err(error) = socket.send_to(data, addr).await {
+            tracing::debug!(?error, %addr, "failed to send datagram to peer");
+        }
+    }
+
+    /// Stop the tunnel of a client, if it has one
+    pub fn close(&self, client_id: &ClientId) {
+        if let Some((_, tunnel)) = self.tunnels.remove(client_id) {
+            tunnel.task.abort();
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, client, lease, socket, peers), fields(port = lease.port))]
+async fn receive_datagrams(
+    state: Arc<ServerState>,
+    mut client: ConnectedClient,
+    lease: PortLease,
+    socket: Arc<UdpSocket>,
+    peers: Arc<DashMap<StreamId, Peer>>,
+) {
+    let mut streams: HashMap<SocketAddr, StreamId> = HashMap::new();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut sweep = tokio::time::interval(PEER_IDLE_TIMEOUT);
+
+    loop {
+        let (n, addr) = tokio::select! {
+            result = socket.recv_from(&mut buf) => match result {
+                Ok(received) => received,
+                Err(error) => {
+                    tracing::debug!(?error, "failed to receive datagram");
+                    continue;
+                }
+            },
+            _ = sweep.tick() => {
+                peers.retain(|_, peer| peer.last_seen.elapsed() < PEER_IDLE_TIMEOUT);
+                streams.retain(|_, stream_id| peers.contains_key(stream_id));
+                continue;
+            }
+        };
+
+        if state.config.blocked_ips.contains(&addr.ip()) {
+            continue;
+        }
+
+        let stream_id = match streams.get(&addr) {
+            Some(stream_id) => stream_id.clone(),
+            None if streams.len() >= MAX_PEERS_PER_TUNNEL => {
+                tracing::trace!(%addr, "too many udp peers, dropping datagram");
+                continue;
+            }
+            None => {
+                tracing::debug!(%addr, "new udp peer");
+                let stream_id = StreamId::generate();
+                streams.insert(addr, stream_id.clone());
+                stream_id
+            }
+        };
+        peers.insert(
+            stream_id.clone(),
+            Peer {
+                addr,
+                last_seen: Instant::now(),
+            },
+        );
+
+        // UDP is lossy anyway, drop datagrams rather than queue them without bound
+        let packet = ControlPacket::Datagram(stream_id, buf[..n].to_vec());
+        match client.tx.try_send(packet) {
+            Ok(_) => {}
+            Err(error) if error.is_full() => {
+                tracing::trace!(%addr, "client queue is full, dropping datagram");
+            }
+            Err(_) => {
+                tracing::debug!("client disconnected, closing udp tunnel");
+                return;
+            }
+        }
+    }
+}
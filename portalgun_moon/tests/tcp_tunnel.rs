@@ -0,0 +1,60 @@
+// SPDX-FileCopyrightText: 2024 perillamint <perillamint@silicon.moe>
+//
+// SPDX-License-Identifier: MIT
+
+use portalgun_lib::client::Tunnel;
+use portalgun_lib::TunnelType;
+use portalgun_moon::{Config, Server};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Services like databases and mail servers greet their clients before
+/// reading anything, the visitor must see the greeting without sending first
+#[tokio::test]
+async fn server_first_banner_reaches_the_visitor() {
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = local.accept().await {
+            let _ = socket.write_all(b"220 mail.example.com ESMTP\r\n").await;
+        }
+    });
+
+    let config = Config {
+        remote_port: 0,
+        control_port: 0,
+        internal_network_port: 0,
+        tcp_port_range: Some(41000..=41999),
+        ..Config::default()
+    };
+    let server = Server::builder(config)
+        .build()
+        .await
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+
+    let control_url = format!("ws://127.0.0.1:{}/wormhole", server.control_addr().port());
+    let tunnel = Tunnel::builder()
+        .control_url(control_url.parse().unwrap())
+        .token("token")
+        .tunnel_type(TunnelType::Tcp)
+        .forward_to(local_addr)
+        .connect()
+        .await
+        .unwrap();
+    let port = tunnel.connected().port.unwrap();
+
+    let mut visitor = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))
+        .await
+        .unwrap();
+    let mut banner = [0; 28];
+    tokio::time::timeout(Duration::from_secs(5), visitor.read_exact(&mut banner))
+        .await
+        .expect("no banner within 5s")
+        .unwrap();
+    assert_eq!(&banner, b"220 mail.example.com ESMTP\r\n");
+}